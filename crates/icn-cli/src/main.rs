@@ -1,8 +1,6 @@
 use clap::{App, Arg, SubCommand};
-use reqwest;
 use serde_json::{json, Value};
 use std::error::Error;
-use std::io::{self, Write};
 use std::process;
 
 const VERSION: &str = "0.1.0";
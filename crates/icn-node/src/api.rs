@@ -0,0 +1,373 @@
+use crate::dag::{self, IngestStatus, VertexDetails, VertexQuery};
+use crate::error::{NodeError, NodeResult};
+use crate::federation::{self, FederationConfig};
+use crate::queue;
+use crate::signing;
+use crate::state;
+use crate::vertex::Vertex;
+use axum::extract::rejection::JsonRejection;
+use axum::extract::{ConnectInfo, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use tracing::{error, info, warn};
+
+// Responses wrap their content as `{"result": ...}` and errors as
+// `{"error": {"code": ..., "message": ...}}`, the shape icn-cli and the
+// scripts already read
+type ApiResult = Result<Json<Value>, ApiError>;
+
+fn result<T: Serialize>(content: T) -> ApiResult {
+    Ok(Json(json!({ "result": content })))
+}
+
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    // Lookups fail with a DAG error when the vertex is unknown
+    fn not_found(e: NodeError) -> Self {
+        match e {
+            NodeError::Dag(message) => ApiError { status: StatusCode::NOT_FOUND, message },
+            other => other.into(),
+        }
+    }
+}
+
+impl From<NodeError> for ApiError {
+    fn from(e: NodeError) -> Self {
+        let status = match &e {
+            NodeError::Validation(_) | NodeError::Dag(_) | NodeError::Queue(_) | NodeError::Json(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        ApiError { status, message: e.to_string() }
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError { status: rejection.status(), message: rejection.body_text() }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.status.is_server_error() {
+            error!("API request failed: {}", self.message);
+        }
+        let body = json!({ "error": { "code": self.status.as_u16(), "message": self.message } });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct NodeInfo {
+    id: String,
+    // Federation and node name from the federation config
+    network: String,
+    moniker: String,
+    version: String,
+    // Key the node signs vertices with, for peers to register
+    public_key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SyncInfo {
+    // Number of vertices the node holds, which grows as it catches up
+    latest_block_height: usize,
+    latest_block_time: DateTime<Utc>,
+    dag_height: u64,
+    state_root: String,
+    catching_up: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct NodeStatus {
+    node_info: NodeInfo,
+    sync_info: SyncInfo,
+}
+
+// A vertex as `dag_vertex` returns it; clients call the submitter the proposer
+#[derive(Debug, Serialize)]
+struct VertexView {
+    #[serde(flatten)]
+    details: VertexDetails,
+    proposer: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VertexParams {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RelatedParams {
+    id: String,
+    depth: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PathParams {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    since: Option<String>,
+    until: Option<String>,
+    proposal: Option<String>,
+    submitter: Option<String>,
+    hash: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+// Merkle tree position: a level of subtree hashes or a single bucket
+#[derive(Debug, Deserialize)]
+struct MerkleParams {
+    level: Option<u32>,
+    bucket: Option<usize>,
+}
+
+async fn status() -> ApiResult {
+    let info = dag::get_dag_info().await?;
+    let node_id = state::manager().read(|state| state.node_id.clone())?;
+    let federation = state::get_extension::<FederationConfig>()?;
+
+    result(NodeStatus {
+        node_info: NodeInfo {
+            id: node_id,
+            network: federation.as_ref().map(|config| config.federation_name.clone()).unwrap_or_default(),
+            moniker: federation.map(|config| config.node_name).unwrap_or_default(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            public_key: signing::public_key(signing::node_key()?),
+        },
+        sync_info: SyncInfo {
+            latest_block_height: info.vertex_count,
+            latest_block_time: info.latest_update,
+            dag_height: info.height,
+            state_root: info.state_root,
+            catching_up: false,
+        },
+    })
+}
+
+async fn dag_info() -> ApiResult {
+    result(json!({ "dag_info": dag::get_dag_info().await? }))
+}
+
+async fn dag_vertex(Query(params): Query<VertexParams>) -> ApiResult {
+    let details = dag::get_vertex_details(&params.id).map_err(ApiError::not_found)?;
+    let proposer = details.vertex.submitter.clone();
+    result(json!({ "vertex": VertexView { details, proposer } }))
+}
+
+async fn dag_ancestors(Query(params): Query<RelatedParams>) -> ApiResult {
+    let ancestors = dag::get_ancestors(&params.id, params.depth).map_err(ApiError::not_found)?;
+    result(json!({ "ancestors": ancestors }))
+}
+
+async fn dag_descendants(Query(params): Query<RelatedParams>) -> ApiResult {
+    let descendants = dag::get_descendants(&params.id, params.depth).map_err(ApiError::not_found)?;
+    result(json!({ "descendants": descendants }))
+}
+
+async fn dag_path(Query(params): Query<PathParams>) -> ApiResult {
+    match dag::find_path(&params.from, &params.to).map_err(ApiError::not_found)? {
+        Some(path) => result(json!({ "path": path })),
+        None => Err(ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: format!("{} does not descend from {}", params.to, params.from),
+        }),
+    }
+}
+
+async fn list_vertices(Query(params): Query<ListParams>) -> ApiResult {
+    let query = VertexQuery {
+        since: params.since.as_deref().map(dag::parse_timestamp).transpose()?,
+        until: params.until.as_deref().map(dag::parse_timestamp).transpose()?,
+        proposal_id: params.proposal,
+        submitter: params.submitter,
+        hash: params.hash,
+    };
+    result(dag::query_vertices(&query, params.offset, params.limit)?)
+}
+
+// Vertices broadcast by peers (see `federation::broadcast_vertex`). Only
+// connections from a configured peer's address are accepted; the vertex
+// itself must be signed by a federation member (see `dag::verify_submitter`).
+async fn ingest_vertex(
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    body: Result<Json<Vertex>, JsonRejection>,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    if !federation::is_peer_address(remote.ip()).await? {
+        warn!("Refused a vertex from {}, which is not a federation peer", remote);
+        return Err(ApiError {
+            status: StatusCode::FORBIDDEN,
+            message: format!("{} is not the address of a federation peer", remote.ip()),
+        });
+    }
+
+    let Json(vertex) = body?;
+    let id = vertex.id.clone();
+
+    let outcome = dag::ingest_vertex(vertex).await.map_err(|e| {
+        warn!("Rejected vertex {} from a peer: {}", id, e);
+        ApiError::from(e)
+    })?;
+
+    let status = match outcome {
+        IngestStatus::Added => StatusCode::CREATED,
+        IngestStatus::Known => StatusCode::OK,
+        IngestStatus::Held => StatusCode::ACCEPTED,
+    };
+    Ok((status, Json(json!({ "result": { "id": id, "status": outcome } }))))
+}
+
+// Merkle root over the vertex set, for a quick comparison with a peer
+async fn dag_root() -> ApiResult {
+    let info = dag::get_dag_info().await?;
+    result(json!({ "state_root": info.state_root, "vertex_count": info.vertex_count }))
+}
+
+// Same as `dag hashes`: the subtree hashes at `level`, or the vertex ids in
+// `bucket`. Comparing hashes level by level narrows a mismatch down to the
+// buckets that differ.
+async fn dag_hashes(Query(params): Query<MerkleParams>) -> ApiResult {
+    if let Some(bucket) = params.bucket {
+        return result(json!({ "bucket": bucket, "vertices": dag::get_bucket_vertices(bucket)? }));
+    }
+
+    let level = params.level.unwrap_or(0);
+    result(json!({ "level": level, "hashes": dag::get_range_hashes(level)? }))
+}
+
+// Merkle comparison for catching up with a peer, like `dag_root` and
+// `dag_hashes`, except that a bucket is sent with its vertices so the peer
+// can ingest the ones it lacks. Vertices pruned to cold storage are listed by
+// id only.
+async fn dag_sync(Query(params): Query<MerkleParams>) -> ApiResult {
+    if let Some(bucket) = params.bucket {
+        let mut vertices = Vec::new();
+        let mut pruned = Vec::new();
+        for id in dag::get_bucket_vertices(bucket)? {
+            match state::get_vertex(&id)? {
+                Some(vertex) => vertices.push(vertex),
+                None => pruned.push(id),
+            }
+        }
+        return result(json!({ "bucket": bucket, "vertices": vertices, "pruned": pruned }));
+    }
+
+    if params.level.is_some() {
+        return dag_hashes(Query(params)).await;
+    }
+
+    dag_root().await
+}
+
+async fn list_queue() -> ApiResult {
+    result(json!({ "proposals": queue::get_proposal_statuses()? }))
+}
+
+fn router() -> Router {
+    Router::new()
+        .route("/status", get(status))
+        .route("/dag_info", get(dag_info))
+        .route("/dag_vertex", get(dag_vertex))
+        .route("/dag_ancestors", get(dag_ancestors))
+        .route("/dag_descendants", get(dag_descendants))
+        .route("/dag_path", get(dag_path))
+        .route("/dag/vertices", get(list_vertices).post(ingest_vertex))
+        .route("/dag/root", get(dag_root))
+        .route("/dag/hashes", get(dag_hashes))
+        .route("/dag/sync", get(dag_sync))
+        .route("/queue", get(list_queue))
+}
+
+// Bind the node API and serve it in the background, returning the address it
+// is bound to. A bind failure is returned, so the node does not run without
+// the API it was configured to host.
+pub fn spawn(listen: SocketAddr) -> NodeResult<SocketAddr> {
+    let server = axum::Server::try_bind(&listen)
+        .map_err(|e| NodeError::Config(format!("Failed to bind the node API to {}: {}", listen, e)))?
+        .serve(router().into_make_service_with_connect_info::<SocketAddr>());
+
+    let bound = server.local_addr();
+    info!("Serving the node API on http://{}", bound);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Node API server stopped: {}", e);
+        }
+    });
+
+    Ok(bound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::federation::Peer;
+    use crate::test_support::data_dir;
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn rejects_unsigned_vertices_from_peers() {
+        let _dir = data_dir();
+        state::init().unwrap();
+        state::put_extension(&FederationConfig {
+            federation_name: "test".to_string(),
+            node_id: "node".to_string(),
+            node_name: "node".to_string(),
+            peers: vec![Peer {
+                id: "peer".to_string(),
+                name: "peer".to_string(),
+                address: "http://127.0.0.1:9".to_string(),
+                last_seen: None,
+                public_key: String::new(),
+            }],
+            sync_endpoint: String::new(),
+            listen_address: "127.0.0.1:0".to_string(),
+        }).unwrap();
+        let api = spawn("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let unversioned = json!({
+            "id": "0b6c8f7e-5d0a-4a55-9a5e-7f1c8b3e2d10",
+            "proposal_id": "1",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "hash": "abc",
+            "parents": ["forged-parent"],
+            "submitter": "someone",
+        });
+        let mut legacy = unversioned.clone();
+        legacy["version"] = json!(crate::vertex::LEGACY_VERTEX_FORMAT_VERSION);
+
+        let client = reqwest::Client::new();
+        for vertex in [unversioned, legacy] {
+            let response = client.post(format!("http://{}/dag/vertices", api))
+                .json(&vertex)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        }
+        assert_eq!(state::manager().read(|state| state.vertex_count()).unwrap(), 0);
+    }
+}
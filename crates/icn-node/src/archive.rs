@@ -0,0 +1,473 @@
+use crate::crypto::{self, KeySource};
+use crate::error::{NodeError, NodeResult};
+use crate::state;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, info};
+
+// Layout version of archives written by this build
+pub const ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_NAME: &str = "manifest.json";
+const STATE_NAME: &str = "state.json";
+
+// Data directory entries copied as they are on disk
+const DATA_ENTRIES: &[&str] = &[
+    "logs/dag.log",
+    "logs/dag",
+    "queue",
+    "executed",
+    "output",
+    "storage",
+    "checkpoints",
+    "dag/archive",
+    "identity.json",
+    "keys",
+    "encryption.json",
+];
+
+// Describes an archive and the checksum of every file in it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub node_version: String,
+    pub created: DateTime<Utc>,
+    pub node_id: String,
+    pub schema_version: u32,
+    pub encrypted: bool,
+    pub files: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub sha256: String,
+}
+
+// Reader that hashes everything read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self { inner, hasher: Sha256::new(), size: 0 }
+    }
+
+    fn finish(self) -> ManifestEntry {
+        ManifestEntry {
+            size: self.size,
+            sha256: format!("{:x}", self.hasher.finalize()),
+        }
+    }
+}
+
+// Write a node archive containing the state and the node's data files.
+// The manifest is written last, once every checksum is known.
+pub fn export(output: &Path) -> NodeResult<Manifest> {
+    let data_dir = state::get_state_dir()?;
+    let current = state::snapshot()?;
+
+    let file = File::create(output)
+        .map_err(|e| NodeError::State(format!("Failed to create archive {:?}: {}", output, e)))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    let mut files = BTreeMap::new();
+
+    // The state is exported from memory so any backend can be imported into any other
+    let snapshot = crypto::seal(&state::encode_snapshot(&current)?)?;
+    files.insert(STATE_NAME.to_string(), append_bytes(&mut builder, STATE_NAME, &snapshot)?);
+
+    for entry in DATA_ENTRIES {
+        let path = data_dir.join(entry);
+        append_tree(&mut builder, &path, entry, &mut files)?;
+    }
+
+    let manifest = Manifest {
+        format_version: ARCHIVE_FORMAT_VERSION,
+        node_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: Utc::now(),
+        node_id: current.node_id.clone(),
+        schema_version: state::STATE_SCHEMA_VERSION,
+        encrypted: crypto::is_enabled(),
+        files,
+    };
+
+    let content = serde_json::to_vec_pretty(&manifest)?;
+    append_bytes(&mut builder, MANIFEST_NAME, &content)?;
+
+    builder.into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|file| file.sync_all())
+        .map_err(|e| NodeError::State(format!("Failed to write archive {:?}: {}", output, e)))?;
+
+    info!("Exported {} files to {:?}", manifest.files.len(), output);
+    Ok(manifest)
+}
+
+fn tar_header(size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    header.set_entry_type(tar::EntryType::Regular);
+    header
+}
+
+fn append_bytes(builder: &mut tar::Builder<GzEncoder<File>>, name: &str, content: &[u8]) -> NodeResult<ManifestEntry> {
+    let mut header = tar_header(content.len() as u64);
+    let mut reader = HashingReader::new(content);
+
+    builder.append_data(&mut header, name, &mut reader)
+        .map_err(|e| NodeError::State(format!("Failed to add {} to archive: {}", name, e)))?;
+
+    Ok(reader.finish())
+}
+
+// Add a file, or every file below a directory, under the given archive name
+fn append_tree(
+    builder: &mut tar::Builder<GzEncoder<File>>,
+    path: &Path,
+    name: &str,
+    files: &mut BTreeMap<String, ManifestEntry>,
+) -> NodeResult<()> {
+    if path.is_dir() {
+        let mut entries: Vec<_> = fs::read_dir(path)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let child = format!("{}/{}", name, entry.file_name().to_string_lossy());
+            append_tree(builder, &entry.path(), &child, files)?;
+        }
+    } else if path.is_file() {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        // Hash exactly the bytes that go into the archive, even if the file grows meanwhile
+        let mut header = tar_header(size);
+        let mut reader = HashingReader::new(file.take(size));
+        builder.append_data(&mut header, name, &mut reader)
+            .map_err(|e| NodeError::State(format!("Failed to add {} to archive: {}", name, e)))?;
+
+        debug!("Archived {}", name);
+        files.insert(name.to_string(), reader.finish());
+    }
+
+    Ok(())
+}
+
+// Archive names must stay inside the data directory
+fn safe_relative_path(name: &Path) -> NodeResult<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => {
+                return Err(NodeError::State(format!("Refusing archive entry with unsafe path {:?}", name)));
+            }
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err(NodeError::State("Refusing archive entry with an empty path".to_string()));
+    }
+
+    Ok(path)
+}
+
+// Unpack an archive into `staging`, returning its manifest and the checksums
+// of the files actually found
+fn unpack(archive: &Path, staging: &Path) -> NodeResult<(Manifest, BTreeMap<String, ManifestEntry>)> {
+    let file = File::open(archive)
+        .map_err(|e| NodeError::State(format!("Failed to open archive {:?}: {}", archive, e)))?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+
+    let mut manifest = None;
+    let mut found = BTreeMap::new();
+
+    let entries = tar.entries()
+        .map_err(|e| NodeError::State(format!("Failed to read archive {:?}: {}", archive, e)))?;
+
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| NodeError::State(format!("Failed to read archive {:?}: {}", archive, e)))?;
+
+        match entry.header().entry_type() {
+            tar::EntryType::Regular => {}
+            tar::EntryType::Directory => continue,
+            other => {
+                return Err(NodeError::State(format!("Refusing archive entry of type {:?}", other)));
+            }
+        }
+
+        let relative = safe_relative_path(&entry.path()?)?;
+        let name = relative.to_string_lossy().replace('\\', "/");
+
+        if name == MANIFEST_NAME {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            let parsed: Manifest = serde_json::from_slice(&content)
+                .map_err(|e| NodeError::State(format!("Invalid archive manifest: {}", e)))?;
+            manifest = Some(parsed);
+            continue;
+        }
+
+        let target = staging.join(&relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut reader = HashingReader::new(&mut entry);
+        io::copy(&mut reader, &mut File::create(&target)?)?;
+        found.insert(name, reader.finish());
+    }
+
+    let manifest = manifest
+        .ok_or_else(|| NodeError::State(format!("Archive {:?} has no manifest", archive)))?;
+
+    Ok((manifest, found))
+}
+
+// Check the unpacked files against the manifest
+fn verify(manifest: &Manifest, found: &BTreeMap<String, ManifestEntry>) -> NodeResult<()> {
+    if manifest.format_version > ARCHIVE_FORMAT_VERSION {
+        return Err(NodeError::State(format!(
+            "Archive format version {} is newer than the version {} supported by icn-node {}",
+            manifest.format_version, ARCHIVE_FORMAT_VERSION, env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    if !manifest.files.contains_key(STATE_NAME) {
+        return Err(NodeError::State("Archive manifest does not list a state snapshot".to_string()));
+    }
+
+    for (name, expected) in &manifest.files {
+        match found.get(name) {
+            Some(actual) if actual == expected => {}
+            Some(actual) => {
+                return Err(NodeError::State(format!(
+                    "Checksum mismatch for {} in archive: expected sha256 {} ({} bytes), found {} ({} bytes)",
+                    name, expected.sha256, expected.size, actual.sha256, actual.size
+                )));
+            }
+            None => {
+                return Err(NodeError::State(format!("File {} listed in the archive manifest is missing", name)));
+            }
+        }
+    }
+
+    if let Some(extra) = found.keys().find(|name| !manifest.files.contains_key(*name)) {
+        return Err(NodeError::State(format!("Archive contains {} which is not in its manifest", extra)));
+    }
+
+    Ok(())
+}
+
+// Whether a data directory is missing or empty
+fn is_fresh(data_dir: &Path) -> NodeResult<bool> {
+    if !data_dir.exists() {
+        return Ok(true);
+    }
+
+    Ok(fs::read_dir(data_dir)?.next().is_none())
+}
+
+// Verify an archive and restore it into a fresh data directory. Nothing is
+// written outside a staging directory until every checksum has matched.
+pub fn import(archive: &Path, key_source: Option<KeySource>) -> NodeResult<Manifest> {
+    let data_dir = state::get_state_dir()?;
+
+    if !is_fresh(&data_dir)? {
+        return Err(NodeError::State(format!(
+            "Data directory {:?} is not empty; import into a fresh data directory", data_dir
+        )));
+    }
+
+    fs::create_dir_all(&data_dir)?;
+    let staging = data_dir.join(format!(".import-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&staging)?;
+
+    let result = import_staged(archive, &staging, &data_dir, key_source);
+
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+
+    // Leave the data directory fresh again so the import can be retried
+    if result.is_err() {
+        for entry in fs::read_dir(&data_dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                fs::remove_dir_all(&path)?;
+            } else {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+
+    result
+}
+
+fn import_staged(archive: &Path, staging: &Path, data_dir: &Path, key_source: Option<KeySource>) -> NodeResult<Manifest> {
+    let (manifest, found) = unpack(archive, staging)?;
+    verify(&manifest, &found)?;
+
+    info!(
+        "Verified archive of node {} ({} files, created {} by icn-node {})",
+        manifest.node_id, manifest.files.len(), manifest.created, manifest.node_version
+    );
+
+    // Install the key config first so a missing or wrong key is refused
+    // before any other file is moved into place
+    let key_config = staging.join("encryption.json");
+    if key_config.exists() {
+        fs::rename(&key_config, data_dir.join("encryption.json"))?;
+    }
+    crypto::init(key_source)?;
+
+    let (imported, _) = state::read_snapshot(&staging.join(STATE_NAME))?;
+
+    for entry in fs::read_dir(staging)? {
+        let entry = entry?;
+        if entry.file_name() == STATE_NAME {
+            continue;
+        }
+
+        fs::rename(entry.path(), data_dir.join(entry.file_name()))?;
+    }
+
+    state::import_state(imported)?;
+
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn manifest(files: BTreeMap<String, ManifestEntry>) -> Manifest {
+        Manifest {
+            format_version: ARCHIVE_FORMAT_VERSION,
+            node_version: env!("CARGO_PKG_VERSION").to_string(),
+            created: Utc::now(),
+            node_id: "node-1".to_string(),
+            schema_version: state::STATE_SCHEMA_VERSION,
+            encrypted: false,
+            files,
+        }
+    }
+
+    // Write an archive holding `entries`, with a manifest built by `describe`
+    // from the checksums of what was written
+    fn write_archive(
+        path: &Path,
+        entries: &[(&str, &[u8])],
+        describe: impl FnOnce(BTreeMap<String, ManifestEntry>) -> Manifest,
+    ) {
+        let file = File::create(path).unwrap();
+        let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+        let mut files = BTreeMap::new();
+        for (name, content) in entries {
+            files.insert(name.to_string(), append_bytes(&mut builder, name, content).unwrap());
+        }
+
+        let content = serde_json::to_vec(&describe(files)).unwrap();
+        append_bytes(&mut builder, MANIFEST_NAME, &content).unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn unpack_and_verify(dir: &TempDir) -> NodeResult<Manifest> {
+        let staging = dir.path().join("staging");
+        fs::create_dir_all(&staging)?;
+        let (manifest, found) = unpack(&dir.path().join("node.tar.gz"), &staging)?;
+        verify(&manifest, &found)?;
+        Ok(manifest)
+    }
+
+    const ENTRIES: &[(&str, &[u8])] = &[(STATE_NAME, b"{}"), ("output/1.json", b"result")];
+
+    #[test]
+    fn accepts_an_intact_archive() {
+        let dir = TempDir::new().unwrap();
+        write_archive(&dir.path().join("node.tar.gz"), ENTRIES, manifest);
+
+        let manifest = unpack_and_verify(&dir).unwrap();
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(fs::read(dir.path().join("staging/output/1.json")).unwrap(), b"result");
+    }
+
+    #[test]
+    fn rejects_a_changed_file() {
+        let dir = TempDir::new().unwrap();
+        write_archive(&dir.path().join("node.tar.gz"), ENTRIES, |mut files| {
+            files.get_mut("output/1.json").unwrap().sha256 = format!("{:x}", Sha256::digest(b"other"));
+            manifest(files)
+        });
+
+        let err = unpack_and_verify(&dir).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch for output/1.json"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_missing_and_unlisted_files() {
+        let dir = TempDir::new().unwrap();
+        write_archive(&dir.path().join("node.tar.gz"), ENTRIES, |mut files| {
+            files.insert("output/2.json".to_string(), ManifestEntry { size: 0, sha256: String::new() });
+            manifest(files)
+        });
+        let err = unpack_and_verify(&dir).unwrap_err();
+        assert!(err.to_string().contains("output/2.json listed in the archive manifest is missing"), "{}", err);
+
+        let dir = TempDir::new().unwrap();
+        write_archive(&dir.path().join("node.tar.gz"), ENTRIES, |mut files| {
+            files.remove("output/1.json");
+            manifest(files)
+        });
+        let err = unpack_and_verify(&dir).unwrap_err();
+        assert!(err.to_string().contains("output/1.json which is not in its manifest"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_archives_without_state_or_from_newer_builds() {
+        let dir = TempDir::new().unwrap();
+        write_archive(&dir.path().join("node.tar.gz"), &ENTRIES[1..], manifest);
+        assert!(unpack_and_verify(&dir).unwrap_err().to_string().contains("state snapshot"));
+
+        let dir = TempDir::new().unwrap();
+        write_archive(&dir.path().join("node.tar.gz"), ENTRIES, |files| Manifest {
+            format_version: ARCHIVE_FORMAT_VERSION + 1,
+            ..manifest(files)
+        });
+        assert!(unpack_and_verify(&dir).unwrap_err().to_string().contains("newer than the version"));
+    }
+
+    #[test]
+    fn refuses_paths_outside_the_data_directory() {
+        assert_eq!(safe_relative_path(Path::new("./output/1.json")).unwrap(), PathBuf::from("output/1.json"));
+        assert!(safe_relative_path(Path::new("../state.json")).is_err());
+        assert!(safe_relative_path(Path::new("output/../../state.json")).is_err());
+        assert!(safe_relative_path(Path::new("/etc/passwd")).is_err());
+        assert!(safe_relative_path(Path::new(".")).is_err());
+    }
+}
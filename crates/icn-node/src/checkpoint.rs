@@ -0,0 +1,594 @@
+use crate::crypto;
+use crate::dag::{DagGraph, PrunedHistory, PrunedSegment, VertexMerkle};
+use crate::error::{NodeError, NodeResult};
+use crate::state::{self, StateExtension};
+use crate::vertex::Vertex;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{debug, info, warn};
+
+const SEGMENT_SUFFIX: &str = ".jsonl.gz";
+const INDEX_SUFFIX: &str = ".index.json";
+
+// Segment indexes of an archive directory, oldest first
+type CachedIndex = (PathBuf, Arc<Vec<PrunedSegment>>);
+
+// Segment indexes of the archive directory they were loaded from
+static PRUNED_INDEX: Lazy<RwLock<Option<CachedIndex>>> = Lazy::new(|| RwLock::new(None));
+
+// The DAG and CoVM storage as they were after a given number of vertices
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub id: u64,
+    pub created: DateTime<Utc>,
+    // Last vertex covered by the checkpoint, if any
+    pub vertex_id: Option<String>,
+    // Vertices covered, counted in recording order including pruned ones
+    pub vertex_count: usize,
+    pub state_root: String,
+    pub tips: Vec<String>,
+    pub height: u64,
+    // SHA-256 over the plaintext storage files (see `hash_storage`)
+    pub storage_hash: String,
+}
+
+// Checkpoints kept in the node state, oldest first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Checkpoints {
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl StateExtension for Checkpoints {
+    const NAMESPACE: &'static str = "dag_checkpoints";
+}
+
+// Outcome of pruning
+#[derive(Debug)]
+pub struct PruneReport {
+    pub checkpoint: u64,
+    pub pruned: usize,
+    pub retained: usize,
+    pub segment: Option<PathBuf>,
+}
+
+// Directory holding checkpoint storage copies
+pub fn get_checkpoints_dir() -> NodeResult<PathBuf> {
+    Ok(state::get_state_dir()?.join("checkpoints"))
+}
+
+// Directory holding pruned vertices
+pub fn get_archive_dir() -> NodeResult<PathBuf> {
+    Ok(state::get_state_dir()?.join("dag").join("archive"))
+}
+
+fn checkpoint_storage_dir(id: u64) -> NodeResult<PathBuf> {
+    Ok(get_checkpoints_dir()?.join(format!("{:08}", id)).join("storage"))
+}
+
+// List checkpoints, oldest first
+pub fn list_checkpoints() -> NodeResult<Vec<Checkpoint>> {
+    Ok(state::get_extension::<Checkpoints>()?.unwrap_or_default().checkpoints)
+}
+
+// Look up a checkpoint by id
+pub fn get_checkpoint(id: u64) -> NodeResult<Checkpoint> {
+    list_checkpoints()?
+        .into_iter()
+        .find(|checkpoint| checkpoint.id == id)
+        .ok_or_else(|| NodeError::Dag(format!("Checkpoint {} not found", id)))
+}
+
+// Copy a directory tree as it is on disk
+fn copy_tree(src: &Path, dst: &Path) -> NodeResult<()> {
+    if src.is_file() {
+        fs::copy(src, dst)?;
+    } else if src.is_dir() {
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Hash a storage directory over its relative file names and plaintext
+// contents, so sealed and plaintext copies of the same storage match
+pub fn hash_storage(dir: &Path) -> NodeResult<String> {
+    fn collect(dir: &Path, prefix: &str, files: &mut BTreeMap<String, PathBuf>) -> NodeResult<()> {
+        if !dir.is_dir() {
+            return Ok(());
+        }
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            let path = entry.path();
+            if path.is_dir() {
+                collect(&path, &format!("{}/", name), files)?;
+            } else {
+                files.insert(name, path);
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut files = BTreeMap::new();
+    collect(dir, "", &mut files)?;
+
+    let mut hasher = Sha256::new();
+    for (name, path) in files {
+        let content = crypto::read_file(&path)?;
+        hasher.update((name.len() as u64).to_be_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update((content.len() as u64).to_be_bytes());
+        hasher.update(&content);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Record a checkpoint of the DAG and a copy of the node's CoVM storage.
+// Run it between executions so the two match.
+pub fn create_checkpoint() -> NodeResult<Checkpoint> {
+    let (vertex_id, vertex_count, state_root, tips, height) = state::manager().read(|state| {
+        let graph = state.dag();
+        (
+            state.dag_vertices.last().map(|vertex| vertex.id.clone()),
+            state.vertex_count(),
+            graph.merkle().root(),
+            graph.tips().cloned().collect::<Vec<_>>(),
+            graph.height(),
+        )
+    })?;
+
+    let id = list_checkpoints()?.last().map_or(1, |last| last.id + 1);
+
+    let storage_dir = checkpoint_storage_dir(id)?;
+    if storage_dir.exists() {
+        fs::remove_dir_all(&storage_dir)?;
+    }
+    fs::create_dir_all(&storage_dir)?;
+    copy_tree(&state::get_state_dir()?.join("storage"), &storage_dir)?;
+
+    let checkpoint = Checkpoint {
+        id,
+        created: Utc::now(),
+        vertex_id,
+        vertex_count,
+        state_root,
+        tips,
+        height,
+        storage_hash: hash_storage(&storage_dir)?,
+    };
+
+    let recorded = checkpoint.clone();
+    state::update_extension::<Checkpoints, _>(move |checkpoints| checkpoints.checkpoints.push(recorded))?;
+
+    info!("Created checkpoint {} at {} vertices", checkpoint.id, checkpoint.vertex_count);
+    Ok(checkpoint)
+}
+
+// Copy a checkpoint's storage into `dst` and check it against the recorded hash
+pub fn restore_storage(checkpoint: &Checkpoint, dst: &Path) -> NodeResult<()> {
+    copy_tree(&checkpoint_storage_dir(checkpoint.id)?, dst)?;
+
+    let hash = hash_storage(dst)?;
+    if hash != checkpoint.storage_hash {
+        return Err(NodeError::Dag(format!(
+            "Storage of checkpoint {} does not match its hash (expected {}, found {})",
+            checkpoint.id, checkpoint.storage_hash, hash
+        )));
+    }
+
+    Ok(())
+}
+
+// Name of the segment holding the vertices recorded at positions `start..end`,
+// or of its index
+fn segment_name(start: usize, end: usize, suffix: &str) -> String {
+    format!("segment_{:010}_{:010}{}", start, end, suffix)
+}
+
+// Positions covered by a segment or index file, from its name
+fn segment_range(path: &Path, suffix: &str) -> Option<(usize, usize)> {
+    let name = path.file_name()?.to_str()?;
+    let (start, end) = name.strip_prefix("segment_")?.strip_suffix(suffix)?.split_once('_')?;
+    Some((start.parse().ok()?, end.parse().ok()?))
+}
+
+// Write vertices to a compressed, sealed JSON Lines segment
+fn write_segment(path: &Path, vertices: &[Vertex]) -> NodeResult<()> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for vertex in vertices {
+        serde_json::to_writer(&mut encoder, vertex)?;
+        encoder.write_all(b"\n")?;
+    }
+    let compressed = encoder.finish()?;
+
+    state::write_atomic(path, &crypto::seal(&compressed)?)
+        .map_err(|e| NodeError::Dag(format!("Failed to write archive segment {:?}: {}", path, e)))
+}
+
+fn read_segment(path: &Path) -> NodeResult<Vec<Vertex>> {
+    let compressed = crypto::read_file(path)?;
+
+    let mut content = Vec::new();
+    GzDecoder::new(compressed.as_slice()).read_to_end(&mut content)
+        .map_err(|e| NodeError::Dag(format!("Failed to decompress archive segment {:?}: {}", path, e)))?;
+
+    content.split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(|line| serde_json::from_slice(line)
+            .map_err(|e| NodeError::Dag(format!("Invalid vertex in archive segment {:?}: {}", path, e))))
+        .collect()
+}
+
+// Files in the archive directory with the given suffix that cover positions
+// before `pruned`, in recording order. Files past it are left over from an
+// interrupted prune.
+fn list_archive(suffix: &str, pruned: usize) -> NodeResult<Vec<PathBuf>> {
+    let archive_dir = get_archive_dir()?;
+    if !archive_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&archive_dir)? {
+        let path = entry?.path();
+        if segment_range(&path, suffix).is_some_and(|(_, end)| end <= pruned) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn pruned_count() -> NodeResult<usize> {
+    state::manager().read(|state| state.pruned_history.as_ref().map_or(0, |pruned| pruned.vertex_count))
+}
+
+// Archive segments in recording order
+fn list_segments() -> NodeResult<Vec<PathBuf>> {
+    list_archive(SEGMENT_SUFFIX, pruned_count()?)
+}
+
+// All pruned vertices, in recording order
+pub fn archived_vertices() -> NodeResult<Vec<Vertex>> {
+    let mut vertices = Vec::new();
+    for segment in list_segments()? {
+        vertices.extend(read_segment(&segment)?);
+    }
+    Ok(vertices)
+}
+
+// Look up a pruned vertex in cold storage
+pub fn find_archived_vertex(id: &str) -> NodeResult<Option<Vertex>> {
+    let pruned = pruned_count()?;
+    let segments = pruned_segments();
+
+    // The segment indexes say which segment to open
+    let segment = segments.iter()
+        .filter(|segment| segment.end <= pruned)
+        .find(|segment| segment.ids.iter().any(|pruned_id| pruned_id == id));
+    let path = match segment {
+        Some(segment) => get_archive_dir()?.join(segment_name(segment.start, segment.end, SEGMENT_SUFFIX)),
+        None => return Ok(None),
+    };
+
+    let vertex = read_segment(&path)?.into_iter().find(|vertex| vertex.id == id);
+    if vertex.is_some() {
+        debug!("Found vertex {} in archive segment {:?}", id, path);
+    }
+    Ok(vertex)
+}
+
+// Segment indexes of the data directory, empty until `load_pruned_index` ran
+pub fn pruned_segments() -> Arc<Vec<PrunedSegment>> {
+    let archive_dir = get_archive_dir().ok();
+    PRUNED_INDEX.read().ok()
+        .and_then(|index| index.as_ref()
+            .filter(|(dir, _)| Some(dir) == archive_dir.as_ref())
+            .map(|(_, segments)| segments.clone()))
+        .unwrap_or_default()
+}
+
+// Replace the cached index of the segment starting at the same position
+fn cache_segment(segment: Option<PrunedSegment>, start: usize) -> NodeResult<()> {
+    let archive_dir = get_archive_dir()?;
+    let mut segments: Vec<PrunedSegment> = pruned_segments().iter()
+        .filter(|cached| cached.start != start)
+        .cloned()
+        .collect();
+    segments.extend(segment);
+    segments.sort_by_key(|segment| segment.start);
+
+    let mut index = PRUNED_INDEX.write()
+        .map_err(|e| NodeError::Dag(format!("Failed to lock the pruned vertex index: {}", e)))?;
+    *index = Some((archive_dir, Arc::new(segments)));
+    Ok(())
+}
+
+// Merkle root over the ids in the segments that cover the first `count` vertices
+fn pruned_merkle_root(segments: &[PrunedSegment], count: usize) -> String {
+    let mut merkle = VertexMerkle::default();
+    for segment in segments.iter().filter(|segment| segment.end <= count) {
+        for id in &segment.ids {
+            merkle.insert(id);
+        }
+    }
+    merkle.root()
+}
+
+// Read the segment indexes of the archive directory and check them against
+// the pruned history summary of the state being loaded
+pub fn load_pruned_index(pruned: Option<&PrunedHistory>) -> NodeResult<()> {
+    let pruned = match pruned {
+        Some(pruned) => pruned,
+        None => {
+            *PRUNED_INDEX.write()
+                .map_err(|e| NodeError::Dag(format!("Failed to lock the pruned vertex index: {}", e)))? = None;
+            return Ok(());
+        }
+    };
+
+    let mut segments = Vec::new();
+    for path in list_archive(INDEX_SUFFIX, pruned.vertex_count)? {
+        let segment: PrunedSegment = serde_json::from_slice(&crypto::read_file(&path)?)
+            .map_err(|e| NodeError::Dag(format!("Invalid archive index {:?}: {}", path, e)))?;
+        segments.push(segment);
+    }
+
+    let covered = segments.iter().try_fold(0, |next, segment| (segment.start == next).then_some(segment.end));
+    let root = pruned_merkle_root(&segments, pruned.vertex_count);
+    if covered != Some(pruned.vertex_count) || root != pruned.merkle_root {
+        return Err(NodeError::Dag(format!(
+            "The archive indexes in {:?} do not match the {} pruned vertices recorded in the state",
+            get_archive_dir()?, pruned.vertex_count
+        )));
+    }
+
+    *PRUNED_INDEX.write()
+        .map_err(|e| NodeError::Dag(format!("Failed to lock the pruned vertex index: {}", e)))? =
+        Some((get_archive_dir()?, Arc::new(segments)));
+    Ok(())
+}
+
+// Move the vertices covered by a checkpoint (the newest by default) to cold
+// storage, keeping a summary of them in the state. Older checkpoints are
+// dropped since their history is no longer held in the state.
+pub fn prune(checkpoint_id: Option<u64>) -> NodeResult<PruneReport> {
+    // Pick the vertices to move without holding up writers while the
+    // segment is written
+    let (checkpoint, already, segment, claims) = state::manager().read(|state| -> NodeResult<_> {
+        let checkpoints = state.extension::<Checkpoints>()?.unwrap_or_default().checkpoints;
+        let checkpoint = match checkpoint_id {
+            Some(id) => checkpoints.into_iter().find(|checkpoint| checkpoint.id == id)
+                .ok_or_else(|| NodeError::Dag(format!("Checkpoint {} not found", id)))?,
+            None => checkpoints.last().cloned()
+                .ok_or_else(|| NodeError::Dag("No checkpoint to prune to; create one with `dag checkpoint`".to_string()))?,
+        };
+
+        let already = state.pruned_history.as_ref().map_or(0, |pruned| pruned.vertex_count);
+        let count = checkpoint.vertex_count.saturating_sub(already);
+        if count > state.dag_vertices.len() {
+            return Err(NodeError::Dag(format!(
+                "Checkpoint {} covers {} vertices but the DAG only holds {}",
+                checkpoint.id, checkpoint.vertex_count, state.vertex_count()
+            )));
+        }
+
+        let segment = state.dag_vertices[..count].to_vec();
+        let ids: HashSet<&str> = segment.iter().map(|vertex| vertex.id.as_str()).collect();
+        let claims = state.dag().claims_of(&ids);
+        Ok((checkpoint, already, segment, claims))
+    })??;
+
+    if segment.is_empty() {
+        let retained = state::manager().read(|state| state.dag_vertices.len())?;
+        return Ok(PruneReport { checkpoint: checkpoint.id, pruned: 0, retained, segment: None });
+    }
+
+    let archive_dir = get_archive_dir()?;
+    fs::create_dir_all(&archive_dir)?;
+
+    // Files starting at the same position are left over from an interrupted prune
+    for suffix in [SEGMENT_SUFFIX, INDEX_SUFFIX] {
+        for entry in fs::read_dir(&archive_dir)? {
+            let path = entry?.path();
+            if segment_range(&path, suffix).is_some_and(|(start, _)| start == already) {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+
+    let end = checkpoint.vertex_count;
+    let segment_file = archive_dir.join(segment_name(already, end, SEGMENT_SUFFIX));
+    let index_file = archive_dir.join(segment_name(already, end, INDEX_SUFFIX));
+    let index = PrunedSegment {
+        start: already,
+        end,
+        ids: segment.iter().map(|vertex| vertex.id.clone()).collect(),
+        claims,
+    };
+    write_segment(&segment_file, &segment)?;
+    crypto::write_file(&index_file, &serde_json::to_vec(&index)?)?;
+
+    let committed = state::manager().write(|state, tx| {
+        // Checkpoints are read under the writer lock so one created meanwhile is kept
+        let checkpoints = state.extension::<Checkpoints>()?.unwrap_or_default().checkpoints;
+        let current = state.pruned_history.as_ref().map_or(0, |pruned| pruned.vertex_count);
+        let count = segment.len();
+        if current != already || state.dag_vertices.get(count - 1).map(|vertex| &vertex.id) != index.ids.last() {
+            return Err(NodeError::Dag("The DAG was pruned by another process meanwhile; run the prune again".to_string()));
+        }
+
+        // Reindexing the state after the commit picks the new segment up
+        cache_segment(Some(index.clone()), already)?;
+
+        let retained = &state.dag_vertices[count..];
+        let pruned = summarize(state.pruned_history.as_ref(), state.dag(), &segment, retained, checkpoint.id);
+
+        let kept = Checkpoints {
+            checkpoints: checkpoints.iter().filter(|c| c.id >= checkpoint.id).cloned().collect(),
+        };
+        let dropped: Vec<u64> = checkpoints.iter().filter(|c| c.id < checkpoint.id).map(|c| c.id).collect();
+
+        tx.set("pruned_history", &pruned)?
+            .set("dag_vertices", retained)?
+            .put_extension(&kept)?;
+
+        Ok((retained.len(), dropped))
+    });
+
+    let (retained, dropped) = match committed {
+        Ok(committed) => committed,
+        Err(e) => {
+            if let Err(e) = cache_segment(None, already) {
+                warn!("Failed to drop the index of an unfinished archive segment: {}", e);
+            }
+            for path in [&segment_file, &index_file] {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("Failed to remove unfinished archive file {:?}: {}", path, e);
+                }
+            }
+            return Err(e);
+        }
+    };
+
+    for id in dropped {
+        let dir = get_checkpoints_dir()?.join(format!("{:08}", id));
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            warn!("Failed to remove storage of checkpoint {}: {}", id, e);
+        }
+    }
+
+    info!("Pruned {} vertices up to checkpoint {}", segment.len(), checkpoint.id);
+    Ok(PruneReport {
+        checkpoint: checkpoint.id,
+        pruned: segment.len(),
+        retained,
+        segment: Some(segment_file),
+    })
+}
+
+// Fold newly pruned vertices into the pruned history summary. The index of
+// their segment must already be cached.
+fn summarize(
+    previous: Option<&PrunedHistory>,
+    graph: &DagGraph,
+    segment: &[Vertex],
+    retained: &[Vertex],
+    checkpoint: u64,
+) -> PrunedHistory {
+    let mut pruned = previous.cloned().unwrap_or_default();
+    if previous.is_none() {
+        pruned.genesis_time = segment.first().map_or_else(Utc::now, |vertex| vertex.timestamp);
+    }
+
+    pruned.checkpoint = checkpoint;
+    pruned.vertex_count += segment.len();
+    if let Some(last) = segment.last() {
+        pruned.latest_time = last.timestamp;
+    }
+    for vertex in segment {
+        pruned.height = pruned.height.max(graph.height_of(&vertex.id).unwrap_or(0));
+    }
+
+    let ids: HashSet<&str> = segment.iter().map(|vertex| vertex.id.as_str()).collect();
+    let is_pruned = |id: &String| graph.is_pruned(id) || ids.contains(id.as_str());
+    pruned.roots = graph.roots().filter(|id| is_pruned(id)).cloned().collect();
+    pruned.tips = graph.tips().filter(|id| is_pruned(id)).cloned().collect();
+
+    let frontier: HashSet<&String> = pruned.tips.iter()
+        .chain(retained.iter().flat_map(|vertex| vertex.parents.iter()))
+        .filter(|id| is_pruned(id))
+        .collect();
+    pruned.frontier = frontier.into_iter()
+        .filter_map(|id| graph.height_of(id).map(|height| (id.clone(), height)))
+        .collect();
+    pruned.merkle_root = pruned_merkle_root(&pruned_segments(), pruned.vertex_count);
+
+    pruned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::data_dir;
+    use serial_test::serial;
+
+    fn segment(start: usize, ids: &[&str]) -> PrunedSegment {
+        PrunedSegment {
+            start,
+            end: start + ids.len(),
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+            claims: BTreeMap::new(),
+        }
+    }
+
+    fn write_index(segment: &PrunedSegment) {
+        let dir = get_archive_dir().unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(segment_name(segment.start, segment.end, INDEX_SUFFIX));
+        crypto::write_file(&path, &serde_json::to_vec(segment).unwrap()).unwrap();
+    }
+
+    fn summary(segments: &[PrunedSegment]) -> PrunedHistory {
+        let vertex_count = segments.last().map_or(0, |segment| segment.end);
+        PrunedHistory {
+            vertex_count,
+            merkle_root: pruned_merkle_root(segments, vertex_count),
+            ..PrunedHistory::default()
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn loads_segment_indexes_covered_by_the_state() {
+        let _dir = data_dir();
+        let segments = [segment(0, &["a", "b"]), segment(2, &["c"])];
+        for segment in &segments {
+            write_index(segment);
+        }
+        // Left over from a prune that never committed
+        write_index(&segment(3, &["d"]));
+
+        let pruned = summary(&segments);
+        load_pruned_index(Some(&pruned)).unwrap();
+        assert_eq!(pruned_segments().len(), 2);
+
+        let mut graph = DagGraph::default();
+        graph.seed(&pruned, &pruned_segments());
+        assert!(graph.is_pruned("c"));
+        assert!(!graph.contains("d"));
+        assert_eq!(graph.merkle().root(), pruned.merkle_root);
+    }
+
+    #[test]
+    #[serial]
+    fn rejects_segment_indexes_that_do_not_match_the_state() {
+        let _dir = data_dir();
+        write_index(&segment(0, &["a", "b"]));
+
+        // An index file is missing
+        let pruned = summary(&[segment(0, &["a", "b"]), segment(2, &["c"])]);
+        assert!(load_pruned_index(Some(&pruned)).is_err());
+
+        // An index lists other vertices than were pruned
+        let pruned = summary(&[segment(0, &["a", "x"])]);
+        assert!(load_pruned_index(Some(&pruned)).is_err());
+    }
+}
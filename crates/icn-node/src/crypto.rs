@@ -0,0 +1,725 @@
+use crate::checkpoint;
+use crate::dag_log;
+use crate::error::{NodeError, NodeResult};
+use crate::signing;
+use crate::state;
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use tracing::{debug, info};
+
+// Prefix of every encrypted blob: `icnenc1:<key id>:<base64 nonce + ciphertext>`
+const MAGIC: &str = "icnenc1";
+
+// Known plaintext sealed into the key config to tell a wrong key from damaged data
+const KEY_CHECK: &[u8] = b"icn-node encryption key check";
+
+const NONCE_LEN: usize = 24;
+const SALT_LEN: usize = 16;
+
+// Keys available to this process. Data is opened with whichever key sealed it
+// and sealed with `seal_with`, or left in plaintext when that is `None`.
+static KEYRING: Lazy<RwLock<Keyring>> = Lazy::new(|| RwLock::new(Keyring::default()));
+
+// Where the encryption key comes from
+#[derive(Debug, Clone)]
+pub enum KeySource {
+    Passphrase(String),
+    KeyFile(PathBuf),
+}
+
+// Key derivation parameters stored next to the encrypted data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyConfig {
+    version: u32,
+    kdf: String,
+    salt: String,
+    check: String,
+}
+
+#[derive(Default)]
+struct Keyring {
+    keys: Vec<DataKey>,
+    seal_with: Option<usize>,
+}
+
+struct DataKey {
+    id: String,
+    cipher: XChaCha20Poly1305,
+}
+
+// Files that were re-encrypted by a rekey
+#[derive(Debug, Default)]
+pub struct RekeyReport {
+    pub resealed: usize,
+    pub unchanged: usize,
+}
+
+impl KeySource {
+    // Key file from the CLI, falling back to a passphrase in the given environment variable
+    pub fn resolve(key_file: Option<&str>, passphrase_var: &str) -> Option<KeySource> {
+        if let Some(path) = key_file {
+            return Some(KeySource::KeyFile(PathBuf::from(shellexpand::tilde(path).to_string())));
+        }
+
+        match std::env::var(passphrase_var) {
+            Ok(passphrase) if !passphrase.is_empty() => Some(KeySource::Passphrase(passphrase)),
+            _ => None,
+        }
+    }
+
+    fn secret(&self) -> NodeResult<Vec<u8>> {
+        let secret = match self {
+            KeySource::Passphrase(passphrase) => passphrase.as_bytes().to_vec(),
+            KeySource::KeyFile(path) => {
+                let mut content = fs::read(path)
+                    .map_err(|e| NodeError::Crypto(format!("Failed to read key file {:?}: {}", path, e)))?;
+
+                // Key files written with `echo` end in a newline that is not part of the key
+                while content.last().is_some_and(|b| b.is_ascii_whitespace()) {
+                    content.pop();
+                }
+                content
+            }
+        };
+
+        if secret.is_empty() {
+            return Err(NodeError::Crypto("Encryption key is empty".to_string()));
+        }
+
+        Ok(secret)
+    }
+}
+
+impl KeyConfig {
+    // Fresh parameters for a new key
+    fn generate(source: &KeySource) -> NodeResult<(Self, DataKey)> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        let key = derive_key(source, &salt)?;
+        let config = Self {
+            version: 1,
+            kdf: "argon2id".to_string(),
+            salt: BASE64.encode(salt),
+            check: String::from_utf8(key.seal(KEY_CHECK)?)
+                .map_err(|e| NodeError::Crypto(format!("Invalid key check: {}", e)))?,
+        };
+
+        Ok((config, key))
+    }
+
+    fn read(path: &Path) -> NodeResult<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read(path)?;
+        let config = serde_json::from_slice(&content)
+            .map_err(|e| NodeError::Crypto(format!("Failed to parse key config {:?}: {}", path, e)))?;
+        Ok(Some(config))
+    }
+
+    fn write(&self, path: &Path) -> NodeResult<()> {
+        let content = serde_json::to_vec_pretty(self)?;
+        state::write_atomic(path, &content)
+            .map_err(|e| NodeError::Crypto(format!("Failed to write key config {:?}: {}", path, e)))
+    }
+
+    // Derive the key described by this config and check it against the stored check value
+    fn unlock(&self, source: &KeySource, path: &Path) -> NodeResult<DataKey> {
+        if self.kdf != "argon2id" {
+            return Err(NodeError::Crypto(format!("Unsupported key derivation {} in {:?}", self.kdf, path)));
+        }
+
+        let salt = BASE64.decode(&self.salt)
+            .map_err(|e| NodeError::Crypto(format!("Invalid salt in {:?}: {}", path, e)))?;
+        let key = derive_key(source, &salt)?;
+
+        match key.open(self.check.as_bytes()) {
+            Ok(check) if check == KEY_CHECK => Ok(key),
+            _ => Err(NodeError::Crypto(format!(
+                "The configured passphrase or key file does not match the key in {:?}", path
+            ))),
+        }
+    }
+}
+
+impl DataKey {
+    fn seal(&self, plaintext: &[u8]) -> NodeResult<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.cipher.encrypt(&nonce, plaintext)
+            .map_err(|e| NodeError::Crypto(format!("Encryption failed: {}", e)))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend_from_slice(&ciphertext);
+
+        Ok(format!("{}:{}:{}", MAGIC, self.id, BASE64.encode(payload)).into_bytes())
+    }
+
+    fn open(&self, sealed: &[u8]) -> NodeResult<Vec<u8>> {
+        let (_, payload) = split_sealed(sealed)?;
+        let payload = BASE64.decode(payload)
+            .map_err(|e| NodeError::Crypto(format!("Invalid encrypted data: {}", e)))?;
+
+        if payload.len() < NONCE_LEN {
+            return Err(NodeError::Crypto("Encrypted data is truncated".to_string()));
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        self.cipher.decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| NodeError::Crypto("Failed to decrypt data: it is damaged or was sealed with another key".to_string()))
+    }
+}
+
+// Derive a 256-bit key with Argon2id and name it by a hash of the key
+fn derive_key(source: &KeySource, salt: &[u8]) -> NodeResult<DataKey> {
+    let secret = source.secret()?;
+
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(&secret, salt, &mut key)
+        .map_err(|e| NodeError::Crypto(format!("Key derivation failed: {}", e)))?;
+
+    let id = format!("{:x}", Sha256::digest(key))[..16].to_string();
+    let cipher = XChaCha20Poly1305::new_from_slice(&key)
+        .map_err(|e| NodeError::Crypto(format!("Invalid key: {}", e)))?;
+
+    Ok(DataKey { id, cipher })
+}
+
+// Split an encrypted blob into its key id and payload
+fn split_sealed(sealed: &[u8]) -> NodeResult<(&str, &str)> {
+    let text = std::str::from_utf8(sealed)
+        .map_err(|_| NodeError::Crypto("Invalid encrypted data".to_string()))?;
+
+    let mut parts = text.trim_end().splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(MAGIC), Some(id), Some(payload)) => Ok((id, payload)),
+        _ => Err(NodeError::Crypto("Invalid encrypted data".to_string())),
+    }
+}
+
+fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(MAGIC.as_bytes()) && data.get(MAGIC.len()) == Some(&b':')
+}
+
+fn key_config_file() -> NodeResult<PathBuf> {
+    Ok(state::get_state_dir()?.join("encryption.json"))
+}
+
+// Written at the start of a rekey to a new key and renamed over the key config at the end
+fn pending_key_config_file() -> NodeResult<PathBuf> {
+    Ok(state::get_state_dir()?.join("encryption.json.rekey"))
+}
+
+// Marks a rekey that removes encryption
+fn pending_decrypt_file() -> NodeResult<PathBuf> {
+    Ok(state::get_state_dir()?.join("encryption.json.decrypt"))
+}
+
+fn install(keys: Vec<DataKey>, seal_with: Option<usize>) -> NodeResult<()> {
+    let mut keyring = KEYRING.write()
+        .map_err(|e| NodeError::Crypto(format!("Failed to lock keyring: {}", e)))?;
+    *keyring = Keyring { keys, seal_with };
+    Ok(())
+}
+
+// Whether anything has been written to the data directory yet
+fn has_node_data() -> NodeResult<bool> {
+    Ok(state::get_state_file()?.exists() || state::get_database_dir()?.exists())
+}
+
+// Load the data key for the data directory. Must be called before `state::init`.
+pub fn init(source: Option<KeySource>) -> NodeResult<()> {
+    let config_file = key_config_file()?;
+
+    if pending_key_config_file()?.exists() || pending_decrypt_file()?.exists() {
+        return Err(NodeError::Crypto(
+            "An interrupted rekey was found; run `icn-node state rekey` again with the same keys to finish it".to_string()
+        ));
+    }
+
+    match (KeyConfig::read(&config_file)?, source) {
+        (Some(config), Some(source)) => {
+            let key = config.unlock(&source, &config_file)?;
+            info!("Node data is encrypted (key {})", key.id);
+            install(vec![key], Some(0))
+        }
+        (Some(_), None) => Err(NodeError::Crypto(format!(
+            "Node data in {:?} is encrypted but no key is configured; \
+             set ICN_STATE_PASSPHRASE or pass --key-file",
+            config_file.parent().unwrap_or(config_file.as_path())
+        ))),
+        (None, Some(source)) => {
+            if has_node_data()? {
+                return Err(NodeError::Crypto(
+                    "An encryption key is configured but the existing node data is not encrypted; \
+                     run `icn-node state rekey` to encrypt it".to_string()
+                ));
+            }
+
+            // A new node starts out encrypted
+            fs::create_dir_all(state::get_state_dir()?)?;
+            let (config, key) = KeyConfig::generate(&source)?;
+            config.write(&config_file)?;
+            info!("Encrypting new node data (key {})", key.id);
+            install(vec![key], Some(0))
+        }
+        (None, None) => install(Vec::new(), None),
+    }
+}
+
+// Load both the current and the new key for a rekey, so data sealed with
+// either can be opened while everything is resealed with the new one. Must be
+// called before `state::init`. `new` is `None` to remove encryption.
+pub fn begin_rekey(current: Option<KeySource>, new: Option<KeySource>) -> NodeResult<()> {
+    let config_file = key_config_file()?;
+    let pending_file = pending_key_config_file()?;
+    let decrypt_file = pending_decrypt_file()?;
+
+    if (new.is_some() && decrypt_file.exists()) || (new.is_none() && pending_file.exists()) {
+        return Err(NodeError::Crypto(
+            "An interrupted rekey with a different target was found; finish it first".to_string()
+        ));
+    }
+
+    let mut keys = Vec::new();
+    match (KeyConfig::read(&config_file)?, current) {
+        (Some(config), Some(current)) => keys.push(config.unlock(&current, &config_file)?),
+        (Some(_), None) => {
+            return Err(NodeError::Crypto(
+                "Node data is encrypted; set ICN_STATE_PASSPHRASE or pass --key-file with the current key".to_string()
+            ));
+        }
+        (None, _) => {}
+    }
+
+    let seal_with = match new {
+        Some(new) => {
+            let key = match KeyConfig::read(&pending_file)? {
+                // Resume with the key chosen by the interrupted run
+                Some(pending) => pending.unlock(&new, &pending_file)?,
+                None => {
+                    let (pending, key) = KeyConfig::generate(&new)?;
+                    pending.write(&pending_file)?;
+                    key
+                }
+            };
+
+            if keys.iter().any(|existing| existing.id == key.id) {
+                return Err(NodeError::Crypto("The new key is the same as the current key".to_string()));
+            }
+
+            keys.push(key);
+            Some(keys.len() - 1)
+        }
+        None => {
+            if keys.is_empty() {
+                return Err(NodeError::Crypto("Node data is not encrypted".to_string()));
+            }
+
+            state::write_atomic(&decrypt_file, b"")
+                .map_err(|e| NodeError::Crypto(format!("Failed to mark rekey: {}", e)))?;
+            None
+        }
+    };
+
+    install(keys, seal_with)
+}
+
+// Make the new key the only key once all data has been resealed
+fn finish_rekey() -> NodeResult<()> {
+    let config_file = key_config_file()?;
+    let pending_file = pending_key_config_file()?;
+    let decrypt_file = pending_decrypt_file()?;
+
+    if pending_file.exists() {
+        fs::rename(&pending_file, &config_file)
+            .map_err(|e| NodeError::Crypto(format!("Failed to install new key config: {}", e)))?;
+    } else if decrypt_file.exists() {
+        if config_file.exists() {
+            fs::remove_file(&config_file)?;
+        }
+        fs::remove_file(&decrypt_file)?;
+    }
+
+    let mut keyring = KEYRING.write()
+        .map_err(|e| NodeError::Crypto(format!("Failed to lock keyring: {}", e)))?;
+    if let Some(index) = keyring.seal_with {
+        let key = keyring.keys.swap_remove(index);
+        keyring.keys = vec![key];
+        keyring.seal_with = Some(0);
+    } else {
+        keyring.keys.clear();
+    }
+
+    Ok(())
+}
+
+// Whether new data is written encrypted
+pub fn is_enabled() -> bool {
+    KEYRING.read().is_ok_and(|keyring| keyring.seal_with.is_some())
+}
+
+// Encrypt data with the active key, or return it unchanged if encryption is off
+pub fn seal(plaintext: &[u8]) -> NodeResult<Vec<u8>> {
+    let keyring = KEYRING.read()
+        .map_err(|e| NodeError::Crypto(format!("Failed to lock keyring: {}", e)))?;
+
+    match keyring.seal_with {
+        Some(index) => keyring.keys[index].seal(plaintext),
+        None => Ok(plaintext.to_vec()),
+    }
+}
+
+// Decrypt data sealed with any loaded key. Plaintext is returned unchanged.
+pub fn open(data: &[u8]) -> NodeResult<Vec<u8>> {
+    if !is_sealed(data) {
+        return Ok(data.to_vec());
+    }
+
+    let keyring = KEYRING.read()
+        .map_err(|e| NodeError::Crypto(format!("Failed to lock keyring: {}", e)))?;
+
+    if keyring.keys.is_empty() {
+        return Err(NodeError::Crypto(
+            "Data is encrypted but no key is configured; set ICN_STATE_PASSPHRASE or pass --key-file".to_string()
+        ));
+    }
+
+    let (id, _) = split_sealed(data)?;
+    let key = keyring.keys.iter()
+        .find(|key| key.id == id)
+        .ok_or_else(|| NodeError::Crypto(format!("Data was encrypted with another key ({})", id)))?;
+
+    key.open(data)
+}
+
+// Whether data is plaintext or sealed with one of the loaded keys
+pub fn has_key_for(data: &[u8]) -> bool {
+    if !is_sealed(data) {
+        return true;
+    }
+
+    let keyring = match KEYRING.read() {
+        Ok(keyring) => keyring,
+        Err(_) => return false,
+    };
+
+    match split_sealed(data) {
+        Ok((id, _)) => keyring.keys.iter().any(|key| key.id == id),
+        Err(_) => true,
+    }
+}
+
+// Read and decrypt a file
+pub fn read_file(path: &Path) -> NodeResult<Vec<u8>> {
+    let data = fs::read(path)?;
+    open(&data).map_err(|e| NodeError::Crypto(format!("{:?}: {}", path, e)))
+}
+
+// Encrypt and write a file, replacing it atomically so a crash never leaves
+// it half written
+pub fn write_file(path: &Path, plaintext: &[u8]) -> NodeResult<()> {
+    state::write_atomic(path, &seal(plaintext)?)
+        .map_err(|e| NodeError::Crypto(format!("Failed to write {:?}: {}", path, e)))
+}
+
+// Re-encrypt all node data with the new key loaded by `begin_rekey`: backups,
+// execution outputs, the identity, the node key, CoVM storage, checkpoints,
+// pruned DAG history, the DAG log and finally the state store. Interrupted
+// runs can be repeated with the same keys.
+pub fn rekey() -> NodeResult<RekeyReport> {
+    let data_dir = state::get_state_dir()?;
+    let mut report = RekeyReport::default();
+
+    for path in [
+        state::get_backup_dir()?,
+        data_dir.join("output"),
+        data_dir.join("identity.json"),
+        signing::get_keys_dir()?,
+        data_dir.join("storage"),
+        checkpoint::get_checkpoints_dir()?,
+        checkpoint::get_archive_dir()?,
+    ] {
+        reseal_tree(&path, &mut report)?;
+    }
+    dag_log::reseal(&mut report)?;
+
+    // Rewrites the snapshot or database with the new key and empties the log
+    state::save_state()?;
+
+    finish_rekey()?;
+    Ok(report)
+}
+
+// Re-encrypt data with the active key. Returns None if it already was.
+pub fn reseal(data: &[u8]) -> NodeResult<Option<Vec<u8>>> {
+    {
+        let keyring = KEYRING.read()
+            .map_err(|e| NodeError::Crypto(format!("Failed to lock keyring: {}", e)))?;
+
+        let done = match keyring.seal_with {
+            Some(index) => is_sealed(data) && split_sealed(data)?.0 == keyring.keys[index].id,
+            None => !is_sealed(data),
+        };
+        if done {
+            return Ok(None);
+        }
+    }
+
+    seal(&open(data)?).map(Some)
+}
+
+// Re-encrypt a file with the active key. Returns false if it already was.
+fn reseal_file(path: &Path) -> NodeResult<bool> {
+    let data = fs::read(path)?;
+
+    let sealed = match reseal(&data).map_err(|e| NodeError::Crypto(format!("{:?}: {}", path, e)))? {
+        Some(sealed) => sealed,
+        None => return Ok(false),
+    };
+    state::write_atomic(path, &sealed)
+        .map_err(|e| NodeError::Crypto(format!("Failed to rewrite {:?}: {}", path, e)))?;
+
+    debug!("Resealed {:?}", path);
+    Ok(true)
+}
+
+// Re-encrypt every file below `path` with the active key
+fn reseal_tree(path: &Path, report: &mut RekeyReport) -> NodeResult<()> {
+    if path.is_file() {
+        if reseal_file(path)? {
+            report.resealed += 1;
+        } else {
+            report.unchanged += 1;
+        }
+    } else if path.is_dir() {
+        for entry in fs::read_dir(path)? {
+            reseal_tree(&entry?.path(), report)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Decrypt every file below `src` into `dst`
+pub fn unseal_tree(src: &Path, dst: &Path) -> NodeResult<()> {
+    if src.is_file() {
+        fs::write(dst, read_file(src)?)?;
+    } else if src.is_dir() {
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            unseal_tree(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Encrypt every file below `src` into `dst`, removing files from `dst` that
+// no longer exist in `src`
+pub fn seal_tree(src: &Path, dst: &Path) -> NodeResult<()> {
+    if src.is_file() {
+        let plaintext = fs::read(src)?;
+        state::write_atomic(dst, &seal(&plaintext)?)
+            .map_err(|e| NodeError::Crypto(format!("Failed to write {:?}: {}", dst, e)))?;
+    } else if src.is_dir() {
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            seal_tree(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+
+        for entry in fs::read_dir(dst)? {
+            let entry = entry?;
+            let path = entry.path();
+            if !src.join(entry.file_name()).exists() {
+                if path.is_dir() {
+                    fs::remove_dir_all(&path)?;
+                } else {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, DataDir};
+    use serial_test::serial;
+
+    // Point the node at a fresh data directory with no keys loaded
+    fn data_dir() -> DataDir {
+        let dir = test_support::data_dir();
+        install(Vec::new(), None).unwrap();
+        dir
+    }
+
+    fn passphrase(passphrase: &str) -> KeySource {
+        KeySource::Passphrase(passphrase.to_string())
+    }
+
+    fn key_id(path: &Path) -> String {
+        let data = fs::read(path).unwrap();
+        split_sealed(&data).unwrap().0.to_string()
+    }
+
+    #[test]
+    #[serial]
+    fn seals_with_the_configured_key() {
+        let _dir = data_dir();
+        assert_eq!(seal(b"secret").unwrap(), b"secret");
+
+        init(Some(passphrase("first"))).unwrap();
+        let sealed = seal(b"secret").unwrap();
+        assert!(is_sealed(&sealed));
+        assert_eq!(open(&sealed).unwrap(), b"secret");
+
+        // Plaintext written before encryption was enabled still reads
+        assert_eq!(open(b"plain").unwrap(), b"plain");
+    }
+
+    #[test]
+    #[serial]
+    fn rejects_a_wrong_key() {
+        let _dir = data_dir();
+        init(Some(passphrase("first"))).unwrap();
+        let sealed = seal(b"secret").unwrap();
+
+        install(Vec::new(), None).unwrap();
+        let err = init(Some(passphrase("second"))).unwrap_err();
+        assert!(err.to_string().contains("does not match"), "{}", err);
+        assert!(init(None).is_err());
+        assert!(open(&sealed).is_err());
+        assert!(!has_key_for(&sealed));
+    }
+
+    #[test]
+    #[serial]
+    fn write_file_replaces_the_whole_file() {
+        let dir = data_dir();
+        init(Some(passphrase("first"))).unwrap();
+        let path = dir.path().join("result.json");
+
+        write_file(&path, b"a longer first version").unwrap();
+        write_file(&path, b"second").unwrap();
+
+        assert_eq!(read_file(&path).unwrap(), b"second");
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    #[serial]
+    fn rekey_reseals_files_with_the_new_key() {
+        let dir = data_dir();
+        init(Some(passphrase("first"))).unwrap();
+
+        let output = dir.path().join("output");
+        fs::create_dir_all(&output).unwrap();
+        write_file(&output.join("sealed.json"), b"sealed").unwrap();
+        fs::write(output.join("plain.json"), b"plain").unwrap();
+        let old_key = key_id(&output.join("sealed.json"));
+
+        begin_rekey(Some(passphrase("first")), Some(passphrase("second"))).unwrap();
+
+        // A normal start is refused until the rekey is finished
+        assert!(init(Some(passphrase("first"))).is_err());
+
+        let mut report = RekeyReport::default();
+        reseal_tree(&output, &mut report).unwrap();
+        assert_eq!((report.resealed, report.unchanged), (2, 0));
+
+        // Repeating an interrupted run leaves resealed files alone
+        let mut report = RekeyReport::default();
+        reseal_tree(&output, &mut report).unwrap();
+        assert_eq!((report.resealed, report.unchanged), (0, 2));
+        finish_rekey().unwrap();
+
+        let new_key = key_id(&output.join("sealed.json"));
+        assert_ne!(new_key, old_key);
+        assert_eq!(key_id(&output.join("plain.json")), new_key);
+
+        install(Vec::new(), None).unwrap();
+        assert!(init(Some(passphrase("first"))).is_err());
+        init(Some(passphrase("second"))).unwrap();
+        assert_eq!(read_file(&output.join("sealed.json")).unwrap(), b"sealed");
+        assert_eq!(read_file(&output.join("plain.json")).unwrap(), b"plain");
+    }
+
+    #[test]
+    #[serial]
+    fn seals_and_reseals_the_dag_log() {
+        let _dir = data_dir();
+        init(Some(passphrase("first"))).unwrap();
+
+        let entry = dag_log::DagLogEntry {
+            logged_at: chrono::Utc::now(),
+            event: dag_log::DagEvent::VertexAdded,
+            vertex_id: "vertex".to_string(),
+            proposal_id: "1".to_string(),
+            data_type: "proposal_execution".to_string(),
+            hash: "hash".to_string(),
+            submitter: "node".to_string(),
+            timestamp: chrono::Utc::now(),
+            parents: Vec::new(),
+            detail: None,
+        };
+        dag_log::append(&entry).unwrap();
+        dag_log::append(&entry).unwrap();
+
+        let current = dag_log::get_dag_log_dir().unwrap().join("current.jsonl");
+        let content = fs::read_to_string(&current).unwrap();
+        assert_eq!(content.lines().count(), 2);
+        assert!(content.lines().all(|line| is_sealed(line.as_bytes())));
+        let old_key = split_sealed(content.as_bytes()).unwrap().0.to_string();
+
+        begin_rekey(Some(passphrase("first")), Some(passphrase("second"))).unwrap();
+        let mut report = RekeyReport::default();
+        dag_log::reseal(&mut report).unwrap();
+        assert_eq!((report.resealed, report.unchanged), (1, 0));
+        finish_rekey().unwrap();
+
+        let content = fs::read_to_string(&current).unwrap();
+        assert!(content.lines().all(|line| split_sealed(line.as_bytes()).unwrap().0 != old_key));
+
+        install(Vec::new(), None).unwrap();
+        init(Some(passphrase("second"))).unwrap();
+        let entries: Vec<_> = dag_log::read(dag_log::DagLogFilter::default()).unwrap()
+            .collect::<NodeResult<_>>().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].vertex_id, "vertex");
+    }
+
+    #[test]
+    #[serial]
+    fn rekey_can_remove_encryption() {
+        let dir = data_dir();
+        init(Some(passphrase("first"))).unwrap();
+        let path = dir.path().join("result.json");
+        write_file(&path, b"result").unwrap();
+
+        begin_rekey(Some(passphrase("first")), None).unwrap();
+        let mut report = RekeyReport::default();
+        reseal_tree(&path, &mut report).unwrap();
+        finish_rekey().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"result");
+        assert!(!is_enabled());
+        assert!(!key_config_file().unwrap().exists());
+    }
+}
@@ -1,23 +1,23 @@
+use crate::checkpoint;
+use crate::dag_log::{self, DagLogFilter, DagLogReader};
 use crate::error::{NodeError, NodeResult};
-use crate::federation;
-use crate::state::{self, VertexEntry};
+use crate::federation::{self, FederationConfig};
+use crate::payload::VertexPayload;
+use crate::signing;
+use crate::state::{self, NodeState};
+use crate::vertex::Vertex;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use ed25519_dalek::SigningKey;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::fs;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Mutex;
 use tokio::sync::mpsc;
 use tokio::time::{self, Duration};
-use tracing::{error, info};
-
-// DAG vertex structure
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Vertex {
-    pub id: String,
-    pub timestamp: chrono::DateTime<chrono::Utc>,
-    pub proposal_id: String,
-    pub parents: Vec<String>,
-    pub hash: String,
-    pub submitter: String,
-}
+use tracing::info;
 
 // DAG info structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -28,70 +28,1066 @@ pub struct DagInfo {
     pub genesis_time: chrono::DateTime<chrono::Utc>,
     pub latest_update: chrono::DateTime<chrono::Utc>,
     pub tips: Vec<String>,
+    #[serde(default)]
+    pub height: u64,
+    // Merkle root over the vertex set (see `VertexMerkle`)
+    #[serde(default)]
+    pub state_root: String,
+    // Subjects with incompatible claims (see `Conflict`)
+    #[serde(default)]
+    pub conflict_count: usize,
 }
 
-// Get DAG info
-pub async fn get_dag_info() -> NodeResult<DagInfo> {
-    // For now, we're just using the state to get DAG info
-    let vertices = get_all_vertices()?;
-    
-    // Very simple DAG implementation for now
-    // In a real implementation, we would track the actual DAG structure with parents/children
-    let vertex_count = vertices.len();
-    let root_count = 1; // Simplified
-    let tips = if vertex_count > 0 {
-        vec![vertices.last().unwrap().id.clone()]
-    } else {
-        Vec::new()
+// A vertex with its links in the DAG
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VertexDetails {
+    #[serde(flatten)]
+    pub vertex: Vertex,
+    pub data_type: String,
+    pub scope: Option<String>,
+    pub height: Option<u64>,
+    pub children: Vec<String>,
+    pub pruned: bool,
+    // Lost a conflict to another vertex's claim
+    pub superseded: bool,
+}
+
+// A vertex reached by following parent or child links, `depth` links away
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelatedVertex {
+    pub id: String,
+    pub depth: usize,
+}
+
+// Which vertices to list; all conditions must hold
+#[derive(Debug, Clone, Default)]
+pub struct VertexQuery {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub proposal_id: Option<String>,
+    pub submitter: Option<String>,
+    // Content hash (see `Vertex::hash`)
+    pub hash: Option<String>,
+}
+
+// One page of a vertex listing, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VertexPage {
+    pub vertices: Vec<Vertex>,
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub next_offset: Option<usize>,
+}
+
+// Largest page `query_vertices` returns
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+// What became of a vertex received from a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestStatus {
+    Added,
+    // Already recorded
+    Known,
+    // Waiting for parents that have not been received yet
+    Held,
+}
+
+// Vertices received from peers before their parents, by id
+static ORPHANS: Lazy<Mutex<HashMap<String, Vertex>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Most vertices held for missing parents at once
+const MAX_ORPHANS: usize = 1000;
+
+// Prefixes of the encodings hashed into a vertex id; a new encoding needs a new prefix
+const VERTEX_ID_DOMAIN: &[u8] = b"icn-vertex-id-v1";
+const VERTEX_ID_DOMAIN_V2: &[u8] = b"icn-vertex-id-v2";
+
+fn encode_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u64).to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+// Content address of a vertex: the SHA-256 of a canonical encoding of its
+// payload hash, parents (sorted), submitter and timestamp, followed by the
+// digest of its typed payload if it has one. Every field is length-prefixed
+// so different vertices can never encode to the same bytes.
+pub fn compute_vertex_id(vertex: &Vertex) -> NodeResult<String> {
+    let mut parents: Vec<&str> = vertex.parents.iter().map(String::as_str).collect();
+    parents.sort_unstable();
+    parents.dedup();
+
+    let domain = if vertex.payload.is_some() { VERTEX_ID_DOMAIN_V2 } else { VERTEX_ID_DOMAIN };
+    let mut buf = domain.to_vec();
+    encode_field(&mut buf, vertex.hash.as_bytes());
+    buf.extend_from_slice(&(parents.len() as u64).to_be_bytes());
+    for parent in parents {
+        encode_field(&mut buf, parent.as_bytes());
+    }
+    encode_field(&mut buf, vertex.submitter.as_bytes());
+    buf.extend_from_slice(&vertex.timestamp.timestamp().to_be_bytes());
+    buf.extend_from_slice(&vertex.timestamp.timestamp_subsec_nanos().to_be_bytes());
+    if let Some(payload) = &vertex.payload {
+        encode_field(&mut buf, payload.digest()?.as_bytes());
+    }
+
+    Ok(format!("{:x}", Sha256::digest(&buf)))
+}
+
+// Check that a vertex id is the content address of the vertex
+pub fn verify_vertex_id(vertex: &Vertex) -> NodeResult<()> {
+    let expected = compute_vertex_id(vertex)?;
+    if vertex.id != expected {
+        return Err(NodeError::Dag(format!(
+            "Vertex id {} does not match its content (expected {})", vertex.id, expected
+        )));
+    }
+
+    Ok(())
+}
+
+// Check that a typed payload follows its schema and agrees with the fields
+// of the vertex that carries it
+pub fn verify_payload(vertex: &Vertex) -> NodeResult<()> {
+    let payload = match &vertex.payload {
+        Some(payload) => payload,
+        None => return Ok(()),
     };
-    
-    let genesis_time = if vertices.is_empty() {
-        chrono::Utc::now()
-    } else {
-        vertices.first().unwrap().timestamp
+    payload.validate()?;
+
+    let (hash, result_hash) = match payload {
+        VertexPayload::ProposalExecution(execution) => (execution.content_hash.clone(), execution.result_hash.as_str()),
+        _ => (payload.digest()?, ""),
     };
-    
-    let latest_update = if vertices.is_empty() {
-        chrono::Utc::now()
+
+    if vertex.proposal_id != payload.proposal_id().unwrap_or_default()
+        || vertex.hash != hash
+        || vertex.result_hash != result_hash
+    {
+        return Err(NodeError::Validation(format!(
+            "Vertex {} does not match its {} payload", vertex.id, payload.data_type()
+        )));
+    }
+
+    Ok(())
+}
+
+// Check that a vertex's id matches its content, that its payload is valid and
+// that it is signed by the key it carries. Legacy vertices carry nothing to
+// verify, so they are only taken from a migrated state, never through here.
+pub fn verify_vertex(vertex: &Vertex) -> NodeResult<()> {
+    if vertex.is_legacy() {
+        return Err(NodeError::Validation(format!(
+            "Vertex {} is an unsigned legacy vertex, which is only accepted from a migrated state", vertex.id
+        )));
+    }
+
+    verify_vertex_id(vertex)?;
+    verify_payload(vertex)?;
+    signing::verify_vertex(vertex)
+}
+
+// Check that a vertex is signed with the key registered for its submitter:
+// this node's own key, or the key of the peer with that id in the federation
+// config. The signature alone only shows that someone held the key it names.
+pub fn verify_submitter(state: &NodeState, vertex: &Vertex) -> NodeResult<()> {
+    let registered = if vertex.submitter == state.node_id {
+        signing::public_key(signing::node_key()?)
     } else {
-        vertices.last().unwrap().timestamp
+        state.extension::<FederationConfig>()?
+            .and_then(|config| config.peers.into_iter().find(|peer| peer.id == vertex.submitter))
+            .map(|peer| peer.public_key)
+            .filter(|key| !key.is_empty())
+            .ok_or_else(|| NodeError::Validation(format!(
+                "Vertex {} was submitted by {}, which is not a federation peer with a registered key",
+                vertex.id, vertex.submitter
+            )))?
     };
-    
-    Ok(DagInfo {
-        vertex_count,
-        root_count,
-        tip_count: tips.len(),
-        genesis_time,
-        latest_update,
-        tips,
+
+    if vertex.public_key != registered {
+        return Err(NodeError::Validation(format!(
+            "Vertex {} is signed with key {}, not the key registered for its submitter {}",
+            vertex.id, vertex.public_key, vertex.submitter
+        )));
+    }
+
+    Ok(())
+}
+
+// Fill in the parents (the current tips), submitter and id of a new vertex
+// and sign it. Called inside the write that commits the vertex so no
+// concurrent vertex is skipped.
+pub fn prepare_vertex(state: &NodeState, key: &SigningKey, vertex: &mut Vertex) -> NodeResult<()> {
+    vertex.parents = state.dag().tips().cloned().collect();
+    vertex.submitter = state.node_id.clone();
+    vertex.id = compute_vertex_id(vertex)?;
+    signing::sign_vertex(key, vertex);
+    Ok(())
+}
+
+// Parse a command line timestamp: RFC 3339, or a date meaning midnight UTC
+pub fn parse_timestamp(value: &str) -> NodeResult<DateTime<Utc>> {
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|midnight| Utc.from_utc_datetime(&midnight))
+        .ok_or_else(|| NodeError::Dag(format!(
+            "Invalid timestamp {}; use RFC 3339 (2024-01-01T12:00:00Z) or a date (2024-01-01)", value
+        )))
+}
+
+// Number of leaf buckets in the vertex Merkle tree, one per leading byte of a
+// leaf hash
+pub const MERKLE_BUCKETS: usize = 256;
+
+// Depth of the vertex Merkle tree: the root is level 0, buckets are level 8
+pub const MERKLE_DEPTH: u32 = 8;
+
+// Merkle tree over the set of vertex ids. Each id's leaf is its SHA-256; a
+// leaf goes into the bucket named by its first byte, a bucket hashes its
+// sorted leaves and the buckets form a binary tree up to the root. The root
+// only depends on which vertices are present, not on the order they arrived,
+// so nodes can compare roots and then walk down to the differing buckets.
+#[derive(Debug, Clone)]
+pub struct VertexMerkle {
+    buckets: Vec<BTreeMap<[u8; 32], String>>,
+    // Heap layout: node 1 is the root, node i has children 2i and 2i + 1,
+    // and bucket b is node MERKLE_BUCKETS + b
+    nodes: Vec<[u8; 32]>,
+}
+
+impl Default for VertexMerkle {
+    fn default() -> Self {
+        let mut tree = Self {
+            buckets: vec![BTreeMap::new(); MERKLE_BUCKETS],
+            nodes: vec![[0u8; 32]; 2 * MERKLE_BUCKETS],
+        };
+
+        for bucket in 0..MERKLE_BUCKETS {
+            tree.nodes[MERKLE_BUCKETS + bucket] = tree.bucket_hash(bucket);
+        }
+        for node in (1..MERKLE_BUCKETS).rev() {
+            tree.nodes[node] = tree.branch_hash(node);
+        }
+
+        tree
+    }
+}
+
+impl VertexMerkle {
+    // Add a vertex id, updating the hashes from its bucket up to the root
+    pub fn insert(&mut self, id: &str) {
+        let leaf: [u8; 32] = Sha256::digest(id.as_bytes()).into();
+        let bucket = leaf[0] as usize;
+
+        if self.buckets[bucket].insert(leaf, id.to_string()).is_some() {
+            return;
+        }
+
+        let mut node = MERKLE_BUCKETS + bucket;
+        self.nodes[node] = self.bucket_hash(bucket);
+        while node > 1 {
+            node /= 2;
+            self.nodes[node] = self.branch_hash(node);
+        }
+    }
+
+    fn bucket_hash(&self, bucket: usize) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(b"icn-dag-bucket");
+        for leaf in self.buckets[bucket].keys() {
+            hasher.update(leaf);
+        }
+        hasher.finalize().into()
+    }
+
+    fn branch_hash(&self, node: usize) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.nodes[2 * node]);
+        hasher.update(self.nodes[2 * node + 1]);
+        hasher.finalize().into()
+    }
+
+    // Root hash over all vertex ids
+    pub fn root(&self) -> String {
+        hex(&self.nodes[1])
+    }
+
+    // Hashes of the 2^level subtrees at `level`, left to right. Subtree i at
+    // level l covers buckets i * 2^(8 - l) up to (i + 1) * 2^(8 - l).
+    pub fn level(&self, level: u32) -> NodeResult<Vec<String>> {
+        if level > MERKLE_DEPTH {
+            return Err(NodeError::Dag(format!(
+                "Merkle level {} is deeper than the tree ({} levels)", level, MERKLE_DEPTH
+            )));
+        }
+
+        let first = 1usize << level;
+        Ok(self.nodes[first..2 * first].iter().map(hex).collect())
+    }
+
+    // Vertex ids in a bucket, in leaf order
+    pub fn bucket(&self, bucket: usize) -> NodeResult<Vec<String>> {
+        self.buckets.get(bucket)
+            .map(|leaves| leaves.values().cloned().collect())
+            .ok_or_else(|| NodeError::Dag(format!("Merkle bucket {} out of range (0-{})", bucket, MERKLE_BUCKETS - 1)))
+    }
+}
+
+fn hex(bytes: &[u8; 32]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A vertex's claim about a subject that at most one value may be recorded
+// for, e.g. the result of executing a proposal. Claims are attributed to the
+// key that signed the vertex, which `verify_submitter` binds to a federation
+// member before the vertex is recorded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claim {
+    pub vertex_id: String,
+    pub submitter: String,
+    pub public_key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    // Different members made incompatible claims
+    Fork,
+    // One member's key signed incompatible claims
+    Equivocation,
+}
+
+// Incompatible claims about one subject. The earliest claim in causal order
+// wins: a claim loses to any claim its vertex descends from, and pruned
+// claims precede all retained ones. Concurrent claims are ranked by signing
+// key, keys that equivocated on the subject last, so the outcome depends
+// only on the vertices held and cannot be steered by varying a vertex (and
+// with it its id). Vertices with a different value than the winner are
+// superseded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conflict {
+    pub subject: String,
+    pub kind: ConflictKind,
+    // Claims in vertex id order; the vertices themselves are the signed evidence
+    pub claims: Vec<Claim>,
+    pub winner: String,
+    pub superseded: Vec<String>,
+}
+
+// The subject a vertex makes a claim about and the value it claims. Legacy
+// vertices, untyped vertices without a result hash, parameter changes and
+// federation config changes make no exclusive claims.
+fn claim_of(vertex: &Vertex) -> Option<(String, String)> {
+    if vertex.is_legacy() {
+        return None;
+    }
+
+    match &vertex.payload {
+        None if vertex.result_hash.is_empty() => None,
+        None => Some((format!("execution:{}", vertex.proposal_id), vertex.result_hash.clone())),
+        Some(VertexPayload::ProposalExecution(execution)) => {
+            Some((format!("execution:{}", execution.proposal_id), execution.result_hash.clone()))
+        }
+        Some(VertexPayload::Vote(vote)) => Some((
+            format!("vote:{}:{}", vote.proposal_id, vote.voter),
+            format!("{}:{}", vote.choice.as_str(), vote.weight),
+        )),
+        Some(VertexPayload::IdentityRegistration(identity)) => {
+            Some((format!("identity:{}:{}", identity.scope, identity.did), identity.public_key.clone()))
+        }
+        Some(payload @ VertexPayload::AssetRegistration(asset)) => {
+            Some((format!("asset:{}:{}", asset.scope, asset.asset_id), payload.digest().ok()?))
+        }
+        Some(VertexPayload::ParameterChange(_)) | Some(VertexPayload::FederationConfigChange(_)) => None,
+    }
+}
+
+// What the state keeps of vertices moved to cold storage by pruning: enough
+// to report the whole DAG and extend its tips. The ids and claims of pruned
+// vertices are kept next to them in cold storage (see `PrunedSegment`).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrunedHistory {
+    // Checkpoint the history was pruned up to
+    pub checkpoint: u64,
+    pub vertex_count: usize,
+    pub genesis_time: DateTime<Utc>,
+    pub latest_time: DateTime<Utc>,
+    pub height: u64,
+    pub roots: Vec<String>,
+    // Pruned vertices that were still tips
+    pub tips: Vec<String>,
+    // Heights of pruned vertices that are tips or parents of retained vertices
+    pub frontier: BTreeMap<String, u64>,
+    // Merkle root over the pruned vertex ids, which the segment indexes are
+    // checked against when the state is loaded
+    pub merkle_root: String,
+}
+
+// Index of an archive segment: the ids of the vertices recorded at positions
+// `start..end` and the exclusive claims they made, by subject
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrunedSegment {
+    pub start: usize,
+    pub end: usize,
+    pub ids: Vec<String>,
+    pub claims: BTreeMap<String, Vec<Claim>>,
+}
+
+// Parent/child structure of the DAG, maintained as vertices are added.
+// Tips are vertices without children, roots are vertices without parents,
+// and a vertex's height is one more than that of its highest parent.
+#[derive(Debug, Clone, Default)]
+pub struct DagGraph {
+    children: HashMap<String, Vec<String>>,
+    heights: HashMap<String, u64>,
+    tips: BTreeSet<String>,
+    roots: BTreeSet<String>,
+    max_height: u64,
+    merkle: VertexMerkle,
+    pruned: HashSet<String>,
+    // Exclusive claims by subject in vertex id order, the subject of each
+    // claiming vertex, and the subjects with incompatible claims
+    claims: HashMap<String, Vec<Claim>>,
+    subjects: HashMap<String, String>,
+    conflicted: BTreeSet<String>,
+}
+
+impl DagGraph {
+    // Start from the summary of pruned history and the indexes of the
+    // segments it covers
+    pub fn seed(&mut self, pruned: &PrunedHistory, segments: &[PrunedSegment]) {
+        for segment in segments.iter().filter(|segment| segment.end <= pruned.vertex_count) {
+            for id in &segment.ids {
+                self.pruned.insert(id.clone());
+                self.merkle.insert(id);
+            }
+
+            for (subject, claims) in &segment.claims {
+                for claim in claims {
+                    self.add_claim(subject, claim.clone());
+                }
+            }
+        }
+
+        self.heights.extend(pruned.frontier.iter().map(|(id, height)| (id.clone(), *height)));
+        self.roots.extend(pruned.roots.iter().cloned());
+        self.tips.extend(pruned.tips.iter().cloned());
+        self.max_height = self.max_height.max(pruned.height);
+    }
+
+    // Record a claim, keeping claims in vertex id order, and note the subject
+    // as conflicted once its claims disagree
+    fn add_claim(&mut self, subject: &str, claim: Claim) {
+        self.subjects.insert(claim.vertex_id.clone(), subject.to_string());
+
+        let claims = self.claims.entry(subject.to_string()).or_default();
+        let position = claims.partition_point(|existing| existing.vertex_id < claim.vertex_id);
+        claims.insert(position, claim);
+
+        if claims.iter().any(|other| other.value != claims[0].value) {
+            self.conflicted.insert(subject.to_string());
+        }
+    }
+
+    pub fn insert(&mut self, vertex: &Vertex) {
+        // A repeated id keeps the links of its first vertex
+        if self.contains(&vertex.id) {
+            return;
+        }
+
+        let mut height = 0;
+        for parent in &vertex.parents {
+            self.children.entry(parent.clone()).or_default().push(vertex.id.clone());
+            self.tips.remove(parent);
+
+            if let Some(parent_height) = self.heights.get(parent) {
+                height = height.max(parent_height + 1);
+            }
+        }
+
+        if vertex.parents.is_empty() {
+            self.roots.insert(vertex.id.clone());
+        }
+
+        // Children can arrive before their parent when vertices are synced
+        if !self.children.contains_key(&vertex.id) {
+            self.tips.insert(vertex.id.clone());
+        }
+
+        self.heights.insert(vertex.id.clone(), height);
+        self.max_height = self.max_height.max(height);
+        self.raise_descendants(&vertex.id);
+        self.merkle.insert(&vertex.id);
+
+        if let Some((subject, value)) = claim_of(vertex) {
+            let claim = Claim {
+                vertex_id: vertex.id.clone(),
+                submitter: vertex.submitter.clone(),
+                public_key: vertex.public_key.clone(),
+                value,
+            };
+            self.add_claim(&subject, claim);
+        }
+    }
+
+    // Children inserted before their parent were given a height without it;
+    // raise them and everything below them now that the parent is known
+    fn raise_descendants(&mut self, id: &str) {
+        let mut pending = vec![id.to_string()];
+        while let Some(parent) = pending.pop() {
+            let height = self.heights[&parent] + 1;
+            for child in self.children.get(&parent).into_iter().flatten() {
+                if let Some(child_height) = self.heights.get_mut(child) {
+                    if *child_height < height {
+                        *child_height = height;
+                        self.max_height = self.max_height.max(height);
+                        pending.push(child.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    // Claims of the given vertices, by subject
+    pub fn claims_of(&self, ids: &HashSet<&str>) -> BTreeMap<String, Vec<Claim>> {
+        let mut claims = BTreeMap::new();
+        for (subject, subject_claims) in &self.claims {
+            let matching: Vec<Claim> = subject_claims.iter()
+                .filter(|claim| ids.contains(claim.vertex_id.as_str()))
+                .cloned()
+                .collect();
+            if !matching.is_empty() {
+                claims.insert(subject.clone(), matching);
+            }
+        }
+        claims
+    }
+
+    // Whether vertex `a` comes before vertex `b` in causal order: pruned
+    // vertices come before retained ones, and retained vertices before their
+    // descendants
+    fn precedes(&self, a: &str, b: &str) -> bool {
+        match (self.is_pruned(a), self.is_pruned(b)) {
+            (true, false) => true,
+            (false, false) => self.is_ancestor(a, b),
+            _ => false,
+        }
+    }
+
+    // Whether `descendant` can be reached from `ancestor` through child
+    // links. Ancestors are lower than their descendants, so only vertices
+    // below `descendant` are searched.
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> bool {
+        let target = match self.heights.get(descendant) {
+            Some(height) => *height,
+            None => return false,
+        };
+
+        let mut visited = HashSet::new();
+        let mut pending = vec![ancestor];
+        while let Some(id) = pending.pop() {
+            for child in self.children(id) {
+                if child == descendant {
+                    return true;
+                }
+                if self.heights.get(child).is_some_and(|height| *height < target) && visited.insert(child.as_str()) {
+                    pending.push(child);
+                }
+            }
+        }
+
+        false
+    }
+
+    fn resolve(&self, subject: &str, claims: &[Claim]) -> Conflict {
+        let mut equivocators = HashSet::new();
+        for (i, a) in claims.iter().enumerate() {
+            for b in &claims[i + 1..] {
+                if a.public_key == b.public_key && a.value != b.value {
+                    equivocators.insert(a.public_key.as_str());
+                }
+            }
+        }
+
+        let winner = claims.iter()
+            .filter(|claim| !claims.iter().any(|other| self.precedes(&other.vertex_id, &claim.vertex_id)))
+            .min_by_key(|claim| (equivocators.contains(claim.public_key.as_str()), &claim.public_key, &claim.vertex_id))
+            .unwrap_or(&claims[0]);
+
+        let superseded = claims.iter()
+            .filter(|claim| claim.value != winner.value)
+            .map(|claim| claim.vertex_id.clone())
+            .collect();
+
+        Conflict {
+            subject: subject.to_string(),
+            kind: if equivocators.is_empty() { ConflictKind::Fork } else { ConflictKind::Equivocation },
+            claims: claims.to_vec(),
+            winner: winner.vertex_id.clone(),
+            superseded,
+        }
+    }
+
+    // All conflicts, by subject
+    pub fn conflicts(&self) -> Vec<Conflict> {
+        self.conflicted.iter()
+            .map(|subject| self.resolve(subject, &self.claims[subject]))
+            .collect()
+    }
+
+    pub fn conflict_count(&self) -> usize {
+        self.conflicted.len()
+    }
+
+    // The conflict a vertex is part of, if any
+    pub fn conflict_of(&self, id: &str) -> Option<Conflict> {
+        let subject = self.subjects.get(id)?;
+        self.conflicted.contains(subject).then(|| self.resolve(subject, &self.claims[subject]))
+    }
+
+    // Whether a vertex lost a conflict
+    pub fn is_superseded(&self, id: &str) -> bool {
+        self.conflict_of(id).is_some_and(|conflict| conflict.superseded.iter().any(|superseded| superseded == id))
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.heights.contains_key(id) || self.pruned.contains(id)
+    }
+
+    // Whether a vertex was pruned to cold storage
+    pub fn is_pruned(&self, id: &str) -> bool {
+        self.pruned.contains(id)
+    }
+
+    // Tips in id order
+    pub fn tips(&self) -> impl Iterator<Item = &String> + '_ {
+        self.tips.iter()
+    }
+
+    // Roots in id order
+    pub fn roots(&self) -> impl Iterator<Item = &String> + '_ {
+        self.roots.iter()
+    }
+
+    #[allow(dead_code)]
+    pub fn tip_count(&self) -> usize {
+        self.tips.len()
+    }
+
+    pub fn root_count(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn children(&self, id: &str) -> &[String] {
+        self.children.get(id).map_or(&[], |children| children.as_slice())
+    }
+
+    pub fn height_of(&self, id: &str) -> Option<u64> {
+        self.heights.get(id).copied()
+    }
+
+    // Height of the highest vertex
+    pub fn height(&self) -> u64 {
+        self.max_height
+    }
+
+    pub fn merkle(&self) -> &VertexMerkle {
+        &self.merkle
+    }
+}
+
+// Order vertices so every vertex comes after its parents. Vertices that are
+// ready at the same time are ordered by timestamp, then id, so every node
+// derives the same order. Parents outside `vertices` are ignored.
+pub fn topological_order(vertices: &[Vertex]) -> NodeResult<Vec<&Vertex>> {
+    let positions: HashMap<&str, usize> = vertices.iter()
+        .enumerate()
+        .map(|(position, vertex)| (vertex.id.as_str(), position))
+        .collect();
+
+    let mut pending = vec![0usize; vertices.len()];
+    let mut children: Vec<Vec<usize>> = vec![Vec::new(); vertices.len()];
+    for (position, vertex) in vertices.iter().enumerate() {
+        let parents: BTreeSet<&str> = vertex.parents.iter().map(String::as_str).collect();
+        for parent in parents {
+            if let Some(&parent_position) = positions.get(parent) {
+                pending[position] += 1;
+                children[parent_position].push(position);
+            }
+        }
+    }
+
+    let key = |position: usize| Reverse((vertices[position].timestamp, vertices[position].id.as_str(), position));
+    let mut ready: BinaryHeap<_> = (0..vertices.len())
+        .filter(|&position| pending[position] == 0)
+        .map(key)
+        .collect();
+
+    let mut order = Vec::with_capacity(vertices.len());
+    while let Some(Reverse((_, _, position))) = ready.pop() {
+        order.push(&vertices[position]);
+        for &child in &children[position] {
+            pending[child] -= 1;
+            if pending[child] == 0 {
+                ready.push(key(child));
+            }
+        }
+    }
+
+    if order.len() != vertices.len() {
+        return Err(NodeError::Dag(format!(
+            "DAG has a cycle through {} vertices", vertices.len() - order.len()
+        )));
+    }
+
+    Ok(order)
+}
+
+// Get DAG info
+pub async fn get_dag_info() -> NodeResult<DagInfo> {
+    state::manager().read(|state| {
+        let vertices = &state.dag_vertices;
+        let pruned = state.pruned_history.as_ref();
+        let graph = state.dag();
+        let tips: Vec<String> = graph.tips().cloned().collect();
+
+        let genesis_time = pruned.map(|pruned| pruned.genesis_time)
+            .or_else(|| vertices.first().map(|v| v.timestamp))
+            .unwrap_or_else(chrono::Utc::now);
+        let latest_update = vertices.last().map(|v| v.timestamp)
+            .or_else(|| pruned.map(|pruned| pruned.latest_time))
+            .unwrap_or_else(chrono::Utc::now);
+
+        DagInfo {
+            vertex_count: state.vertex_count(),
+            root_count: graph.root_count(),
+            tip_count: tips.len(),
+            genesis_time,
+            latest_update,
+            tips,
+            height: graph.height(),
+            state_root: graph.merkle().root(),
+            conflict_count: graph.conflict_count(),
+        }
     })
 }
 
-// Record a new vertex in the DAG
-pub async fn add_vertex(vertex: VertexEntry) -> NodeResult<()> {
-    // Add to state
-    state::add_vertex(vertex.clone())?;
-    
-    // Sync with federation
+// Get the Merkle root over the vertex set
+#[allow(dead_code)]
+pub fn get_state_root() -> NodeResult<String> {
+    state::manager().read(|state| state.dag().merkle().root())
+}
+
+// Get the subtree hashes at one level of the vertex Merkle tree
+pub fn get_range_hashes(level: u32) -> NodeResult<Vec<String>> {
+    state::manager().read(|state| state.dag().merkle().level(level))?
+}
+
+// Get the ids of the vertices in one Merkle bucket
+pub fn get_bucket_vertices(bucket: usize) -> NodeResult<Vec<String>> {
+    state::manager().read(|state| state.dag().merkle().bucket(bucket))?
+}
+
+// Record a vertex for a vote, registration or configuration change and
+// broadcast it to the federation. Proposal executions are recorded by the
+// executor.
+pub async fn record_payload(payload: VertexPayload) -> NodeResult<Vertex> {
+    if let VertexPayload::ProposalExecution(_) = payload {
+        return Err(NodeError::Validation("Proposal executions are recorded when a proposal is executed".to_string()));
+    }
+    payload.validate()?;
+
+    let proposal_id = payload.proposal_id().unwrap_or_default().to_string();
+    let hash = payload.digest()?;
+    let mut vertex = Vertex::new(proposal_id, hash, String::new(), payload);
+    let node_key = signing::node_key()?;
+
+    let vertex = state::write_async(move |state, tx| {
+        prepare_vertex(state, node_key, &mut vertex)?;
+        tx.add_vertex(vertex.clone());
+        Ok(vertex)
+    }).await?;
+
+    info!("Recorded {} vertex {}", vertex.data_type(), vertex.id);
     federation::broadcast_vertex(&vertex).await?;
-    
-    Ok(())
+
+    Ok(vertex)
+}
+
+// Add a vertex received from a peer. It is verified like any other vertex
+// but not broadcast again. A vertex whose parents are not all known yet is
+// held until they arrive, so the DAG never links to missing vertices.
+pub async fn ingest_vertex(vertex: Vertex) -> NodeResult<IngestStatus> {
+    verify_vertex(&vertex)?;
+
+    let id = vertex.id.clone();
+    let added = state::write_async(move |state, tx| {
+        if state.dag().contains(&vertex.id) {
+            return Ok(None);
+        }
+        verify_submitter(state, &vertex)?;
+
+        let mut orphans = ORPHANS.lock()
+            .map_err(|e| NodeError::Dag(format!("Failed to lock held vertices: {}", e)))?;
+
+        if !vertex.parents.iter().all(|parent| state.dag().contains(parent)) {
+            if orphans.len() >= MAX_ORPHANS && !orphans.contains_key(&vertex.id) {
+                return Err(NodeError::Dag(format!(
+                    "Too many vertices are waiting for their parents; vertex {} was dropped", vertex.id
+                )));
+            }
+            orphans.insert(vertex.id.clone(), vertex);
+            return Ok(Some(Vec::new()));
+        }
+
+        // Commit the vertex together with every held vertex it completes,
+        // parents first
+        orphans.retain(|id, _| !state.dag().contains(id));
+        let mut added = vec![vertex.id.clone()];
+        let mut known: HashSet<String> = added.iter().cloned().collect();
+        tx.add_vertex(vertex);
+
+        loop {
+            let mut ready: Vec<String> = orphans.values()
+                .filter(|orphan| orphan.parents.iter().all(|parent| known.contains(parent) || state.dag().contains(parent)))
+                .map(|orphan| orphan.id.clone())
+                .collect();
+            if ready.is_empty() {
+                break;
+            }
+
+            ready.sort();
+            for id in ready {
+                if let Some(orphan) = orphans.remove(&id) {
+                    tx.add_vertex(orphan);
+                }
+                known.insert(id.clone());
+                added.push(id);
+            }
+        }
+
+        Ok(Some(added))
+    }).await?;
+
+    match added {
+        None => Ok(IngestStatus::Known),
+        Some(added) if added.is_empty() => {
+            info!("Holding vertex {} from a peer until its parents arrive", id);
+            Ok(IngestStatus::Held)
+        }
+        Some(added) => {
+            for added_id in &added {
+                info!("Received vertex {} from a peer", added_id);
+            }
+            Ok(IngestStatus::Added)
+        }
+    }
 }
 
 // Get all vertices
-pub fn get_all_vertices() -> NodeResult<Vec<VertexEntry>> {
-    let state = state::get::<state::NodeState>("state")?;
-    Ok(state.dag_vertices)
+#[allow(dead_code)]
+pub fn get_all_vertices() -> NodeResult<Vec<Vertex>> {
+    state::manager().read(|state| state.dag_vertices.clone())
 }
 
-// Get specific vertex by ID
-pub fn get_vertex(id: &str) -> NodeResult<VertexEntry> {
-    let vertices = get_all_vertices()?;
-    
-    vertices.iter()
-        .find(|v| v.id == id)
-        .cloned()
-        .ok_or_else(|| NodeError::Dag(format!("Vertex not found: {}", id)))
+// Get vertices added after the first `skip`
+fn get_vertices_from(skip: usize) -> NodeResult<Vec<Vertex>> {
+    state::manager().read(|state| {
+        let pruned = state.vertex_count() - state.dag_vertices.len();
+        state.dag_vertices.iter().skip(skip.saturating_sub(pruned)).cloned().collect()
+    })
+}
+
+// Get specific vertex by ID, looking in cold storage for pruned vertices
+pub fn get_vertex(id: &str) -> NodeResult<Vertex> {
+    if let Some(vertex) = state::get_vertex(id)? {
+        return Ok(vertex);
+    }
+
+    if state::manager().read(|state| state.dag().is_pruned(id))? {
+        if let Some(vertex) = checkpoint::find_archived_vertex(id)? {
+            return Ok(vertex);
+        }
+    }
+
+    Err(NodeError::Dag(format!("Vertex not found: {}", id)))
+}
+
+// Get all conflicts with their resolution
+pub fn get_conflicts() -> NodeResult<Vec<Conflict>> {
+    state::manager().read(|state| state.dag().conflicts())
+}
+
+// Get the ids of the vertices that reference `id` as a parent
+#[allow(dead_code)]
+pub fn get_children(id: &str) -> NodeResult<Vec<String>> {
+    state::manager().read(|state| state.dag().children(id).to_vec())
+}
+
+// Get the height of a vertex, if it is known
+#[allow(dead_code)]
+pub fn get_height(id: &str) -> NodeResult<Option<u64>> {
+    state::manager().read(|state| state.dag().height_of(id))
+}
+
+// Get a vertex together with its height and children
+pub fn get_vertex_details(id: &str) -> NodeResult<VertexDetails> {
+    let vertex = get_vertex(id)?;
+    state::manager().read(|state| {
+        let graph = state.dag();
+        VertexDetails {
+            data_type: vertex.data_type().to_string(),
+            scope: vertex.scope().map(str::to_string),
+            height: graph.height_of(id),
+            children: graph.children(id).to_vec(),
+            pruned: graph.is_pruned(id),
+            superseded: graph.is_superseded(id),
+            vertex,
+        }
+    })
+}
+
+fn ensure_known(id: &str) -> NodeResult<()> {
+    if state::manager().read(|state| state.dag().contains(id))? {
+        Ok(())
+    } else {
+        Err(NodeError::Dag(format!("Vertex not found: {}", id)))
+    }
+}
+
+// Breadth-first walk from `id` along the links returned by `next`, up to
+// `max_depth` links away. Vertices are listed nearest first, then by id.
+fn walk<F>(id: &str, max_depth: Option<usize>, mut next: F) -> NodeResult<Vec<RelatedVertex>>
+where
+    F: FnMut(&str) -> NodeResult<Vec<String>>,
+{
+    let mut seen = HashSet::from([id.to_string()]);
+    let mut frontier = vec![id.to_string()];
+    let mut related = Vec::new();
+    let mut depth = 0;
+
+    while !frontier.is_empty() && max_depth.is_none_or(|max| depth < max) {
+        depth += 1;
+
+        let mut level = BTreeSet::new();
+        for current in &frontier {
+            for linked in next(current)? {
+                if seen.insert(linked.clone()) {
+                    level.insert(linked);
+                }
+            }
+        }
+
+        related.extend(level.iter().map(|id| RelatedVertex { id: id.clone(), depth }));
+        frontier = level.into_iter().collect();
+    }
+
+    Ok(related)
+}
+
+// Get the ancestors of a vertex, up to `max_depth` generations back. Pruned
+// ancestors are listed but not followed further.
+pub fn get_ancestors(id: &str, max_depth: Option<usize>) -> NodeResult<Vec<RelatedVertex>> {
+    ensure_known(id)?;
+    walk(id, max_depth, |current| {
+        Ok(state::get_vertex(current)?.map(|vertex| vertex.parents).unwrap_or_default())
+    })
+}
+
+// Get the descendants of a vertex, up to `max_depth` generations down
+pub fn get_descendants(id: &str, max_depth: Option<usize>) -> NodeResult<Vec<RelatedVertex>> {
+    ensure_known(id)?;
+    state::manager().read(|state| {
+        walk(id, max_depth, |current| Ok(state.dag().children(current).to_vec()))
+    })?
+}
+
+// Get a shortest path of child links from `from` down to `to`, or None if
+// `to` does not descend from `from`
+pub fn find_path(from: &str, to: &str) -> NodeResult<Option<Vec<String>>> {
+    ensure_known(from)?;
+    ensure_known(to)?;
+
+    state::manager().read(|state| {
+        let graph = state.dag();
+        let mut previous: HashMap<&str, &str> = HashMap::new();
+        let mut queue = VecDeque::from([from]);
+        let mut found = from == to;
+
+        while let Some(current) = queue.pop_front() {
+            if found {
+                break;
+            }
+
+            for child in graph.children(current) {
+                let child = child.as_str();
+                if child == from || previous.contains_key(child) {
+                    continue;
+                }
+
+                previous.insert(child, current);
+                if child == to {
+                    found = true;
+                    break;
+                }
+                queue.push_back(child);
+            }
+        }
+
+        if !found {
+            return None;
+        }
+
+        let mut path = vec![to.to_string()];
+        let mut current = to;
+        while let Some(&parent) = previous.get(current) {
+            path.push(parent.to_string());
+            current = parent;
+        }
+        path.reverse();
+        Some(path)
+    })
+}
+
+// List retained vertices matching a query, oldest first, `limit` at a time
+// (at most `MAX_PAGE_SIZE`) starting at `offset`
+pub fn query_vertices(query: &VertexQuery, offset: usize, limit: usize) -> NodeResult<VertexPage> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let range = (
+        query.since.map_or(Bound::Unbounded, Bound::Included),
+        query.until.map_or(Bound::Unbounded, Bound::Excluded),
+    );
+
+    state::manager().read(|state| {
+        // Start from the narrowest index
+        let mut candidates = match (&query.hash, &query.proposal_id, &query.submitter) {
+            (Some(hash), _, _) => state.vertices_with_hash(hash),
+            (None, Some(proposal_id), _) => state.vertices_for_proposal(proposal_id),
+            (None, None, Some(submitter)) => state.vertices_by_submitter(submitter),
+            (None, None, None) => state.vertices_between(range),
+        };
+        candidates.sort_by_key(|vertex| vertex.timestamp);
+
+        let matching: Vec<&Vertex> = candidates.into_iter()
+            .filter(|vertex| range.contains(&vertex.timestamp))
+            .filter(|vertex| query.proposal_id.as_ref().is_none_or(|id| &vertex.proposal_id == id))
+            .filter(|vertex| query.submitter.as_ref().is_none_or(|submitter| &vertex.submitter == submitter))
+            .filter(|vertex| query.hash.as_ref().is_none_or(|hash| &vertex.hash == hash))
+            .collect();
+
+        let total = matching.len();
+        let vertices: Vec<Vertex> = matching.into_iter().skip(offset).take(limit).cloned().collect();
+        let next_offset = (offset + vertices.len() < total).then_some(offset + vertices.len());
+
+        VertexPage { vertices, total, offset, limit, next_offset }
+    })
 }
 
 // Watch the DAG for changes
@@ -100,18 +1096,15 @@ pub async fn watch_dag(tx: mpsc::Sender<String>) -> NodeResult<()> {
     
     loop {
         // Check for new vertices
-        let vertices = get_all_vertices()?;
-        let current_count = vertices.len();
+        let new_vertices = get_vertices_from(last_count)?;
         
-        if current_count > last_count {
-            let new_vertices = vertices.iter().skip(last_count).cloned().collect::<Vec<_>>();
-            
+        if !new_vertices.is_empty() {
             for vertex in &new_vertices {
                 tx.send(format!("New DAG vertex: {}", vertex.id)).await
                     .map_err(|e| NodeError::Dag(format!("Failed to send DAG event: {}", e)))?;
             }
             
-            last_count = current_count;
+            last_count += new_vertices.len();
         }
         
         // Wait before checking again
@@ -119,27 +1112,247 @@ pub async fn watch_dag(tx: mpsc::Sender<String>) -> NodeResult<()> {
     }
 }
 
-// Get DAG logs
-pub fn get_dag_logs() -> NodeResult<String> {
-    let log_path = get_dag_log_path()?;
-    
-    if !log_path.exists() {
-        return Ok("No DAG logs found".to_string());
+// Stream DAG log entries matching a filter, oldest first
+pub fn get_dag_logs(filter: DagLogFilter) -> NodeResult<DagLogReader> {
+    dag_log::read(filter)
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vertex(id: &str, parents: &[&str]) -> Vertex {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "proposal_id": "proposal-1",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "hash": "abc",
+            "parents": parents,
+        }))
+        .unwrap()
     }
-    
-    let content = fs::read_to_string(log_path)
-        .map_err(|e| NodeError::Dag(format!("Failed to read DAG log: {}", e)))?;
-    
-    Ok(content)
-}
 
-// Get DAG log path
-fn get_dag_log_path() -> NodeResult<PathBuf> {
-    let state_dir = state::get_state_dir()?;
-    let logs_dir = state_dir.join("logs");
-    
-    // Ensure the directory exists
-    fs::create_dir_all(&logs_dir)?;
-    
-    Ok(logs_dir.join("dag.log"))
-} 
\ No newline at end of file
+    #[test]
+    fn tracks_tips_roots_and_heights() {
+        let mut graph = DagGraph::default();
+        graph.insert(&vertex("a", &[]));
+        graph.insert(&vertex("b", &["a"]));
+        graph.insert(&vertex("c", &["a"]));
+        graph.insert(&vertex("d", &["b", "c"]));
+
+        assert_eq!(graph.roots().collect::<Vec<_>>(), vec!["a"]);
+        assert_eq!(graph.tips().collect::<Vec<_>>(), vec!["d"]);
+        assert_eq!(graph.height_of("d"), Some(2));
+        assert_eq!(graph.height(), 2);
+        assert_eq!(graph.children("a"), ["b", "c"]);
+    }
+
+    #[test]
+    fn raises_heights_when_a_parent_arrives_late() {
+        let mut graph = DagGraph::default();
+        graph.insert(&vertex("a", &[]));
+        graph.insert(&vertex("d", &["c"]));
+        graph.insert(&vertex("e", &["d"]));
+        assert_eq!(graph.height_of("e"), Some(1));
+
+        graph.insert(&vertex("b", &["a"]));
+        graph.insert(&vertex("c", &["b"]));
+
+        assert_eq!(graph.height_of("c"), Some(2));
+        assert_eq!(graph.height_of("d"), Some(3));
+        assert_eq!(graph.height_of("e"), Some(4));
+        assert_eq!(graph.height(), 4);
+        assert_eq!(graph.tips().collect::<Vec<_>>(), vec!["e"]);
+        assert_eq!(graph.roots().collect::<Vec<_>>(), vec!["a"]);
+    }
+
+    #[test]
+    fn rejects_legacy_vertices() {
+        let mut legacy = vertex("0b6c8f7e-5d0a-4a55-9a5e-7f1c8b3e2d10", &[]);
+        legacy.version = crate::vertex::LEGACY_VERTEX_FORMAT_VERSION;
+        assert!(legacy.is_legacy());
+        assert!(verify_vertex(&legacy).is_err());
+
+        // Nor is a current vertex without a signature
+        let mut unsigned = legacy.clone();
+        unsigned.version = crate::vertex::VERTEX_FORMAT_VERSION;
+        unsigned.id = compute_vertex_id(&unsigned).unwrap();
+        assert!(verify_vertex(&unsigned).is_err());
+    }
+
+    // A vertex recorded and signed by `submitter` with `key`
+    fn signed(submitter: &str, key: &SigningKey) -> Vertex {
+        let mut vertex = vertex("", &[]);
+        vertex.version = crate::vertex::VERTEX_FORMAT_VERSION;
+        vertex.submitter = submitter.to_string();
+        vertex.id = compute_vertex_id(&vertex).unwrap();
+        signing::sign_vertex(key, &mut vertex);
+        vertex
+    }
+
+    fn state_with_peer(id: &str, key: &SigningKey) -> NodeState {
+        let mut state = NodeState::default();
+        state.extensions.insert("federation_config".to_string(), serde_json::json!({
+            "federation_name": "test",
+            "node_id": state.node_id,
+            "node_name": "node",
+            "peers": [{ "id": id, "name": id, "address": "http://peer:26657", "last_seen": null,
+                        "public_key": signing::public_key(key) }],
+            "sync_endpoint": "http://peer:26657/dag/sync",
+        }));
+        state
+    }
+
+    #[test]
+    fn binds_submitters_to_registered_keys() {
+        let peer_key = SigningKey::from_bytes(&[1; 32]);
+        let other_key = SigningKey::from_bytes(&[2; 32]);
+        let state = state_with_peer("peer-1", &peer_key);
+
+        let vertex = signed("peer-1", &peer_key);
+        verify_vertex(&vertex).unwrap();
+        verify_submitter(&state, &vertex).unwrap();
+
+        // Validly signed, but not with the key registered for the submitter
+        let forged = signed("peer-1", &other_key);
+        verify_vertex(&forged).unwrap();
+        assert!(verify_submitter(&state, &forged).is_err());
+
+        // Submitters that are not federation peers are not accepted at all
+        assert!(verify_submitter(&state, &signed("peer-2", &other_key)).is_err());
+        assert!(verify_submitter(&NodeState::default(), &vertex).is_err());
+    }
+
+    // A vertex signed with `key` claiming `result` for proposal-1
+    fn execution(key: &SigningKey, result: &str, parents: &[&str], timestamp: &str) -> Vertex {
+        let mut vertex = vertex("", parents);
+        vertex.version = crate::vertex::VERTEX_FORMAT_VERSION;
+        vertex.submitter = format!("node-{}", key.to_bytes()[0]);
+        vertex.result_hash = result.to_string();
+        vertex.timestamp = parse_timestamp(timestamp).unwrap();
+        vertex.id = compute_vertex_id(&vertex).unwrap();
+        signing::sign_vertex(key, &mut vertex);
+        vertex
+    }
+
+    // Two member keys, the first ranking before the second
+    fn ranked_keys() -> (SigningKey, SigningKey) {
+        let a = SigningKey::from_bytes(&[1; 32]);
+        let b = SigningKey::from_bytes(&[2; 32]);
+        if signing::public_key(&a) < signing::public_key(&b) { (a, b) } else { (b, a) }
+    }
+
+    fn conflict_in(vertices: &[&Vertex]) -> Conflict {
+        let mut graph = DagGraph::default();
+        for vertex in vertices {
+            graph.insert(vertex);
+        }
+        let mut conflicts = graph.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        conflicts.remove(0)
+    }
+
+    #[test]
+    fn concurrent_claims_are_ranked_by_key_not_id() {
+        let (low, high) = ranked_keys();
+
+        let expected = execution(&low, "result-a", &[], "2024-01-01T00:00:00Z");
+        // Whatever id the other member grinds for its vertex, it keeps losing
+        for second in 0..5 {
+            let rival = execution(&high, "result-b", &[], &format!("2024-01-01T00:00:0{}Z", second));
+            let conflict = conflict_in(&[&expected, &rival]);
+            assert_eq!(conflict.kind, ConflictKind::Fork);
+            assert_eq!(conflict.winner, expected.id);
+            assert_eq!(conflict.superseded, vec![rival.id.clone()]);
+        }
+    }
+
+    #[test]
+    fn earlier_claims_win_over_their_descendants() {
+        let (low, high) = ranked_keys();
+
+        let first = execution(&high, "result-a", &[], "2024-01-01T00:00:00Z");
+        let middle = vertex("middle", &[&first.id]);
+        let later = execution(&low, "result-b", &["middle"], "2024-01-01T00:00:01Z");
+
+        let conflict = conflict_in(&[&later, &middle, &first]);
+        assert_eq!(conflict.winner, first.id);
+        assert_eq!(conflict.superseded, vec![later.id.clone()]);
+    }
+
+    #[test]
+    fn equivocating_keys_rank_last() {
+        let (low, high) = ranked_keys();
+
+        let one = execution(&low, "result-a", &[], "2024-01-01T00:00:00Z");
+        let two = execution(&low, "result-b", &[], "2024-01-01T00:00:01Z");
+        let honest = execution(&high, "result-b", &[], "2024-01-01T00:00:02Z");
+
+        let conflict = conflict_in(&[&one, &two, &honest]);
+        assert_eq!(conflict.kind, ConflictKind::Equivocation);
+        assert_eq!(conflict.winner, honest.id);
+        assert_eq!(conflict.superseded, vec![one.id.clone()]);
+    }
+
+    #[test]
+    fn merkle_root_depends_only_on_the_vertex_set() {
+        let mut forward = VertexMerkle::default();
+        let mut backward = VertexMerkle::default();
+        let ids: Vec<String> = (0..50).map(|i| format!("vertex-{}", i)).collect();
+
+        for id in &ids {
+            forward.insert(id);
+        }
+        for id in ids.iter().rev() {
+            backward.insert(id);
+        }
+        backward.insert(&ids[0]);
+
+        assert_eq!(forward.root(), backward.root());
+        assert_ne!(forward.root(), VertexMerkle::default().root());
+        assert_eq!(forward.level(0).unwrap(), vec![forward.root()]);
+    }
+
+    #[test]
+    fn merkle_levels_lead_to_the_differing_bucket() {
+        let mut ours = VertexMerkle::default();
+        let mut theirs = VertexMerkle::default();
+        for i in 0..20 {
+            ours.insert(&format!("vertex-{}", i));
+            theirs.insert(&format!("vertex-{}", i));
+        }
+        theirs.insert("extra");
+
+        // Walk down from the root, always into the one subtree that differs
+        let mut subtree = 0;
+        for level in 1..=MERKLE_DEPTH {
+            let (a, b) = (ours.level(level).unwrap(), theirs.level(level).unwrap());
+            assert_eq!(a.len(), 1 << level);
+            let differing: Vec<usize> = (0..a.len()).filter(|&i| a[i] != b[i]).collect();
+            assert_eq!(differing.len(), 1);
+            assert_eq!(differing[0] / 2, subtree);
+            subtree = differing[0];
+        }
+
+        let bucket = Sha256::digest(b"extra")[0] as usize;
+        assert_eq!(subtree, bucket);
+        assert!(theirs.bucket(bucket).unwrap().contains(&"extra".to_string()));
+        assert!(!ours.bucket(bucket).unwrap().contains(&"extra".to_string()));
+    }
+
+    #[test]
+    fn merkle_rejects_positions_outside_the_tree() {
+        let tree = VertexMerkle::default();
+        assert!(tree.level(MERKLE_DEPTH + 1).is_err());
+        assert!(tree.bucket(MERKLE_BUCKETS).is_err());
+    }
+
+    #[test]
+    fn orders_parents_before_children() {
+        let vertices = vec![vertex("c", &["b"]), vertex("b", &["a"]), vertex("a", &[])];
+        let order: Vec<&str> = topological_order(&vertices).unwrap().iter().map(|v| v.id.as_str()).collect();
+        assert_eq!(order, vec!["a", "b", "c"]);
+
+        let cycle = vec![vertex("a", &["b"]), vertex("b", &["a"])];
+        assert!(topological_order(&cycle).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::dag::{self, VertexMerkle};
+use crate::error::{NodeError, NodeResult};
+use crate::queue::{self, ProposalStatus};
+use crate::state;
+use crate::vertex::Vertex;
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Bound;
+use std::path::Path;
+use tracing::{info, warn};
+
+// Layout version of DAG archives written by this build
+pub const DAG_ARCHIVE_FORMAT_VERSION: u32 = 1;
+
+const MANIFEST_NAME: &str = "manifest.json";
+const VERTICES_NAME: &str = "vertices.jsonl";
+
+// Output format of `dag export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// Graphviz DOT, vertices colored by proposal status
+    Dot,
+    /// One JSON vertex per line, parents before children
+    Jsonl,
+    /// Compressed archive with a manifest, importable with `dag import`
+    Archive,
+}
+
+// Which vertices to export
+#[derive(Debug, Clone, Default)]
+pub struct ExportFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub proposal_id: Option<String>,
+}
+
+// Describes a DAG archive and the checksum of its vertex file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DagManifest {
+    pub format_version: u32,
+    pub node_version: String,
+    pub created: DateTime<Utc>,
+    pub node_id: String,
+    pub vertex_count: usize,
+    // Merkle root over the exported vertex ids
+    pub state_root: String,
+    pub sha256: String,
+}
+
+// Outcome of a DAG import
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub known: usize,
+    // Vertex ids that failed id or signature verification
+    pub rejected: Vec<String>,
+}
+
+// Vertices matching a filter, parents before children
+pub fn select(filter: &ExportFilter) -> NodeResult<Vec<Vertex>> {
+    let range = (
+        filter.since.map_or(Bound::Unbounded, Bound::Included),
+        filter.until.map_or(Bound::Unbounded, Bound::Excluded),
+    );
+
+    let selected: Vec<Vertex> = state::get_vertices_between(range)?.into_iter()
+        .filter(|vertex| filter.proposal_id.as_ref().is_none_or(|id| &vertex.proposal_id == id))
+        .collect();
+
+    let ordered = dag::topological_order(&selected)?.into_iter().cloned().collect();
+    Ok(ordered)
+}
+
+// Render vertices as a Graphviz digraph with edges from parent to child
+pub fn to_dot(vertices: &[Vertex]) -> NodeResult<String> {
+    let statuses = queue::get_proposal_statuses()?;
+    let executed = state::get_executed_proposals()?;
+
+    let mut dot = String::from("digraph dag {\n    rankdir=LR;\n    node [shape=box, style=filled, fontname=\"monospace\"];\n");
+
+    for vertex in vertices {
+        let status = statuses.get(&vertex.proposal_id).cloned().or_else(|| {
+            executed.contains(&vertex.proposal_id).then_some(ProposalStatus::Completed)
+        });
+
+        let color = match status {
+            Some(ProposalStatus::Completed) => "palegreen",
+            Some(ProposalStatus::Executing) => "khaki",
+            Some(ProposalStatus::Pending) => "lightblue",
+            Some(ProposalStatus::Failed) => "salmon",
+            Some(ProposalStatus::Rejected) => "orange",
+            None => "lightgrey",
+        };
+
+        let short_id: String = vertex.id.chars().take(12).collect();
+        let subject = match (vertex.is_execution(), vertex.scope()) {
+            (true, _) => format!("proposal {}", vertex.proposal_id),
+            (false, Some(scope)) => format!("{} in {}", vertex.data_type(), scope),
+            (false, None) => format!("{} on proposal {}", vertex.data_type(), vertex.proposal_id),
+        };
+        let _ = writeln!(
+            dot,
+            "    \"{}\" [label=\"{}\\n{}\\n{}\", fillcolor={}];",
+            escape(&vertex.id), escape(&short_id), escape(&subject),
+            vertex.timestamp.format("%Y-%m-%d %H:%M:%S"), color
+        );
+    }
+
+    for vertex in vertices {
+        for parent in &vertex.parents {
+            let _ = writeln!(dot, "    \"{}\" -> \"{}\";", escape(parent), escape(&vertex.id));
+        }
+    }
+
+    dot.push_str("}\n");
+    Ok(dot)
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Encode vertices as JSON Lines
+pub fn to_jsonl(vertices: &[Vertex]) -> NodeResult<Vec<u8>> {
+    let mut content = Vec::new();
+    for vertex in vertices {
+        serde_json::to_writer(&mut content, vertex)?;
+        content.push(b'\n');
+    }
+    Ok(content)
+}
+
+fn append_bytes(builder: &mut tar::Builder<GzEncoder<File>>, name: &str, content: &[u8]) -> NodeResult<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(content.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    header.set_entry_type(tar::EntryType::Regular);
+
+    builder.append_data(&mut header, name, content)
+        .map_err(|e| NodeError::Dag(format!("Failed to add {} to DAG archive: {}", name, e)))
+}
+
+// Write vertices to a DAG archive: the vertices as JSON Lines plus a manifest
+// with their count, Merkle root and checksum
+pub fn write_archive(vertices: &[Vertex], output: &Path) -> NodeResult<DagManifest> {
+    let content = to_jsonl(vertices)?;
+
+    let mut merkle = VertexMerkle::default();
+    for vertex in vertices {
+        merkle.insert(&vertex.id);
+    }
+
+    let manifest = DagManifest {
+        format_version: DAG_ARCHIVE_FORMAT_VERSION,
+        node_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: Utc::now(),
+        node_id: state::get::<String>("node_id")?,
+        vertex_count: vertices.len(),
+        state_root: merkle.root(),
+        sha256: format!("{:x}", Sha256::digest(&content)),
+    };
+
+    let file = File::create(output)
+        .map_err(|e| NodeError::Dag(format!("Failed to create DAG archive {:?}: {}", output, e)))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    append_bytes(&mut builder, MANIFEST_NAME, &serde_json::to_vec_pretty(&manifest)?)?;
+    append_bytes(&mut builder, VERTICES_NAME, &content)?;
+
+    builder.into_inner()
+        .and_then(|encoder| encoder.finish())
+        .and_then(|file| file.sync_all())
+        .map_err(|e| NodeError::Dag(format!("Failed to write DAG archive {:?}: {}", output, e)))?;
+
+    info!("Exported {} vertices to {:?}", manifest.vertex_count, output);
+    Ok(manifest)
+}
+
+// Export the selected vertices in the given format, to a file or stdout
+pub fn export(format: ExportFormat, filter: &ExportFilter, output: Option<&Path>) -> NodeResult<usize> {
+    let vertices = select(filter)?;
+
+    let content = match format {
+        ExportFormat::Dot => to_dot(&vertices)?.into_bytes(),
+        ExportFormat::Jsonl => to_jsonl(&vertices)?,
+        ExportFormat::Archive => {
+            let output = output
+                .ok_or_else(|| NodeError::Dag("Archive exports need --output".to_string()))?;
+            write_archive(&vertices, output)?;
+            return Ok(vertices.len());
+        }
+    };
+
+    match output {
+        Some(path) => std::fs::write(path, &content)
+            .map_err(|e| NodeError::Dag(format!("Failed to write {:?}: {}", path, e)))?,
+        None => std::io::stdout().write_all(&content)?,
+    }
+
+    Ok(vertices.len())
+}
+
+// Read and verify a DAG archive
+pub fn read_archive(archive: &Path) -> NodeResult<(DagManifest, Vec<Vertex>)> {
+    let file = File::open(archive)
+        .map_err(|e| NodeError::Dag(format!("Failed to open DAG archive {:?}: {}", archive, e)))?;
+    let mut tar = tar::Archive::new(GzDecoder::new(file));
+
+    let mut manifest: Option<DagManifest> = None;
+    let mut content: Option<Vec<u8>> = None;
+
+    let entries = tar.entries()
+        .map_err(|e| NodeError::Dag(format!("Failed to read DAG archive {:?}: {}", archive, e)))?;
+
+    for entry in entries {
+        let mut entry = entry
+            .map_err(|e| NodeError::Dag(format!("Failed to read DAG archive {:?}: {}", archive, e)))?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+
+        match name.as_str() {
+            MANIFEST_NAME => {
+                manifest = Some(serde_json::from_slice(&data)
+                    .map_err(|e| NodeError::Dag(format!("Invalid DAG archive manifest: {}", e)))?);
+            }
+            VERTICES_NAME => content = Some(data),
+            other => {
+                return Err(NodeError::Dag(format!("DAG archive contains unexpected file {}", other)));
+            }
+        }
+    }
+
+    let manifest = manifest
+        .ok_or_else(|| NodeError::Dag(format!("DAG archive {:?} has no manifest", archive)))?;
+    let content = content
+        .ok_or_else(|| NodeError::Dag(format!("DAG archive {:?} has no vertices", archive)))?;
+
+    if manifest.format_version > DAG_ARCHIVE_FORMAT_VERSION {
+        return Err(NodeError::Dag(format!(
+            "DAG archive format version {} is newer than the version {} supported by icn-node {}",
+            manifest.format_version, DAG_ARCHIVE_FORMAT_VERSION, env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    let checksum = format!("{:x}", Sha256::digest(&content));
+    if checksum != manifest.sha256 {
+        return Err(NodeError::Dag(format!(
+            "Checksum mismatch for {}: expected sha256 {}, found {}", VERTICES_NAME, manifest.sha256, checksum
+        )));
+    }
+
+    let mut vertices = Vec::with_capacity(manifest.vertex_count);
+    for (number, line) in content.split(|b| *b == b'\n').enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+
+        let vertex: Vertex = serde_json::from_slice(line)
+            .map_err(|e| NodeError::Dag(format!("Invalid vertex on line {} of {}: {}", number + 1, VERTICES_NAME, e)))?;
+        vertices.push(vertex);
+    }
+
+    if vertices.len() != manifest.vertex_count {
+        return Err(NodeError::Dag(format!(
+            "DAG archive lists {} vertices but contains {}", manifest.vertex_count, vertices.len()
+        )));
+    }
+
+    Ok((manifest, vertices))
+}
+
+// Import the vertices of a DAG archive. Vertices that fail verification are
+// reported and left out; the rest are committed as one transaction.
+pub fn import(archive: &Path) -> NodeResult<(DagManifest, ImportReport)> {
+    let (manifest, vertices) = read_archive(archive)?;
+    let mut report = ImportReport::default();
+
+    let mut tx = state::Transaction::new();
+    for vertex in dag::topological_order(&vertices)? {
+        let verified = dag::verify_vertex(vertex)
+            .and_then(|_| state::manager().read(|state| dag::verify_submitter(state, vertex))?);
+        if let Err(e) = verified {
+            warn!("Skipping vertex {} from DAG archive: {}", vertex.id, e);
+            report.rejected.push(vertex.id.clone());
+            continue;
+        }
+
+        if state::get_vertex(&vertex.id)?.is_some() {
+            report.known += 1;
+            continue;
+        }
+
+        tx.add_vertex(vertex.clone());
+        report.imported += 1;
+    }
+
+    state::commit(tx)?;
+
+    info!(
+        "Imported {} vertices from DAG archive of node {} ({} already known, {} rejected)",
+        report.imported, manifest.node_id, report.known, report.rejected.len()
+    );
+    Ok((manifest, report))
+}
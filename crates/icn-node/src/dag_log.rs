@@ -0,0 +1,480 @@
+use crate::crypto::{self, RekeyReport};
+use crate::error::{NodeError, NodeResult};
+use crate::state;
+use crate::vertex::Vertex;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::warn;
+
+// Rotate the current log once it reaches this size...
+const MAX_LOG_BYTES: u64 = 10 * 1024 * 1024;
+// ...or once its first entry is this old
+const MAX_LOG_AGE_HOURS: i64 = 24;
+// Rotated logs kept; older ones are deleted
+const MAX_ROTATED_LOGS: usize = 50;
+
+const CURRENT_NAME: &str = "current.jsonl";
+const ROTATED_PREFIX: &str = "dag-";
+const ROTATED_SUFFIX: &str = ".jsonl.gz";
+
+// What happened in the DAG
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DagEvent {
+    VertexAdded,
+    // The vertex made a claim that conflicts with another vertex's
+    ConflictDetected,
+}
+
+// One line of the DAG log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DagLogEntry {
+    pub logged_at: DateTime<Utc>,
+    pub event: DagEvent,
+    pub vertex_id: String,
+    pub proposal_id: String,
+    pub data_type: String,
+    pub hash: String,
+    pub submitter: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub parents: Vec<String>,
+    #[serde(default)]
+    pub detail: Option<String>,
+}
+
+impl DagLogEntry {
+    pub fn for_vertex(event: DagEvent, vertex: &Vertex) -> Self {
+        Self {
+            logged_at: Utc::now(),
+            event,
+            vertex_id: vertex.id.clone(),
+            proposal_id: vertex.proposal_id.clone(),
+            data_type: vertex.data_type().to_string(),
+            hash: vertex.hash.clone(),
+            submitter: vertex.submitter.clone(),
+            timestamp: vertex.timestamp,
+            parents: vertex.parents.clone(),
+            detail: None,
+        }
+    }
+}
+
+// Which log entries to read; all conditions must hold
+#[derive(Debug, Clone, Default)]
+pub struct DagLogFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub proposal_id: Option<String>,
+    pub vertex_id: Option<String>,
+}
+
+impl DagLogFilter {
+    pub fn matches(&self, entry: &DagLogEntry) -> bool {
+        self.since.is_none_or(|since| entry.logged_at >= since)
+            && self.until.is_none_or(|until| entry.logged_at < until)
+            && self.proposal_id.as_ref().is_none_or(|id| &entry.proposal_id == id)
+            && self.vertex_id.as_ref().is_none_or(|id| &entry.vertex_id == id)
+    }
+}
+
+// The open current log: its size and when its first entry was written
+struct LogWriter {
+    path: PathBuf,
+    size: u64,
+    started: Option<DateTime<Utc>>,
+}
+
+static WRITER: Lazy<Mutex<Option<LogWriter>>> = Lazy::new(|| Mutex::new(None));
+
+// Directory holding the current DAG log and its rotated, compressed predecessors
+pub fn get_dag_log_dir() -> NodeResult<PathBuf> {
+    Ok(state::get_state_dir()?.join("logs").join("dag"))
+}
+
+fn first_entry_time(path: &Path) -> Option<DateTime<Utc>> {
+    let file = File::open(path).ok()?;
+    let line = BufReader::new(file).lines().next()?.ok()?;
+    parse_line(path, &line).ok().map(|entry| entry.logged_at)
+}
+
+impl LogWriter {
+    fn open() -> NodeResult<Self> {
+        let dir = get_dag_log_dir()?;
+        fs::create_dir_all(&dir)?;
+
+        let path = dir.join(CURRENT_NAME);
+        let size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+        let started = first_entry_time(&path);
+
+        Ok(Self { path, size, started })
+    }
+
+    fn needs_rotation(&self, now: DateTime<Utc>) -> bool {
+        self.size >= MAX_LOG_BYTES
+            || self.started.is_some_and(|started| now - started >= Duration::hours(MAX_LOG_AGE_HOURS))
+    }
+
+    // Compress the current log into a file named after its first entry and start a new one
+    fn rotate(&mut self) -> NodeResult<()> {
+        let started = self.started.unwrap_or_else(Utc::now);
+        let dir = self.path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let rotated = dir.join(rotated_name(started));
+
+        let content = fs::read(&self.path)?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&content)?;
+        state::write_atomic(&rotated, &encoder.finish()?)
+            .map_err(|e| NodeError::Dag(format!("Failed to write rotated DAG log {:?}: {}", rotated, e)))?;
+        fs::remove_file(&self.path)?;
+
+        self.size = 0;
+        self.started = None;
+
+        let rotated_logs = list_rotated(&dir)?;
+        let excess = rotated_logs.len().saturating_sub(MAX_ROTATED_LOGS);
+        for old in &rotated_logs[..excess] {
+            if let Err(e) = fs::remove_file(old) {
+                warn!("Failed to remove old DAG log {:?}: {}", old, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append(&mut self, entry: &DagLogEntry) -> NodeResult<()> {
+        if self.needs_rotation(entry.logged_at) {
+            self.rotate()?;
+        }
+
+        // Entries are sealed one per line so the log can still be appended to
+        let mut line = crypto::seal(&serde_json::to_vec(entry)?)?;
+        line.push(b'\n');
+
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&line)?;
+
+        self.size += line.len() as u64;
+        self.started.get_or_insert(entry.logged_at);
+        Ok(())
+    }
+}
+
+// Append an entry to the DAG log, rotating it first if it is too large or old
+pub fn append(entry: &DagLogEntry) -> NodeResult<()> {
+    let mut writer = WRITER.lock()
+        .map_err(|_| NodeError::Dag("DAG log writer lock poisoned".to_string()))?;
+
+    // Reopen if the data directory changed (e.g. after an import)
+    let dir = get_dag_log_dir()?;
+    if writer.as_ref().is_none_or(|writer| !writer.path.starts_with(&dir)) {
+        *writer = Some(LogWriter::open()?);
+    }
+
+    match writer.as_mut() {
+        Some(writer) => writer.append(entry),
+        None => Ok(()),
+    }
+}
+
+// Name of the rotated log whose first entry was logged at `started`
+fn rotated_name(started: DateTime<Utc>) -> String {
+    format!("{}{}{}", ROTATED_PREFIX, started.format("%Y%m%dT%H%M%S%.6fZ"), ROTATED_SUFFIX)
+}
+
+// Rotated logs, oldest first
+fn list_rotated(dir: &Path) -> NodeResult<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut rotated: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|path| {
+            let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+            name.starts_with(ROTATED_PREFIX) && name.ends_with(ROTATED_SUFFIX)
+        })
+        .collect();
+    rotated.sort();
+    Ok(rotated)
+}
+
+fn rotated_start(path: &Path) -> Option<DateTime<Utc>> {
+    let name = path.file_name()?.to_str()?;
+    let stamp = name.strip_prefix(ROTATED_PREFIX)?.strip_suffix(ROTATED_SUFFIX)?;
+    chrono::NaiveDateTime::parse_from_str(stamp, "%Y%m%dT%H%M%S%.fZ").ok().map(|naive| Utc.from_utc_datetime(&naive))
+}
+
+fn open_log(path: &Path) -> NodeResult<Box<dyn BufRead>> {
+    let file = File::open(path)
+        .map_err(|e| NodeError::Dag(format!("Failed to open DAG log {:?}: {}", path, e)))?;
+
+    if path.extension().and_then(|ext| ext.to_str()) == Some("gz") {
+        Ok(Box::new(BufReader::new(GzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+fn parse_line(path: &Path, line: &str) -> NodeResult<DagLogEntry> {
+    let plaintext = crypto::open(line.as_bytes())
+        .map_err(|e| NodeError::Crypto(format!("{:?}: {}", path, e)))?;
+    serde_json::from_slice(&plaintext)
+        .map_err(|e| NodeError::Dag(format!("Invalid entry in DAG log {:?}: {}", path, e)))
+}
+
+// Re-encrypt every entry of the current and rotated logs with the active key.
+// Holds the writer so no entry is appended meanwhile.
+pub fn reseal(report: &mut RekeyReport) -> NodeResult<()> {
+    let _writer = WRITER.lock()
+        .map_err(|_| NodeError::Dag("DAG log writer lock poisoned".to_string()))?;
+
+    let dir = get_dag_log_dir()?;
+    let mut files = list_rotated(&dir)?;
+    let current = dir.join(CURRENT_NAME);
+    if current.exists() {
+        files.push(current);
+    }
+
+    for path in files {
+        let mut content = Vec::new();
+        open_log(&path)?.read_to_end(&mut content)?;
+
+        let mut resealed = Vec::with_capacity(content.len());
+        let mut changed = false;
+        for line in content.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            match crypto::reseal(line).map_err(|e| NodeError::Crypto(format!("{:?}: {}", path, e)))? {
+                Some(sealed) => {
+                    resealed.extend_from_slice(&sealed);
+                    changed = true;
+                }
+                None => resealed.extend_from_slice(line),
+            }
+            resealed.push(b'\n');
+        }
+
+        if !changed {
+            report.unchanged += 1;
+            continue;
+        }
+
+        let data = if path.extension().and_then(|ext| ext.to_str()) == Some("gz") {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&resealed)?;
+            encoder.finish()?
+        } else {
+            resealed
+        };
+        state::write_atomic(&path, &data)
+            .map_err(|e| NodeError::Crypto(format!("Failed to rewrite {:?}: {}", path, e)))?;
+        report.resealed += 1;
+    }
+
+    Ok(())
+}
+
+// Streams matching entries from the rotated logs and then the current log,
+// oldest first, one file open at a time
+pub struct DagLogReader {
+    files: VecDeque<PathBuf>,
+    open: Option<(PathBuf, Box<dyn BufRead>)>,
+    filter: DagLogFilter,
+}
+
+impl Iterator for DagLogReader {
+    type Item = NodeResult<DagLogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.open.is_none() {
+                let path = self.files.pop_front()?;
+                match open_log(&path) {
+                    Ok(reader) => self.open = Some((path, reader)),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            let (path, reader) = self.open.as_mut()?;
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => self.open = None,
+                Ok(_) if line.trim().is_empty() => continue,
+                Ok(_) => match parse_line(path, line.trim_end()) {
+                    Ok(entry) if self.filter.matches(&entry) => return Some(Ok(entry)),
+                    Ok(_) => continue,
+                    Err(e) => return Some(Err(e)),
+                },
+                Err(e) => return Some(Err(e.into())),
+            }
+        }
+    }
+}
+
+// Read the DAG log. Rotated logs that end before `filter.since` are skipped
+// without being opened.
+pub fn read(filter: DagLogFilter) -> NodeResult<DagLogReader> {
+    let dir = get_dag_log_dir()?;
+    let rotated = list_rotated(&dir)?;
+
+    let mut files = VecDeque::new();
+    for (position, path) in rotated.iter().enumerate() {
+        // A rotated log ends where the next one starts
+        let next_start = rotated.get(position + 1).and_then(|next| rotated_start(next));
+        if let (Some(since), Some(end)) = (filter.since, next_start) {
+            if end <= since {
+                continue;
+            }
+        }
+        files.push_back(path.clone());
+    }
+
+    let current = dir.join(CURRENT_NAME);
+    if current.exists() {
+        files.push_back(current);
+    }
+
+    Ok(DagLogReader { files, open: None, filter })
+}
+
+// The last `count` matching entries, oldest first
+pub fn tail(filter: DagLogFilter, count: usize) -> NodeResult<Vec<DagLogEntry>> {
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut last = VecDeque::with_capacity(count);
+
+    for entry in read(filter)? {
+        if last.len() == count {
+            last.pop_front();
+        }
+        last.push_back(entry?);
+    }
+    Ok(last.into())
+}
+
+// Send matching entries as they are appended to the current log. Entries
+// already in the log are not sent. Follows the log across rotations, which
+// are recognised by the current log starting with a different entry.
+pub async fn follow(filter: DagLogFilter, tx: mpsc::Sender<DagLogEntry>) -> NodeResult<()> {
+    let dir = get_dag_log_dir()?;
+    let current = dir.join(CURRENT_NAME);
+    let mut started = first_entry_time(&current);
+    let mut position = fs::metadata(&current).map(|metadata| metadata.len()).unwrap_or(0);
+    let mut pending = String::new();
+
+    loop {
+        time::sleep(time::Duration::from_secs(1)).await;
+
+        let now_started = first_entry_time(&current);
+        if let Some(previous) = started.filter(|previous| now_started != Some(*previous)) {
+            // Rotated: finish the log we were reading, then read any log
+            // rotated after it in full
+            let first = rotated_name(previous);
+            for rotated in list_rotated(&dir)? {
+                let name = rotated.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+                if name < first {
+                    continue;
+                }
+
+                let mut content = Vec::new();
+                open_log(&rotated)?.read_to_end(&mut content)?;
+                let skip = if name == first { position as usize } else { 0 };
+                pending.push_str(&String::from_utf8_lossy(content.get(skip..).unwrap_or_default()));
+            }
+            position = 0;
+        }
+        started = now_started;
+
+        let size = fs::metadata(&current).map(|metadata| metadata.len()).unwrap_or(0);
+        let mut lines = Vec::new();
+
+        if size > position {
+            let mut file = File::open(&current)?;
+            file.seek(SeekFrom::Start(position))?;
+            let mut content = Vec::new();
+            file.take(size - position).read_to_end(&mut content)?;
+            position = size;
+            pending.push_str(&String::from_utf8_lossy(&content));
+        }
+
+        // Keep a trailing partial line for the next poll
+        while let Some(end) = pending.find('\n') {
+            let line: String = pending.drain(..=end).collect();
+            if !line.trim().is_empty() {
+                lines.push(parse_line(&current, line.trim_end())?);
+            }
+        }
+
+        for entry in lines.into_iter().filter(|entry| filter.matches(entry)) {
+            if tx.send(entry).await.is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::data_dir;
+    use serial_test::serial;
+
+    fn entry(vertex_id: &str, logged_at: DateTime<Utc>) -> DagLogEntry {
+        DagLogEntry {
+            logged_at,
+            event: DagEvent::VertexAdded,
+            vertex_id: vertex_id.to_string(),
+            proposal_id: "1".to_string(),
+            data_type: "proposal_execution".to_string(),
+            hash: "hash".to_string(),
+            submitter: "node".to_string(),
+            timestamp: logged_at,
+            parents: Vec::new(),
+            detail: None,
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn follow_continues_across_rotations() {
+        let _dir = data_dir();
+
+        append(&entry("before", Utc::now() - Duration::hours(MAX_LOG_AGE_HOURS + 1))).unwrap();
+
+        let (tx, mut rx) = mpsc::channel(10);
+        let follower = tokio::spawn(follow(DagLogFilter::default(), tx));
+        time::sleep(time::Duration::from_millis(1500)).await;
+
+        // The first entry is old enough that this one rotates the log, and the
+        // new current log ends up longer than the old one
+        append(&entry("rotating", Utc::now())).unwrap();
+        append(&entry("after", Utc::now())).unwrap();
+        assert_eq!(list_rotated(&get_dag_log_dir().unwrap()).unwrap().len(), 1);
+
+        let mut followed = Vec::new();
+        while followed.len() < 2 {
+            let entry = time::timeout(time::Duration::from_secs(5), rx.recv()).await.unwrap().unwrap();
+            followed.push(entry.vertex_id);
+        }
+        follower.abort();
+
+        assert_eq!(followed, ["rotating", "after"]);
+    }
+}
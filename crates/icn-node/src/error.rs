@@ -34,6 +34,9 @@ pub enum NodeError {
     
     #[error("Configuration error: {0}")]
     Config(String),
+
+    #[error("Encryption error: {0}")]
+    Crypto(String),
 }
 
 pub type NodeResult<T> = Result<T, NodeError>; 
\ No newline at end of file
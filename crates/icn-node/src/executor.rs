@@ -1,13 +1,17 @@
+use crate::crypto;
+use crate::dag;
 use crate::error::{NodeError, NodeResult};
+use crate::payload::{ProposalExecution, VertexPayload};
 use crate::queue::{self, ProposalStatus};
-use crate::state::{self, VertexEntry};
+use crate::signing;
+use crate::state;
+use crate::vertex::Vertex;
 use chrono::Utc;
-use icn_covm::{execute_program_from_path, ExecutionResult as CoVMExecutionResult, VMOptions};
+use icn_covm::{execute_program_from_path, VMOptions};
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File};
-use std::io::Write;
+use sha2::{Digest, Sha256};
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
@@ -56,7 +60,7 @@ pub async fn execute_proposal_file(file_path: &str, force: bool) -> NodeResult<E
     }
     
     // Execute proposal with CoVM
-    let result = run_covm(path)?;
+    let mut result = run_covm(path, None)?;
     
     // Process execution result
     if result.status_code == 0 {
@@ -73,20 +77,33 @@ pub async fn execute_proposal_file(file_path: &str, force: bool) -> NodeResult<E
             queue::update_proposal_status(path, ProposalStatus::Completed)?;
         }
         
-        // Record execution in state
-        state::add_executed_proposal(&proposal_id)?;
-        
-        // Generate and record DAG vertex
-        let vertex_id = result.vertex_id.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
-        
-        let vertex = VertexEntry {
-            id: vertex_id.clone(),
+        // Generate DAG vertex
+        let content_hash = generate_content_hash(path)?;
+        let execution_hash = result_hash(&result);
+        let payload = VertexPayload::ProposalExecution(ProposalExecution {
             proposal_id: proposal_id.clone(),
-            timestamp: Utc::now(),
-            hash: generate_content_hash(path)?,
-        };
+            content_hash: content_hash.clone(),
+            status_code: result.status_code,
+            result_hash: execution_hash.clone(),
+        });
+        let mut vertex = Vertex::new(proposal_id.clone(), content_hash, execution_hash, payload);
+        let node_key = signing::node_key()?;
         
-        state::add_vertex(vertex)?;
+        // Record execution and vertex in state as one batch. The vertex extends
+        // the tips current at commit time, so no concurrent vertex is skipped.
+        let executed_id = proposal_id.clone();
+        let vertex_id = state::write_async(move |state, tx| {
+            dag::prepare_vertex(state, node_key, &mut vertex)?;
+
+            let vertex_id = vertex.id.clone();
+            tx.add_executed_proposal(&executed_id).add_vertex(vertex);
+            Ok(vertex_id)
+        }).await?;
+        
+        if let Some(covm_vertex_id) = &result.vertex_id {
+            debug!("Recorded vertex {} in place of CoVM vertex id {}", vertex_id, covm_vertex_id);
+        }
+        result.vertex_id = Some(vertex_id);
         
         // Store execution output
         store_execution_output(&proposal_id, &result)?;
@@ -155,7 +172,7 @@ pub async fn trace_proposal(proposal_id: &str) -> NodeResult<()> {
                     .filter(|p| {
                         p.file_name()
                             .and_then(|f| f.to_str())
-                            .map_or(false, |name| name.contains(&format!("execution_{}_", proposal_id)))
+                            .is_some_and(|name| name.contains(&format!("execution_{}_", proposal_id)))
                     })
                     .collect()
             })
@@ -169,7 +186,8 @@ pub async fn trace_proposal(proposal_id: &str) -> NodeResult<()> {
             let latest_output = &output_files[0];
             info!("Latest execution output: {:?}", latest_output);
             
-            let output_content = fs::read_to_string(latest_output)
+            let output_content = crypto::read_file(latest_output)
+                .map(|content| String::from_utf8_lossy(&content).into_owned())
                 .map_err(|e| NodeError::Execution(format!("Failed to read output file: {}", e)))?;
                 
             println!("Execution Output for Proposal {}:", proposal_id);
@@ -181,11 +199,22 @@ pub async fn trace_proposal(proposal_id: &str) -> NodeResult<()> {
             println!("No execution output found for proposal: {}", proposal_id);
         }
         
+        // Show the DAG vertices recorded for this proposal
+        let vertices = state::get_vertices_for_proposal(proposal_id)?;
+        if !vertices.is_empty() {
+            println!("DAG Vertices for Proposal {}:", proposal_id);
+            for vertex in &vertices {
+                println!("  {}  {}  hash {}", vertex.timestamp, vertex.id, vertex.hash);
+            }
+        }
+        
         // Execute proposal with trace mode
-        let mut options = VMOptions::default();
-        options.trace = true;
-        options.explain = true;
-        options.verbose = true;
+        let options = VMOptions {
+            trace: true,
+            explain: true,
+            verbose: true,
+            ..Default::default()
+        };
         
         let result = execute_program_from_path(&path, options)
             .map_err(|e| NodeError::Execution(format!("Failed to trace execution: {}", e)))?;
@@ -232,8 +261,10 @@ fn validate_proposal(path: &Path) -> NodeResult<bool> {
     }
     
     // Use CoVM directly to validate
-    let mut options = VMOptions::default();
-    options.simulate = true; // Don't make changes during validation
+    let options = VMOptions {
+        simulate: true, // Don't make changes during validation
+        ..Default::default()
+    };
     
     match execute_program_from_path(path, options) {
         Ok(_) => {
@@ -247,32 +278,72 @@ fn validate_proposal(path: &Path) -> NodeResult<bool> {
     }
 }
 
-// Execute proposal with CoVM
-fn run_covm(path: &Path) -> NodeResult<ExecutionResult> {
+// Re-execute a proposal against the given CoVM storage directory instead of
+// the node's own storage
+pub fn replay_proposal(path: &Path, storage_dir: &Path) -> NodeResult<ExecutionResult> {
+    run_covm(path, Some(storage_dir))
+}
+
+// Prefix of the encoding hashed into a result hash; a new encoding needs a new prefix
+const RESULT_HASH_DOMAIN: &[u8] = b"icn-result-v1";
+
+// SHA-256 over the deterministic parts of an execution result: its status
+// code and output. Ids and timestamps differ between runs and are left out.
+pub fn result_hash(result: &ExecutionResult) -> String {
+    let mut buf = RESULT_HASH_DOMAIN.to_vec();
+    buf.extend_from_slice(&result.status_code.to_be_bytes());
+    buf.extend_from_slice(&(result.output.len() as u64).to_be_bytes());
+    buf.extend_from_slice(result.output.as_bytes());
+
+    format!("{:x}", Sha256::digest(&buf))
+}
+
+// Execute proposal with CoVM, using the node's storage unless another
+// storage directory is given
+fn run_covm(path: &Path, storage_dir: Option<&Path>) -> NodeResult<ExecutionResult> {
     info!("Running CoVM execution for: {:?}", path);
     
     // Create VM options
-    let mut options = VMOptions::default();
-    options.use_stdlib = true;
-    options.storage_backend = "file".to_string();
+    let mut options = VMOptions {
+        use_stdlib: true,
+        storage_backend: "file".to_string(),
+        ..Default::default()
+    };
     
     // Get data directory for storage path
-    let data_dir = dirs::home_dir()
-        .ok_or_else(|| NodeError::Execution("Could not determine home directory".to_string()))?
-        .join(".icn");
+    let data_dir = state::get_state_dir()?;
+    let storage_dir = storage_dir.map_or_else(|| data_dir.join("storage"), Path::to_path_buf);
+    let identity_file = data_dir.join("identity.json");
+    
+    // CoVM only reads plaintext, so encrypted storage and identity are
+    // decrypted into a private workspace for the duration of the run
+    let workspace = if crypto::is_enabled() {
+        Some(CovmWorkspace::unseal(&data_dir, &storage_dir, &identity_file)?)
+    } else {
+        None
+    };
+    
+    let (storage_path, identity_path) = match &workspace {
+        Some(workspace) => (workspace.storage_dir(), workspace.identity_file()),
+        None => (storage_dir.clone(), identity_file),
+    };
     
-    options.storage_path = data_dir.join("storage").to_string_lossy().to_string();
+    options.storage_path = storage_path.to_string_lossy().to_string();
     
     // Set the identity if it exists
-    let identity_file = data_dir.join("identity.json");
-    if identity_file.exists() {
-        options.identity_path = Some(identity_file.to_string_lossy().to_string());
+    if identity_path.exists() {
+        options.identity_path = Some(identity_path.to_string_lossy().to_string());
     }
     
     // Execute the program
     let covm_result = execute_program_from_path(path, options)
         .map_err(|e| NodeError::Execution(format!("CoVM execution failed: {}", e)))?;
     
+    // Keep the storage changes of a successful run
+    if let Some(workspace) = &workspace {
+        crypto::seal_tree(&workspace.storage_dir(), &storage_dir)?;
+    }
+    
     let filename = path.file_name()
         .ok_or_else(|| NodeError::Execution("Invalid proposal file path".to_string()))?
         .to_string_lossy();
@@ -306,20 +377,59 @@ fn store_execution_output(proposal_id: &str, result: &ExecutionResult) -> NodeRe
     let output_file = output_dir.join(format!("execution_{}_{}.json", proposal_id, timestamp));
     
     // Write output to file
-    let mut file = File::create(&output_file)?;
     let serialized = serde_json::to_string_pretty(result)?;
-    file.write_all(serialized.as_bytes())?;
+    crypto::write_file(&output_file, serialized.as_bytes())?;
     
     Ok(output_file)
 }
 
-// Generate a content hash for a proposal file
+// Plaintext copies of the encrypted CoVM storage and identity, removed when dropped
+struct CovmWorkspace {
+    root: PathBuf,
+}
+
+impl CovmWorkspace {
+    fn unseal(data_dir: &Path, storage_dir: &Path, identity_file: &Path) -> NodeResult<Self> {
+        let root = data_dir.join("tmp").join(format!("covm-{}", Uuid::new_v4()));
+        fs::create_dir_all(&root)?;
+
+        // Only the node user may read the decrypted files
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&root, fs::Permissions::from_mode(0o700))?;
+        }
+
+        let workspace = Self { root };
+
+        fs::create_dir_all(workspace.storage_dir())?;
+        crypto::unseal_tree(storage_dir, &workspace.storage_dir())?;
+        crypto::unseal_tree(identity_file, &workspace.identity_file())?;
+
+        Ok(workspace)
+    }
+
+    fn storage_dir(&self) -> PathBuf {
+        self.root.join("storage")
+    }
+
+    fn identity_file(&self) -> PathBuf {
+        self.root.join("identity.json")
+    }
+}
+
+impl Drop for CovmWorkspace {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.root) {
+            warn!("Failed to remove CoVM workspace {:?}: {}", self.root, e);
+        }
+    }
+}
+
+// Generate the SHA-256 content hash of a proposal file
 fn generate_content_hash(path: &Path) -> NodeResult<String> {
-    let content = fs::read_to_string(path)
+    let content = fs::read(path)
         .map_err(|e| NodeError::Execution(format!("Failed to read proposal file: {}", e)))?;
     
-    // Use a simple hash for now
-    let hash = format!("{:x}", md5::compute(content));
-    
-    Ok(hash)
+    Ok(format!("{:x}", Sha256::digest(&content)))
 } 
\ No newline at end of file
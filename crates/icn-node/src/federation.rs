@@ -1,21 +1,30 @@
 use crate::error::{NodeError, NodeResult};
-use crate::state::{self, VertexEntry};
-use reqwest::Client;
+use crate::state::{self, StateExtension};
+use crate::vertex::Vertex;
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::process::Command;
+use tokio::net::lookup_host;
 use tracing::{debug, info, warn};
 
 // Federation peer structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
+    // Node id of the peer, which its vertices name as submitter
     pub id: String,
     pub name: String,
     pub address: String,
     pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    // Ed25519 key the peer signs its vertices with (base64); vertices from a
+    // peer without one are rejected
+    #[serde(default)]
+    pub public_key: String,
 }
 
 // Federation status
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FederationStatus {
     pub online_peers: Vec<Peer>,
@@ -31,15 +40,40 @@ pub struct FederationConfig {
     pub node_name: String,
     pub peers: Vec<Peer>,
     pub sync_endpoint: String,
+    // Address the node API is served on, which peers list as this node's address
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+}
+
+impl StateExtension for FederationConfig {
+    const NAMESPACE: &'static str = "federation_config";
+}
+
+// All interfaces, on the port peers and icn-cli expect
+fn default_listen_address() -> String {
+    "0.0.0.0:26657".to_string()
+}
+
+impl FederationConfig {
+    pub fn listen_address(&self) -> NodeResult<SocketAddr> {
+        self.listen_address.parse()
+            .map_err(|e| NodeError::Config(format!("Invalid listen address {}: {}", self.listen_address, e)))
+    }
 }
 
 // Broadcast a DAG vertex to federation peers
-pub async fn broadcast_vertex(vertex: &VertexEntry) -> NodeResult<()> {
+pub async fn broadcast_vertex(vertex: &Vertex) -> NodeResult<()> {
     // Get federation config
     let config = get_federation_config()?;
+    let listen = config.listen_address().ok();
     
     // Loop through peers and broadcast
     for peer in &config.peers {
+        if is_own_peer(peer, listen).await {
+            debug!("Not broadcasting vertex {} to peer {}, which is this node", vertex.id, peer.name);
+            continue;
+        }
+
         let client = Client::new();
         let endpoint = format!("{}/dag/vertices", peer.address);
         
@@ -93,6 +127,7 @@ pub async fn broadcast_vertex(vertex: &VertexEntry) -> NodeResult<()> {
 }
 
 // Check federation health
+#[allow(dead_code)]
 pub async fn check_federation_health() -> NodeResult<FederationStatus> {
     let config = get_federation_config()?;
     let now = chrono::Utc::now();
@@ -111,6 +146,20 @@ pub async fn check_federation_health() -> NodeResult<FederationStatus> {
         }
     }
     
+    // Remember when each peer was last reachable
+    if !online_peers.is_empty() {
+        let mut updated = config.clone();
+        for peer in updated.peers.iter_mut() {
+            if online_peers.iter().any(|p| p.id == peer.id) {
+                peer.last_seen = Some(now);
+            }
+        }
+
+        if let Err(e) = state::put_extension(&updated) {
+            warn!("Failed to record peer status: {}", e);
+        }
+    }
+
     let status = FederationStatus {
         online_peers,
         offline_peers,
@@ -145,9 +194,9 @@ async fn check_peer_status(address: &str) -> NodeResult<()> {
 }
 
 // Get federation configuration
-fn get_federation_config() -> NodeResult<FederationConfig> {
+pub fn get_federation_config() -> NodeResult<FederationConfig> {
     // First try to get from state
-    if let Ok(config) = state::get::<FederationConfig>("federation_config") {
+    if let Some(config) = state::get_extension::<FederationConfig>()? {
         return Ok(config);
     }
     
@@ -168,36 +217,97 @@ fn get_federation_config() -> NodeResult<FederationConfig> {
                 .map_err(|e| NodeError::Federation(format!("Failed to parse federation config: {}", e)))?;
                 
             // Save to state for future use
-            let _ = state::set("federation_config", &config);
+            state::put_extension(&config)?;
             
             return Ok(config);
         }
     }
     
-    // Default config with localhost
-    let node_id = uuid::Uuid::new_v4().to_string();
+    // Default config without peers, identified by this node's own id
+    let node_id = state::get::<String>("node_id")?;
     let config = FederationConfig {
         federation_name: "dev-federation".to_string(),
         node_id: node_id.clone(),
         node_name: format!("node-{}", node_id),
-        peers: vec![
-            Peer {
-                id: "local".to_string(),
-                name: "localhost".to_string(),
-                address: "http://localhost:26657".to_string(),
-                last_seen: None,
-            }
-        ],
+        peers: Vec::new(),
         sync_endpoint: "http://localhost:26657/dag/sync".to_string(),
+        listen_address: default_listen_address(),
     };
     
     // Save to state
-    let _ = state::set("federation_config", &config);
+    state::put_extension(&config)?;
     
     Ok(config)
 }
 
+// Whether a connection from `remote` comes from one of the configured peers
+pub async fn is_peer_address(remote: IpAddr) -> NodeResult<bool> {
+    let config = match state::get_extension::<FederationConfig>()? {
+        Some(config) => config,
+        None => return Ok(false),
+    };
+    Ok(matches_peer(&config.peers, config.listen_address().ok(), remote).await)
+}
+
+// Resolve the host of each peer's address and compare it with `remote`. A
+// peer at this node's own listen address is skipped, so listing the node
+// itself does not let every local connection through.
+async fn matches_peer(peers: &[Peer], listen: Option<SocketAddr>, remote: IpAddr) -> bool {
+    let remote = remote.to_canonical();
+    for peer in peers {
+        let addresses = resolve_peer(peer).await;
+        if listen.is_some_and(|listen| addresses.iter().any(|address| is_own_address(*address, listen))) {
+            continue;
+        }
+        if addresses.iter().any(|address| address.ip().to_canonical() == remote) {
+            return true;
+        }
+    }
+
+    false
+}
+
+// Whether a peer's address is this node's own listen address
+async fn is_own_peer(peer: &Peer, listen: Option<SocketAddr>) -> bool {
+    match listen {
+        Some(listen) => resolve_peer(peer).await.into_iter().any(|address| is_own_address(address, listen)),
+        None => false,
+    }
+}
+
+// A node listening on all interfaces is also reached through loopback
+fn is_own_address(address: SocketAddr, listen: SocketAddr) -> bool {
+    let ip = address.ip().to_canonical();
+    address.port() == listen.port()
+        && (ip == listen.ip().to_canonical() || (listen.ip().is_unspecified() && ip.is_loopback()))
+}
+
+// Resolve the host and port of a peer's address
+async fn resolve_peer(peer: &Peer) -> Vec<SocketAddr> {
+    let url = match Url::parse(&peer.address) {
+        Ok(url) => url,
+        Err(e) => {
+            warn!("Invalid address of peer {}: {} ({})", peer.name, peer.address, e);
+            return Vec::new();
+        }
+    };
+    // IPv6 hosts keep their brackets, as a socket address needs them
+    let address = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        _ => return Vec::new(),
+    };
+
+    match lookup_host(address).await {
+        Ok(addresses) => addresses.collect(),
+        Err(e) => {
+            debug!("Failed to resolve peer {} ({}): {}", peer.name, peer.address, e);
+            Vec::new()
+        }
+    }
+}
+
 // Sync with federation
+#[allow(dead_code)]
 pub async fn sync_with_federation() -> NodeResult<()> {
     let script_path = "../scripts/federation-check.sh";
     
@@ -224,4 +334,44 @@ pub async fn sync_with_federation() -> NodeResult<()> {
     }
     
     Ok(())
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(address: &str) -> Peer {
+        Peer {
+            id: "peer-1".to_string(),
+            name: "peer".to_string(),
+            address: address.to_string(),
+            last_seen: None,
+            public_key: String::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn matches_connections_against_peer_addresses() {
+        let peers = [peer("not a url"), peer("http://127.0.0.2:26657"), peer("http://[::1]:26657")];
+
+        assert!(matches_peer(&peers, None, "127.0.0.2".parse().unwrap()).await);
+        assert!(matches_peer(&peers, None, "::ffff:127.0.0.2".parse().unwrap()).await);
+        assert!(matches_peer(&peers, None, "::1".parse().unwrap()).await);
+        assert!(!matches_peer(&peers, None, "127.0.0.1".parse().unwrap()).await);
+        assert!(!matches_peer(&[], None, "127.0.0.2".parse().unwrap()).await);
+    }
+
+    #[tokio::test]
+    async fn skips_the_node_itself_among_its_peers() {
+        let peers = [peer("http://localhost:26657"), peer("http://127.0.0.1:26667")];
+        let listen = "0.0.0.0:26657".parse().unwrap();
+
+        assert!(is_own_peer(&peers[0], Some(listen)).await);
+        assert!(!is_own_peer(&peers[1], Some(listen)).await);
+        assert!(!is_own_peer(&peers[0], Some("127.0.0.1:26667".parse().unwrap())).await);
+
+        // Only the peer on another port lets loopback connections through
+        assert!(!matches_peer(&peers[..1], Some(listen), "127.0.0.1".parse().unwrap()).await);
+        assert!(matches_peer(&peers, Some(listen), "127.0.0.1".parse().unwrap()).await);
+    }
+}
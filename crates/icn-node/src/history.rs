@@ -0,0 +1,379 @@
+use crate::checkpoint::{self, Checkpoint, Checkpoints};
+use crate::crypto;
+use crate::dag;
+use crate::error::{NodeError, NodeResult};
+use crate::federation::FederationConfig;
+use crate::payload::VertexPayload;
+use crate::replay::{self, ReplayReport, ReplayStorage};
+use crate::state::{self, NodeState, StateExtension};
+use crate::vertex::Vertex;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+// The point in history to reconstruct
+#[derive(Debug, Clone)]
+pub enum HistoryTarget {
+    // Right after this vertex was recorded
+    Vertex(String),
+    // Before the first vertex timestamped after this time was recorded
+    Time(DateTime<Utc>),
+}
+
+impl HistoryTarget {
+    // A timestamp (RFC 3339 or YYYY-MM-DD), otherwise a vertex id
+    pub fn parse(value: &str) -> Self {
+        match dag::parse_timestamp(value) {
+            Ok(time) => HistoryTarget::Time(time),
+            Err(_) => HistoryTarget::Vertex(value.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HistoryOptions {
+    // Extra directories to look for proposal files in
+    pub proposal_dirs: Vec<PathBuf>,
+    // Keep the reconstruction here instead of discarding it
+    pub output: Option<PathBuf>,
+}
+
+// The node state and CoVM storage as they were at a point in history
+#[derive(Debug, Serialize)]
+pub struct HistoricalState {
+    // Vertices recorded up to the target, and the last of them
+    pub vertex_count: usize,
+    pub last_vertex: Option<String>,
+    pub as_of: Option<DateTime<Utc>>,
+    // Checkpoint the storage was restored from before replaying
+    pub checkpoint: Option<u64>,
+    pub replayed: usize,
+    // Vertices whose proposal file was not found; the storage lacks their effects
+    pub missing: Vec<String>,
+    pub storage_hash: String,
+    pub storage_dir: Option<PathBuf>,
+    pub state_root: String,
+    pub tips: Vec<String>,
+    pub height: u64,
+    pub last_proposal_id: u64,
+    pub executed_proposals: usize,
+}
+
+// Every vertex the node has recorded, pruned ones included, in recording order
+fn full_history(current: &NodeState) -> NodeResult<Vec<Vertex>> {
+    if current.pruned_history.is_none() {
+        return Ok(current.dag_vertices.clone());
+    }
+
+    let mut vertices = checkpoint::archived_vertices()?;
+    vertices.extend(current.dag_vertices.iter().cloned());
+
+    if vertices.len() != current.vertex_count() {
+        return Err(NodeError::Dag(format!(
+            "Cold storage holds {} of the {} pruned vertices",
+            vertices.len() - current.dag_vertices.len(),
+            current.vertex_count() - current.dag_vertices.len()
+        )));
+    }
+
+    Ok(vertices)
+}
+
+// Number of vertices recorded up to the target
+fn target_count(vertices: &[Vertex], target: &HistoryTarget) -> NodeResult<usize> {
+    match target {
+        HistoryTarget::Vertex(id) => vertices.iter()
+            .position(|vertex| &vertex.id == id)
+            .map(|position| position + 1)
+            .ok_or_else(|| NodeError::Dag(format!("Vertex not found: {}", id))),
+        HistoryTarget::Time(time) => Ok(vertices.iter()
+            .position(|vertex| vertex.timestamp > *time)
+            .unwrap_or(vertices.len())),
+    }
+}
+
+// Extension holding governance parameters as of a point in history, by
+// scope and key
+const PARAMETERS_NAMESPACE: &str = "parameters";
+
+// Values of keyed settings as of the first `count` vertices, by scope and
+// key: the last value set up to then or, for a setting first changed later,
+// the value that change replaced (if it recorded one)
+fn settings_as_of<F>(vertices: &[Vertex], count: usize, setting: F) -> BTreeMap<(String, String), Option<Value>>
+where
+    F: Fn(&VertexPayload) -> Option<(&str, &str, Option<&Value>, &Value)>,
+{
+    let mut values = BTreeMap::new();
+    for (position, vertex) in vertices.iter().enumerate() {
+        if let Some((scope, key, previous, value)) = vertex.payload.as_ref().and_then(&setting) {
+            let entry = (scope.to_string(), key.to_string());
+            if position < count {
+                values.insert(entry, Some(value.clone()));
+            } else {
+                values.entry(entry).or_insert_with(|| previous.cloned());
+            }
+        }
+    }
+    values
+}
+
+// The current state as it was after the first `count` of `vertices`: the DAG
+// is cut back, executed proposals are those executed by any node up to then,
+// and the parameter and federation config changes recorded up to then are
+// folded into the extensions. Checkpoints taken later are dropped. Fields the
+// DAG does not record, such as peers, keep their current values.
+fn state_as_of(current: &NodeState, vertices: &[Vertex], count: usize) -> NodeState {
+    let recorded = &vertices[..count];
+
+    let mut past = current.clone();
+    past.dag_vertices = recorded.to_vec();
+    past.pruned_history = None;
+
+    let mut executed = HashSet::new();
+    past.executed_proposals = recorded.iter()
+        .filter(|vertex| vertex.is_execution())
+        .map(|vertex| vertex.proposal_id.clone())
+        .filter(|id| executed.insert(id.clone()))
+        .collect();
+    past.last_proposal_id = past.executed_proposals.iter()
+        .filter_map(|id| id.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0);
+    past.last_updated = recorded.last().map_or(current.initialized, |vertex| vertex.timestamp);
+
+    let parameters = settings_as_of(vertices, count, |payload| match payload {
+        VertexPayload::ParameterChange(change) => {
+            Some((change.scope.as_str(), change.key.as_str(), change.previous.as_ref(), &change.value))
+        }
+        _ => None,
+    });
+    let mut scopes = serde_json::Map::new();
+    for ((scope, key), value) in parameters {
+        if let Some(value) = value {
+            if let Value::Object(scope) = scopes.entry(scope).or_insert_with(|| Value::Object(Default::default())) {
+                scope.insert(key, value);
+            }
+        }
+    }
+    past.extensions.remove(PARAMETERS_NAMESPACE);
+    if !scopes.is_empty() {
+        past.extensions.insert(PARAMETERS_NAMESPACE.to_string(), Value::Object(scopes));
+    }
+
+    let federation = settings_as_of(vertices, count, |payload| match payload {
+        VertexPayload::FederationConfigChange(change) => {
+            Some((change.federation.as_str(), change.key.as_str(), change.previous.as_ref(), &change.value))
+        }
+        _ => None,
+    });
+    if let Some(Value::Object(config)) = past.extensions.get_mut(FederationConfig::NAMESPACE) {
+        let name = config.get("federation_name").and_then(Value::as_str).unwrap_or_default().to_string();
+        for ((_, key), value) in federation.into_iter().filter(|((scope, _), _)| *scope == name) {
+            if let Some(value) = value {
+                config.insert(key, value);
+            }
+        }
+    }
+
+    if let Some(checkpoints) = past.extensions.get_mut(Checkpoints::NAMESPACE) {
+        if let Ok(mut kept) = serde_json::from_value::<Checkpoints>(checkpoints.clone()) {
+            kept.checkpoints.retain(|checkpoint| checkpoint.vertex_count <= count);
+            *checkpoints = serde_json::to_value(kept).unwrap_or_default();
+        }
+    }
+
+    past.reindex();
+    past
+}
+
+// Newest checkpoint taken at or before `count` vertices whose storage is still kept
+fn starting_checkpoint(count: usize) -> NodeResult<Option<Checkpoint>> {
+    Ok(checkpoint::list_checkpoints()?
+        .into_iter()
+        .rev()
+        .find(|checkpoint| checkpoint.vertex_count <= count))
+}
+
+// Reconstruct the node state and CoVM storage as of a vertex or time: restore
+// the newest checkpoint before it and replay the executions recorded since.
+// The live data directory is only read.
+pub fn state_at(target: &HistoryTarget, options: &HistoryOptions) -> NodeResult<HistoricalState> {
+    let current = state::snapshot()?;
+    let vertices = full_history(&current)?;
+    let count = target_count(&vertices, target)?;
+    let past = state_as_of(&current, &vertices, count);
+
+    let output_storage = match &options.output {
+        Some(output) => {
+            if output.exists() && fs::read_dir(output)?.next().is_some() {
+                return Err(NodeError::State(format!("Output directory {:?} is not empty", output)));
+            }
+            let storage_dir = output.join("storage");
+            fs::create_dir_all(&storage_dir)?;
+            Some(storage_dir)
+        }
+        None => None,
+    };
+    let scratch = match output_storage {
+        Some(_) => None,
+        None => Some(ReplayStorage::create("history", false)?),
+    };
+    let storage_dir = output_storage.clone()
+        .or_else(|| scratch.as_ref().map(|scratch| scratch.path.clone()))
+        .unwrap_or_default();
+
+    let checkpoint = starting_checkpoint(count)?;
+    let start = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.vertex_count);
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint::restore_storage(checkpoint, &storage_dir)?;
+    }
+
+    // Conflicts are resolved among the vertices known at the time
+    let superseded: HashSet<String> = past.dag().conflicts().into_iter()
+        .flat_map(|conflict| conflict.superseded)
+        .collect();
+    let executions: Vec<Vertex> = vertices[start..count].iter()
+        .filter(|vertex| vertex.is_execution() && !superseded.contains(&vertex.id))
+        .cloned()
+        .collect();
+    let order = dag::topological_order(&executions)?;
+
+    info!(
+        "Reconstructing state at {} vertices from {} by replaying {} executions",
+        count,
+        checkpoint.as_ref().map_or("empty storage".to_string(), |checkpoint| format!("checkpoint {}", checkpoint.id)),
+        order.len()
+    );
+
+    let proposals = replay::proposal_index(&options.proposal_dirs)?;
+    let mut report = ReplayReport::default();
+    replay::run(&order, &proposals, &storage_dir, &mut report)?;
+
+    if let Some(divergence) = report.divergence {
+        return Err(NodeError::Execution(format!(
+            "Replay diverged at vertex {} (proposal {}): recorded result {}, recomputed {}",
+            divergence.vertex_id, divergence.proposal_id, divergence.recorded, divergence.recomputed
+        )));
+    }
+    if !report.missing.is_empty() {
+        warn!("{} executions could not be replayed; their proposal files were not found", report.missing.len());
+    }
+
+    if let Some(output) = &options.output {
+        let content = serde_json::to_vec_pretty(&past)?;
+        state::write_atomic(&output.join("node_state.json"), &crypto::seal(&content)?)?;
+    }
+
+    let graph = past.dag();
+    Ok(HistoricalState {
+        vertex_count: count,
+        last_vertex: past.dag_vertices.last().map(|vertex| vertex.id.clone()),
+        as_of: past.dag_vertices.last().map(|vertex| vertex.timestamp),
+        checkpoint: checkpoint.map(|checkpoint| checkpoint.id),
+        replayed: report.verified + report.unverified,
+        missing: report.missing,
+        storage_hash: checkpoint::hash_storage(&storage_dir)?,
+        storage_dir: output_storage,
+        state_root: graph.merkle().root(),
+        tips: graph.tips().cloned().collect(),
+        height: graph.height(),
+        last_proposal_id: past.last_proposal_id,
+        executed_proposals: past.executed_proposals.len(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payload::{FederationConfigChange, ParameterChange, ProposalExecution};
+    use serde_json::json;
+
+    fn recorded(id: &str, proposal_id: &str, payload: VertexPayload) -> Vertex {
+        let mut vertex = Vertex::new(proposal_id.to_string(), "hash".to_string(), String::new(), payload);
+        vertex.id = id.to_string();
+        vertex.submitter = "peer-1".to_string();
+        vertex
+    }
+
+    fn execution(id: &str, proposal_id: &str) -> Vertex {
+        recorded(id, proposal_id, VertexPayload::ProposalExecution(ProposalExecution {
+            proposal_id: proposal_id.to_string(),
+            content_hash: "hash".to_string(),
+            status_code: 0,
+            result_hash: format!("result-{}", id),
+        }))
+    }
+
+    fn parameter(id: &str, previous: Option<Value>, value: Value) -> Vertex {
+        recorded(id, "", VertexPayload::ParameterChange(ParameterChange {
+            scope: "coop".to_string(),
+            key: "quorum".to_string(),
+            previous,
+            value,
+        }))
+    }
+
+    fn federation_change(id: &str, previous: &str, value: &str) -> Vertex {
+        recorded(id, "", VertexPayload::FederationConfigChange(FederationConfigChange {
+            federation: "test".to_string(),
+            key: "node_name".to_string(),
+            previous: Some(json!(previous)),
+            value: json!(value),
+        }))
+    }
+
+    fn current(vertices: &[Vertex]) -> NodeState {
+        let mut state = NodeState::default();
+        state.dag_vertices = vertices.to_vec();
+        state.executed_proposals = vec!["2".to_string()];
+        state.extensions.insert(FederationConfig::NAMESPACE.to_string(), json!({
+            "federation_name": "test",
+            "node_id": state.node_id,
+            "node_name": "renamed",
+            "peers": [],
+            "sync_endpoint": "",
+        }));
+        state
+    }
+
+    #[test]
+    fn folds_payloads_recorded_up_to_the_target() {
+        let vertices = [
+            execution("a", "1"),
+            parameter("b", None, json!(3)),
+            parameter("c", Some(json!(3)), json!(4)),
+            execution("d", "2"),
+            parameter("e", Some(json!(4)), json!(5)),
+        ];
+        let past = state_as_of(&current(&vertices), &vertices, 4);
+
+        // Executions by peers count, not only this node's
+        assert_eq!(past.executed_proposals, ["1", "2"]);
+        assert_eq!(past.last_proposal_id, 2);
+        assert_eq!(past.extensions[PARAMETERS_NAMESPACE], json!({ "coop": { "quorum": 4 } }));
+
+        let past = state_as_of(&current(&vertices), &vertices, 1);
+        assert_eq!(past.executed_proposals, ["1"]);
+        assert!(!past.extensions.contains_key(PARAMETERS_NAMESPACE));
+    }
+
+    #[test]
+    fn rewinds_federation_config_changes_made_later() {
+        let vertices = [
+            execution("a", "1"),
+            federation_change("b", "original", "interim"),
+            federation_change("c", "interim", "renamed"),
+        ];
+        let current = current(&vertices);
+
+        let name = |count| state_as_of(&current, &vertices, count)
+            .extension::<FederationConfig>().unwrap().unwrap().node_name;
+        assert_eq!(name(1), "original");
+        assert_eq!(name(2), "interim");
+        assert_eq!(name(3), "renamed");
+    }
+}
@@ -0,0 +1,90 @@
+use crate::dag::{DagGraph, PrunedHistory, PrunedSegment};
+use crate::vertex::Vertex;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::RangeBounds;
+
+// Lookup tables over `NodeState::dag_vertices` and `executed_proposals`.
+// Vertices are referenced by their position in `dag_vertices`, which only
+// ever grows between full rebuilds.
+#[derive(Debug, Clone, Default)]
+pub struct StateIndex {
+    by_id: HashMap<String, usize>,
+    by_proposal: HashMap<String, Vec<usize>>,
+    by_hash: HashMap<String, Vec<usize>>,
+    by_submitter: HashMap<String, Vec<usize>>,
+    by_time: BTreeMap<DateTime<Utc>, Vec<usize>>,
+    executed: HashSet<String>,
+    dag: DagGraph,
+}
+
+impl StateIndex {
+    pub fn build(
+        vertices: &[Vertex],
+        executed: &[String],
+        pruned: Option<&PrunedHistory>,
+        segments: &[PrunedSegment],
+    ) -> Self {
+        let mut index = Self {
+            executed: executed.iter().cloned().collect(),
+            ..Self::default()
+        };
+
+        if let Some(pruned) = pruned {
+            index.dag.seed(pruned, segments);
+        }
+
+        for (position, vertex) in vertices.iter().enumerate() {
+            index.insert_vertex(position, vertex);
+        }
+
+        index
+    }
+
+    // Index the vertex stored at `position`
+    pub fn insert_vertex(&mut self, position: usize, vertex: &Vertex) {
+        // A repeated id resolves to its first vertex, like a linear scan would
+        self.by_id.entry(vertex.id.clone()).or_insert(position);
+        self.by_proposal.entry(vertex.proposal_id.clone()).or_default().push(position);
+        self.by_hash.entry(vertex.hash.clone()).or_default().push(position);
+        self.by_submitter.entry(vertex.submitter.clone()).or_default().push(position);
+        self.by_time.entry(vertex.timestamp).or_default().push(position);
+        self.dag.insert(vertex);
+    }
+
+    pub fn insert_executed(&mut self, proposal_id: &str) {
+        self.executed.insert(proposal_id.to_string());
+    }
+
+    pub fn vertex(&self, id: &str) -> Option<usize> {
+        self.by_id.get(id).copied()
+    }
+
+    pub fn vertices_for_proposal(&self, proposal_id: &str) -> &[usize] {
+        self.by_proposal.get(proposal_id).map_or(&[], |positions| positions.as_slice())
+    }
+
+    pub fn vertices_with_hash(&self, hash: &str) -> &[usize] {
+        self.by_hash.get(hash).map_or(&[], |positions| positions.as_slice())
+    }
+
+    pub fn vertices_by_submitter(&self, submitter: &str) -> &[usize] {
+        self.by_submitter.get(submitter).map_or(&[], |positions| positions.as_slice())
+    }
+
+    // Positions of vertices with a timestamp in `range`, oldest first
+    pub fn vertices_between<R>(&self, range: R) -> impl Iterator<Item = usize> + '_
+    where
+        R: RangeBounds<DateTime<Utc>>,
+    {
+        self.by_time.range(range).flat_map(|(_, positions)| positions.iter().copied())
+    }
+
+    pub fn is_executed(&self, proposal_id: &str) -> bool {
+        self.executed.contains(proposal_id)
+    }
+
+    pub fn dag(&self) -> &DagGraph {
+        &self.dag
+    }
+}
@@ -6,9 +6,24 @@ use tracing_subscriber::FmtSubscriber;
 mod executor;
 mod queue;
 mod dag;
+mod dag_export;
+mod dag_log;
+mod payload;
+mod vertex;
 mod federation;
 mod state;
+mod index;
+mod signing;
+mod store;
+mod crypto;
+mod archive;
+mod checkpoint;
+mod replay;
+mod history;
+mod api;
 mod error;
+#[cfg(test)]
+mod test_support;
 
 #[derive(Parser)]
 #[command(author, version, about = "Cooperative Node Runner for the Intercooperative Network")]
@@ -19,6 +34,18 @@ struct Cli {
     /// Set the log level
     #[arg(short, long, global = true, default_value = "info")]
     log_level: Level,
+
+    /// Data directory for state, queue, logs and CoVM storage (default: ~/.icn)
+    #[arg(long, global = true, env = "ICN_DATA_DIR")]
+    data_dir: Option<String>,
+
+    /// Storage backend for node state
+    #[arg(long, global = true, value_enum, env = "ICN_STATE_BACKEND", default_value_t = store::StoreBackend::Json)]
+    state_backend: store::StoreBackend,
+
+    /// Key file for encrypted node data (alternatively set ICN_STATE_PASSPHRASE)
+    #[arg(long, global = true, env = "ICN_KEY_FILE")]
+    key_file: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -28,6 +55,18 @@ enum Commands {
         /// Check interval in seconds
         #[arg(long, default_value = "30")]
         interval: u64,
+
+        /// Create a DAG checkpoint whenever this many vertices were recorded since the last one
+        #[arg(long)]
+        checkpoint_every: Option<usize>,
+
+        /// Pruned mode: move history before the previous checkpoint to cold storage
+        #[arg(long, default_value = "false", requires = "checkpoint_every")]
+        pruned: bool,
+
+        /// Run without the node API
+        #[arg(long, default_value = "false")]
+        no_api: bool,
     },
     
     /// Execute a specific proposal
@@ -50,6 +89,290 @@ enum Commands {
     
     /// Watch the DAG and proposal queue
     Watch,
+
+    /// Re-execute the DAG's proposals in order and check their results
+    Replay {
+        /// Additional directory to search for proposal files (repeatable)
+        #[arg(long = "proposals")]
+        proposals: Vec<String>,
+
+        /// Keep the replayed CoVM storage instead of removing it
+        #[arg(long, default_value = "false")]
+        keep_storage: bool,
+
+        /// Start from a checkpoint's storage and replay only later vertices
+        #[arg(long)]
+        from_checkpoint: Option<u64>,
+    },
+
+    /// Write the node's state and data files to a portable archive
+    Export {
+        /// Path of the archive to create (.tar.gz)
+        #[arg(long)]
+        output: String,
+    },
+
+    /// Verify an archive and restore it into a fresh data directory
+    Import {
+        /// Path of the archive to import
+        #[arg(long)]
+        archive: String,
+    },
+
+    /// Inspect and administer node state
+    State {
+        #[command(subcommand)]
+        command: StateCommands,
+    },
+
+    /// Inspect the DAG
+    Dag {
+        #[command(subcommand)]
+        command: DagCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum DagCommands {
+    /// Print vertex, root and tip counts, height and the state root
+    Info,
+
+    /// Print a vertex with its parents, children and height
+    Vertex {
+        /// Vertex id
+        id: String,
+    },
+
+    /// Record a vote, identity or asset registration, or parameter or federation config change
+    Record {
+        /// JSON file holding the payload, with a `type` field naming its kind
+        #[arg(long)]
+        payload: String,
+    },
+
+    /// List vertices by time range, proposal, submitter or content hash, oldest first
+    Vertices {
+        /// Only vertices recorded at or after this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Only vertices recorded before this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Only vertices of this proposal
+        #[arg(long)]
+        proposal: Option<String>,
+
+        /// Only vertices recorded by this node
+        #[arg(long)]
+        submitter: Option<String>,
+
+        /// Only vertices with this content hash
+        #[arg(long)]
+        hash: Option<String>,
+
+        /// Number of matching vertices to skip
+        #[arg(long, default_value = "0")]
+        offset: usize,
+
+        /// Maximum number of vertices to print
+        #[arg(long, default_value = "100")]
+        limit: usize,
+    },
+
+    /// List the ancestors of a vertex, nearest first
+    Ancestors {
+        /// Vertex id
+        id: String,
+
+        /// Maximum number of generations to go back
+        #[arg(long)]
+        depth: Option<usize>,
+    },
+
+    /// List the descendants of a vertex, nearest first
+    Descendants {
+        /// Vertex id
+        id: String,
+
+        /// Maximum number of generations to go down
+        #[arg(long)]
+        depth: Option<usize>,
+    },
+
+    /// Print a shortest path from a vertex down to one of its descendants
+    Path {
+        /// Ancestor vertex id
+        from: String,
+
+        /// Descendant vertex id
+        to: String,
+    },
+
+    /// Print DAG log entries as JSON Lines, oldest first
+    Logs {
+        /// Only entries logged at or after this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Only entries logged before this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Only entries for this proposal
+        #[arg(long)]
+        proposal: Option<String>,
+
+        /// Only entries for this vertex
+        #[arg(long)]
+        vertex: Option<String>,
+
+        /// Only the last N matching entries
+        #[arg(long)]
+        tail: Option<usize>,
+
+        /// Keep printing new entries as they are logged
+        #[arg(long, default_value = "false")]
+        follow: bool,
+    },
+
+    /// List forks and equivocations with the winning and superseded vertices
+    Conflicts,
+
+    /// Print the Merkle subtree hashes at a level, or the vertices in a bucket
+    Hashes {
+        /// Tree level, from 0 (the root) to 8 (the 256 buckets)
+        #[arg(long, default_value = "0", conflicts_with = "bucket")]
+        level: u32,
+
+        /// Bucket number (0-255) whose vertex ids to list
+        #[arg(long)]
+        bucket: Option<usize>,
+    },
+
+    /// Export vertices as Graphviz DOT, JSON Lines or an importable archive
+    Export {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = dag_export::ExportFormat::Dot)]
+        format: dag_export::ExportFormat,
+
+        /// File to write (default: stdout; required for archives)
+        #[arg(long)]
+        output: Option<String>,
+
+        /// Only vertices recorded at or after this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Only vertices recorded before this time (RFC 3339 or YYYY-MM-DD)
+        #[arg(long)]
+        until: Option<String>,
+
+        /// Only vertices of this proposal
+        #[arg(long)]
+        proposal: Option<String>,
+    },
+
+    /// Import the vertices of a DAG archive written by `dag export --format archive`
+    Import {
+        /// Path of the DAG archive
+        #[arg(long)]
+        archive: String,
+    },
+
+    /// Record a checkpoint of the DAG and the CoVM storage
+    Checkpoint,
+
+    /// List checkpoints
+    Checkpoints,
+
+    /// Move vertices covered by a checkpoint to cold storage
+    Prune {
+        /// Checkpoint to prune up to (default: the newest)
+        #[arg(long)]
+        checkpoint: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum StateCommands {
+    /// Print the value stored under a state key
+    Get {
+        /// State key (e.g. last_proposal_id)
+        key: String,
+    },
+
+    /// Set a state key (the value is parsed as JSON, falling back to a string)
+    Set {
+        /// State key
+        key: String,
+
+        /// New value
+        value: String,
+    },
+
+    /// Print the full node state
+    List,
+
+    /// Reconstruct the node state and CoVM storage as of a vertex or time
+    At {
+        /// Vertex id, or a time (RFC 3339 or YYYY-MM-DD)
+        target: String,
+
+        /// Directory to write the reconstructed state and storage to (must be empty)
+        #[arg(long)]
+        output: Option<String>,
+
+        /// Extra directories holding proposal files to replay
+        #[arg(long)]
+        proposals: Vec<String>,
+    },
+
+    /// Write a backup of the current state
+    Backup,
+
+    /// List available state backups
+    Backups,
+
+    /// Restore state from a backup, showing what would change first
+    Restore {
+        /// Backup file name (in the backup directory) or path
+        backup: String,
+
+        /// Apply the restore instead of only previewing it
+        #[arg(long, default_value = "false")]
+        yes: bool,
+    },
+
+    /// Remove old backups according to a retention policy
+    CleanBackups {
+        /// Keep the N most recent backups
+        #[arg(long)]
+        keep: Option<usize>,
+
+        /// Keep backups newer than D days
+        #[arg(long)]
+        keep_days: Option<i64>,
+    },
+
+    /// Record a proposal as executed
+    RecordExecution {
+        /// Proposal ID
+        #[arg(long)]
+        proposal: String,
+    },
+
+    /// Re-encrypt all node data with a new key (alternatively set ICN_NEW_STATE_PASSPHRASE)
+    Rekey {
+        /// Key file holding the new key
+        #[arg(long, conflicts_with = "decrypt")]
+        new_key_file: Option<String>,
+
+        /// Remove encryption instead of changing the key
+        #[arg(long, default_value = "false")]
+        decrypt: bool,
+    },
 }
 
 #[tokio::main]
@@ -63,13 +386,57 @@ async fn main() -> Result<()> {
     tracing::subscriber::set_global_default(subscriber)
         .expect("Failed to set tracing subscriber");
         
+    // Resolve the data directory before anything touches the filesystem
+    if let Some(data_dir) = &cli.data_dir {
+        state::set_data_dir(data_dir)?;
+    }
+    info!("Using data directory: {:?}", state::get_state_dir()?);
+    state::set_backend(cli.state_backend)?;
+
+    // Load the encryption key before any node data is read
+    let key_source = crypto::KeySource::resolve(cli.key_file.as_deref(), "ICN_STATE_PASSPHRASE");
+
+    // An import brings its own key config and state, so it runs before either is loaded
+    if let Commands::Import { archive } = &cli.command {
+        let archive = shellexpand::tilde(archive).to_string();
+        let manifest = archive::import(std::path::Path::new(&archive), key_source)?;
+        println!(
+            "Imported node {} ({} files) into {}",
+            manifest.node_id, manifest.files.len(), state::get_state_dir()?.display()
+        );
+        return Ok(());
+    }
+
+    match &cli.command {
+        Commands::State { command: StateCommands::Rekey { new_key_file, decrypt } } => {
+            let new_source = if *decrypt {
+                None
+            } else {
+                let source = crypto::KeySource::resolve(new_key_file.as_deref(), "ICN_NEW_STATE_PASSPHRASE");
+                if source.is_none() {
+                    return Err(anyhow::anyhow!(
+                        "Provide the new key with --new-key-file or ICN_NEW_STATE_PASSPHRASE, or pass --decrypt"
+                    ));
+                }
+                source
+            };
+            crypto::begin_rekey(key_source, new_source)?;
+        },
+        _ => crypto::init(key_source)?,
+    }
+
     // Initialize state
     state::init()?;
     
     match cli.command {
-        Commands::Run { interval } => {
+        Commands::Run { interval, checkpoint_every, pruned, no_api } => {
             info!("Starting cooperative node runner with {}s check interval", interval);
-            run_daemon(interval).await
+            // Resolved even without the API, so a new node's config is in place to edit
+            let config = federation::get_federation_config()?;
+            if !no_api {
+                api::spawn(config.listen_address()?)?;
+            }
+            run_daemon(interval, checkpoint_every, pruned).await
         },
         Commands::Execute { file, force } => {
             info!("Executing proposal from file: {}", file);
@@ -92,10 +459,315 @@ async fn main() -> Result<()> {
             info!("Watching DAG and proposal queue");
             watch_dag_and_queue().await
         },
+        Commands::Replay { proposals, keep_storage, from_checkpoint } => {
+            let options = replay::ReplayOptions {
+                proposal_dirs: proposals.iter()
+                    .map(|dir| std::path::PathBuf::from(shellexpand::tilde(dir).to_string()))
+                    .collect(),
+                keep_storage,
+                from_checkpoint,
+            };
+
+            let report = replay::replay(&options)?;
+            if let Some(checkpoint) = report.checkpoint {
+                println!("Started from checkpoint {}", checkpoint);
+            }
+            println!(
+                "Replayed {} of {} vertices: {} verified, {} without a recorded result, {} without a proposal file",
+                report.verified + report.unverified + usize::from(report.divergence.is_some()),
+                report.total, report.verified, report.unverified, report.missing.len()
+            );
+            for vertex_id in &report.missing {
+                println!("  missing proposal for vertex {}", vertex_id);
+            }
+            if let Some(dir) = &report.storage_dir {
+                println!("Replayed storage kept in {}", dir.display());
+            }
+
+            match report.divergence {
+                Some(divergence) => Err(anyhow::anyhow!(
+                    "Vertex {} (proposal {}) diverges: recorded result {}, replayed result {}",
+                    divergence.vertex_id, divergence.proposal_id, divergence.recorded, divergence.recomputed
+                )),
+                None => Ok(()),
+            }
+        },
+        Commands::Export { output } => {
+            let output = shellexpand::tilde(&output).to_string();
+            let manifest = archive::export(std::path::Path::new(&output))?;
+            println!("Exported node {} ({} files) to {}", manifest.node_id, manifest.files.len(), output);
+            Ok(())
+        },
+        Commands::Import { .. } => unreachable!("imports are handled before state is loaded"),
+        Commands::State { command } => run_state_command(command),
+        Commands::Dag { command } => run_dag_command(command).await,
     }
 }
 
-async fn run_daemon(interval: u64) -> Result<()> {
+async fn run_dag_command(command: DagCommands) -> Result<()> {
+    match command {
+        DagCommands::Info => {
+            let info = dag::get_dag_info().await?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        },
+        DagCommands::Vertex { id } => {
+            let details = dag::get_vertex_details(&id)?;
+            println!("{}", serde_json::to_string_pretty(&details)?);
+        },
+        DagCommands::Record { payload } => {
+            let path = shellexpand::tilde(&payload).to_string();
+            let content = std::fs::read_to_string(&path)?;
+            let payload: payload::VertexPayload = serde_json::from_str(&content)?;
+
+            let vertex = dag::record_payload(payload).await?;
+            println!("Recorded {} vertex {}", vertex.data_type(), vertex.id);
+        },
+        DagCommands::Vertices { since, until, proposal, submitter, hash, offset, limit } => {
+            let query = dag::VertexQuery {
+                since: since.as_deref().map(dag::parse_timestamp).transpose()?,
+                until: until.as_deref().map(dag::parse_timestamp).transpose()?,
+                proposal_id: proposal,
+                submitter,
+                hash,
+            };
+
+            let page = dag::query_vertices(&query, offset, limit)?;
+            println!("{}", serde_json::to_string_pretty(&page)?);
+        },
+        DagCommands::Ancestors { id, depth } => {
+            for related in dag::get_ancestors(&id, depth)? {
+                println!("{:>4}  {}", related.depth, related.id);
+            }
+        },
+        DagCommands::Descendants { id, depth } => {
+            for related in dag::get_descendants(&id, depth)? {
+                println!("{:>4}  {}", related.depth, related.id);
+            }
+        },
+        DagCommands::Path { from, to } => {
+            match dag::find_path(&from, &to)? {
+                Some(path) => {
+                    for id in path {
+                        println!("{}", id);
+                    }
+                },
+                None => return Err(anyhow::anyhow!("{} does not descend from {}", to, from)),
+            }
+        },
+        DagCommands::Logs { since, until, proposal, vertex, tail, follow } => {
+            let filter = dag_log::DagLogFilter {
+                since: since.as_deref().map(dag::parse_timestamp).transpose()?,
+                until: until.as_deref().map(dag::parse_timestamp).transpose()?,
+                proposal_id: proposal,
+                vertex_id: vertex,
+            };
+
+            match tail {
+                Some(count) => {
+                    for entry in dag_log::tail(filter.clone(), count)? {
+                        println!("{}", serde_json::to_string(&entry)?);
+                    }
+                },
+                None => {
+                    for entry in dag::get_dag_logs(filter.clone())? {
+                        println!("{}", serde_json::to_string(&entry?)?);
+                    }
+                },
+            }
+
+            if follow {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+                let follower = tokio::spawn(dag_log::follow(filter, tx));
+                while let Some(entry) = rx.recv().await {
+                    println!("{}", serde_json::to_string(&entry)?);
+                }
+                follower.await??;
+            }
+        },
+        DagCommands::Conflicts => {
+            let conflicts = dag::get_conflicts()?;
+            println!("{}", serde_json::to_string_pretty(&conflicts)?);
+        },
+        DagCommands::Hashes { level, bucket } => {
+            match bucket {
+                Some(bucket) => {
+                    for id in dag::get_bucket_vertices(bucket)? {
+                        println!("{}", id);
+                    }
+                },
+                None => {
+                    for (index, hash) in dag::get_range_hashes(level)?.iter().enumerate() {
+                        println!("{:>3}  {}", index, hash);
+                    }
+                },
+            }
+        },
+        DagCommands::Export { format, output, since, until, proposal } => {
+            let filter = dag_export::ExportFilter {
+                since: since.as_deref().map(dag::parse_timestamp).transpose()?,
+                until: until.as_deref().map(dag::parse_timestamp).transpose()?,
+                proposal_id: proposal,
+            };
+            let output = output.map(|path| std::path::PathBuf::from(shellexpand::tilde(&path).to_string()));
+
+            let count = dag_export::export(format, &filter, output.as_deref())?;
+            if let Some(path) = &output {
+                println!("Exported {} vertices to {}", count, path.display());
+            }
+        },
+        DagCommands::Import { archive } => {
+            let archive = shellexpand::tilde(&archive).to_string();
+            let (manifest, report) = dag_export::import(std::path::Path::new(&archive))?;
+            println!(
+                "Imported {} of {} vertices from node {} ({} already known, {} rejected)",
+                report.imported, manifest.vertex_count, manifest.node_id, report.known, report.rejected.len()
+            );
+            for vertex_id in &report.rejected {
+                println!("  rejected vertex {}", vertex_id);
+            }
+        },
+        DagCommands::Checkpoint => {
+            let checkpoint = checkpoint::create_checkpoint()?;
+            println!(
+                "Created checkpoint {} at {} vertices (state root {})",
+                checkpoint.id, checkpoint.vertex_count, checkpoint.state_root
+            );
+        },
+        DagCommands::Checkpoints => {
+            let checkpoints = checkpoint::list_checkpoints()?;
+            if checkpoints.is_empty() {
+                println!("No checkpoints found");
+            }
+            for checkpoint in checkpoints {
+                println!(
+                    "{:>4}  {}  {:>8} vertices  height {:>6}  root {}",
+                    checkpoint.id, checkpoint.created.format("%Y-%m-%d %H:%M:%S"),
+                    checkpoint.vertex_count, checkpoint.height, checkpoint.state_root
+                );
+            }
+        },
+        DagCommands::Prune { checkpoint } => {
+            let report = checkpoint::prune(checkpoint)?;
+            match &report.segment {
+                Some(segment) => println!(
+                    "Pruned {} vertices up to checkpoint {} into {} ({} retained)",
+                    report.pruned, report.checkpoint, segment.display(), report.retained
+                ),
+                None => println!("History is already pruned up to checkpoint {}", report.checkpoint),
+            }
+        },
+    }
+
+    Ok(())
+}
+
+fn run_state_command(command: StateCommands) -> Result<()> {
+    match command {
+        StateCommands::Get { key } => {
+            let value = state::get::<serde_json::Value>(&key)?;
+            println!("{}", serde_json::to_string_pretty(&value)?);
+        },
+        StateCommands::Set { key, value } => {
+            // Refuse keys the state does not have rather than dropping them silently
+            state::get::<serde_json::Value>(&key)?;
+
+            let value = serde_json::from_str::<serde_json::Value>(&value)
+                .unwrap_or(serde_json::Value::String(value));
+            state::set(&key, &value)?;
+            info!("Set state: {} = {}", key, value);
+        },
+        StateCommands::List => {
+            let current = state::snapshot()?;
+            println!("{}", serde_json::to_string_pretty(&current)?);
+        },
+        StateCommands::At { target, output, proposals } => {
+            let options = history::HistoryOptions {
+                proposal_dirs: proposals.iter()
+                    .map(|dir| std::path::PathBuf::from(shellexpand::tilde(dir).to_string()))
+                    .collect(),
+                output: output.map(|dir| std::path::PathBuf::from(shellexpand::tilde(&dir).to_string())),
+            };
+
+            let historical = history::state_at(&history::HistoryTarget::parse(&target), &options)?;
+            println!("{}", serde_json::to_string_pretty(&historical)?);
+        },
+        StateCommands::Backup => {
+            let backup = state::create_backup()?;
+            println!("State backup created: {}", backup.display());
+        },
+        StateCommands::Backups => {
+            let backups = state::list_backups()?;
+            if backups.is_empty() {
+                println!("No state backups found");
+            }
+            for backup in backups {
+                println!("{}  {:>10} bytes  {}", backup.created.format("%Y-%m-%d %H:%M:%S"), backup.size, backup.path.display());
+            }
+        },
+        StateCommands::Restore { backup, yes } => {
+            let path = state::resolve_backup(&backup)?;
+            let restored = state::read_backup(&path)?;
+            let changes = state::diff(&state::snapshot()?, &restored)?;
+
+            if changes.is_empty() {
+                println!("Backup {} matches the current state, nothing to restore", path.display());
+                return Ok(());
+            }
+
+            println!("Restoring {} would change:", path.display());
+            for change in &changes {
+                println!("  {}: {} -> {}", change.key, describe_value(&change.current), describe_value(&change.other));
+            }
+
+            if !yes {
+                println!("\nPreview only. Run again with --yes to restore this backup.");
+                return Ok(());
+            }
+
+            let pre_restore = state::restore_backup(&path)?;
+            println!("State restored from {} (previous state saved to {})", path.display(), pre_restore.display());
+        },
+        StateCommands::CleanBackups { keep, keep_days } => {
+            if keep.is_none() && keep_days.is_none() {
+                return Err(anyhow::anyhow!("Specify --keep and/or --keep-days"));
+            }
+
+            let policy = state::BackupRetention {
+                keep,
+                max_age: keep_days.map(chrono::Duration::days),
+            };
+
+            let removed = state::prune_backups(&policy)?;
+            for path in &removed {
+                println!("Removed {}", path.display());
+            }
+            println!("Removed {} old state backup(s)", removed.len());
+        },
+        StateCommands::RecordExecution { proposal } => {
+            state::record_execution(&proposal)?;
+            info!("Recorded execution of proposal {}", proposal);
+        },
+        StateCommands::Rekey { decrypt, .. } => {
+            let report = crypto::rekey()?;
+            let action = if decrypt { "Decrypted" } else { "Re-encrypted" };
+            println!("{} node data: {} file(s) rewritten, {} already done", action, report.resealed, report.unchanged);
+        },
+    }
+
+    Ok(())
+}
+
+// Short human-readable form of a state value for diffs
+fn describe_value(value: &Option<serde_json::Value>) -> String {
+    match value {
+        None => "(absent)".to_string(),
+        Some(serde_json::Value::Array(items)) => format!("[{} entries]", items.len()),
+        Some(serde_json::Value::Object(map)) => format!("{{{} keys}}", map.len()),
+        Some(other) => other.to_string(),
+    }
+}
+
+async fn run_daemon(interval: u64, checkpoint_every: Option<usize>, pruned: bool) -> Result<()> {
     info!("Starting cooperative node daemon");
     
     loop {
@@ -121,11 +793,43 @@ async fn run_daemon(interval: u64) -> Result<()> {
             Err(e) => error!("Error syncing from AgoraNet: {}", e),
         }
         
+        // Checkpoint between executions, and prune in pruned mode
+        if let Some(every) = checkpoint_every {
+            if let Err(e) = maintain_checkpoints(every, pruned) {
+                error!("Error maintaining DAG checkpoints: {}", e);
+            }
+        }
+        
         // Wait for the next interval
         tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
     }
 }
 
+// Create a checkpoint once `every` vertices were recorded since the last one.
+// In pruned mode history is then pruned up to the previous checkpoint, so the
+// state keeps between `every` and twice `every` vertices and replays can
+// start from a retained checkpoint.
+fn maintain_checkpoints(every: usize, pruned: bool) -> Result<()> {
+    let checkpoints = checkpoint::list_checkpoints()?;
+    let covered = checkpoints.last().map_or(0, |last| last.vertex_count);
+    let vertex_count = state::manager().read(|state| state.vertex_count())?;
+
+    if vertex_count < covered + every.max(1) {
+        return Ok(());
+    }
+
+    let created = checkpoint::create_checkpoint()?;
+    info!("Created checkpoint {} at {} vertices", created.id, created.vertex_count);
+
+    if pruned {
+        if let Some(previous) = checkpoints.last() {
+            checkpoint::prune(Some(previous.id))?;
+        }
+    }
+
+    Ok(())
+}
+
 async fn watch_dag_and_queue() -> Result<()> {
     info!("Starting DAG and queue watcher");
     
@@ -0,0 +1,215 @@
+use crate::error::{NodeError, NodeResult};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+// Prefix of the encoding hashed into a payload digest; a new encoding needs a new prefix
+const PAYLOAD_DIGEST_DOMAIN: &[u8] = b"icn-payload-v1";
+
+// Data type reported for vertices recorded before payloads were typed; all of
+// them were proposal executions
+pub const LEGACY_DATA_TYPE: &str = "proposal_execution";
+
+// What a vertex records. Serialized with a `type` tag naming the variant.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum VertexPayload {
+    ProposalExecution(ProposalExecution),
+    Vote(Vote),
+    IdentityRegistration(IdentityRegistration),
+    AssetRegistration(AssetRegistration),
+    ParameterChange(ParameterChange),
+    FederationConfigChange(FederationConfigChange),
+}
+
+// A proposal was executed by CoVM
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProposalExecution {
+    pub proposal_id: String,
+    // SHA-256 of the proposal file
+    pub content_hash: String,
+    pub status_code: i32,
+    // See `executor::result_hash`
+    pub result_hash: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VoteChoice {
+    Yes,
+    No,
+    Abstain,
+}
+
+impl VoteChoice {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VoteChoice::Yes => "yes",
+            VoteChoice::No => "no",
+            VoteChoice::Abstain => "abstain",
+        }
+    }
+}
+
+// A member voted on a proposal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Vote {
+    pub proposal_id: String,
+    // DID of the voter
+    pub voter: String,
+    pub choice: VoteChoice,
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+fn default_weight() -> u64 {
+    1
+}
+
+// A DID was registered in a scope (cooperative or community)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IdentityRegistration {
+    pub did: String,
+    pub scope: String,
+    pub public_key: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+// An asset was registered in a scope
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AssetRegistration {
+    pub asset_id: String,
+    pub scope: String,
+    // DID of the owner
+    pub owner: String,
+    pub asset_type: String,
+    #[serde(default)]
+    pub supply: Option<u64>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+// A governance parameter of a scope changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterChange {
+    pub scope: String,
+    pub key: String,
+    #[serde(default)]
+    pub previous: Option<serde_json::Value>,
+    pub value: serde_json::Value,
+}
+
+// A setting of the federation configuration changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FederationConfigChange {
+    pub federation: String,
+    pub key: String,
+    #[serde(default)]
+    pub previous: Option<serde_json::Value>,
+    pub value: serde_json::Value,
+}
+
+fn require(field: &str, value: &str) -> NodeResult<()> {
+    if value.trim().is_empty() {
+        return Err(NodeError::Validation(format!("Payload field {} is empty", field)));
+    }
+    Ok(())
+}
+
+fn require_did(field: &str, value: &str) -> NodeResult<()> {
+    if !value.starts_with("did:") || value.split(':').count() < 3 {
+        return Err(NodeError::Validation(format!("Payload field {} is not a DID: {}", field, value)));
+    }
+    Ok(())
+}
+
+fn require_sha256(field: &str, value: &str) -> NodeResult<()> {
+    if value.len() != 64 || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(NodeError::Validation(format!("Payload field {} is not a SHA-256 hex digest", field)));
+    }
+    Ok(())
+}
+
+impl VertexPayload {
+    // Name of the variant, as used in the `type` tag
+    pub fn data_type(&self) -> &'static str {
+        match self {
+            VertexPayload::ProposalExecution(_) => "proposal_execution",
+            VertexPayload::Vote(_) => "vote",
+            VertexPayload::IdentityRegistration(_) => "identity_registration",
+            VertexPayload::AssetRegistration(_) => "asset_registration",
+            VertexPayload::ParameterChange(_) => "parameter_change",
+            VertexPayload::FederationConfigChange(_) => "federation_config_change",
+        }
+    }
+
+    // Scope the payload applies to, if it has one
+    pub fn scope(&self) -> Option<&str> {
+        match self {
+            VertexPayload::IdentityRegistration(identity) => Some(&identity.scope),
+            VertexPayload::AssetRegistration(asset) => Some(&asset.scope),
+            VertexPayload::ParameterChange(change) => Some(&change.scope),
+            VertexPayload::FederationConfigChange(change) => Some(&change.federation),
+            VertexPayload::ProposalExecution(_) | VertexPayload::Vote(_) => None,
+        }
+    }
+
+    // Proposal the payload refers to, if any
+    pub fn proposal_id(&self) -> Option<&str> {
+        match self {
+            VertexPayload::ProposalExecution(execution) => Some(&execution.proposal_id),
+            VertexPayload::Vote(vote) => Some(&vote.proposal_id),
+            _ => None,
+        }
+    }
+
+    // Check the payload against the schema of its type
+    pub fn validate(&self) -> NodeResult<()> {
+        match self {
+            VertexPayload::ProposalExecution(execution) => {
+                require("proposal_id", &execution.proposal_id)?;
+                require_sha256("content_hash", &execution.content_hash)?;
+                require_sha256("result_hash", &execution.result_hash)
+            }
+            VertexPayload::Vote(vote) => {
+                require("proposal_id", &vote.proposal_id)?;
+                require_did("voter", &vote.voter)?;
+                if vote.weight == 0 {
+                    return Err(NodeError::Validation("Vote weight must be positive".to_string()));
+                }
+                Ok(())
+            }
+            VertexPayload::IdentityRegistration(identity) => {
+                require_did("did", &identity.did)?;
+                require("scope", &identity.scope)?;
+                require("public_key", &identity.public_key)
+            }
+            VertexPayload::AssetRegistration(asset) => {
+                require("asset_id", &asset.asset_id)?;
+                require("scope", &asset.scope)?;
+                require_did("owner", &asset.owner)?;
+                require("asset_type", &asset.asset_type)
+            }
+            VertexPayload::ParameterChange(change) => {
+                require("scope", &change.scope)?;
+                require("key", &change.key)
+            }
+            VertexPayload::FederationConfigChange(change) => {
+                require("federation", &change.federation)?;
+                require("key", &change.key)
+            }
+        }
+    }
+
+    // SHA-256 of the payload's JSON encoding. Struct fields serialize in
+    // declaration order and JSON objects with sorted keys, so the encoding of
+    // a payload is stable.
+    pub fn digest(&self) -> NodeResult<String> {
+        let mut buf = PAYLOAD_DIGEST_DOMAIN.to_vec();
+        serde_json::to_writer(&mut buf, self)?;
+        Ok(format!("{:x}", Sha256::digest(&buf)))
+    }
+}
@@ -1,10 +1,10 @@
 use crate::error::{NodeError, NodeResult};
 use crate::executor;
 use crate::state;
-use async_trait::async_trait;
-use notify::{Event, EventKind, RecursiveMode, Watcher};
+use notify::{EventKind, RecursiveMode, Watcher};
 use serde::{Deserialize, Serialize};
-use std::fs::{self, File};
+use std::collections::HashMap;
+use std::fs;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -12,6 +12,7 @@ use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
 
 // Proposal structure
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Proposal {
     pub id: String,
@@ -65,8 +66,7 @@ pub async fn process_queue() -> NodeResult<u32> {
         let proposal_id = extract_proposal_id(&filename)?;
 
         // Check if proposal has already been executed
-        let executed_proposals = state::get_executed_proposals()?;
-        if executed_proposals.contains(&proposal_id) {
+        if state::is_executed(&proposal_id)? {
             debug!("Proposal already executed, skipping: {}", proposal_id);
             continue;
         }
@@ -134,7 +134,7 @@ pub async fn watch_queue(tx: mpsc::Sender<String>) -> NodeResult<()> {
     info!("Starting proposal queue watcher on {:?}", queue_dir);
     
     // Set up file watcher
-    let (watcher_tx, mut watcher_rx) = std::sync::mpsc::channel();
+    let (watcher_tx, watcher_rx) = std::sync::mpsc::channel();
     
     let mut watcher = notify::recommended_watcher(watcher_tx)
         .map_err(|e| NodeError::Queue(format!("Failed to create queue watcher: {}", e)))?;
@@ -148,7 +148,7 @@ pub async fn watch_queue(tx: mpsc::Sender<String>) -> NodeResult<()> {
             Ok(Ok(event)) => {
                 if let EventKind::Create(_) | EventKind::Modify(_) = event.kind {
                     for path in event.paths {
-                        if path.extension().map_or(false, |ext| ext == "dsl") {
+                        if path.extension().is_some_and(|ext| ext == "dsl") {
                             let filename = path.file_name().unwrap().to_string_lossy().to_string();
                             tx.send(format!("New proposal file: {}", filename)).await
                                 .map_err(|e| NodeError::Queue(format!("Failed to send event: {}", e)))?;
@@ -208,6 +208,41 @@ pub fn update_proposal_status(file_path: &Path, status: ProposalStatus) -> NodeR
     Ok(())
 }
 
+// Status of every proposal with a file in the queue or executed directory,
+// taken from the `proposal_<id>_<status>.dsl` file names
+pub fn get_proposal_statuses() -> NodeResult<HashMap<String, ProposalStatus>> {
+    let mut statuses = HashMap::new();
+
+    for dir in [get_executed_dir()?, get_queue_dir()?] {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let filename = match path.file_name().and_then(|f| f.to_str()) {
+                Some(filename) => filename,
+                None => continue,
+            };
+
+            let proposal_id = match extract_proposal_id(filename) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+
+            let status = match filename.trim_end_matches(".dsl").rsplit('_').next() {
+                Some("pending") => ProposalStatus::Pending,
+                Some("executing") => ProposalStatus::Executing,
+                Some("completed") => ProposalStatus::Completed,
+                Some("failed") => ProposalStatus::Failed,
+                Some("rejected") => ProposalStatus::Rejected,
+                _ => continue,
+            };
+
+            // The queue is read last, so a re-queued proposal shows its current status
+            statuses.insert(proposal_id, status);
+        }
+    }
+
+    Ok(statuses)
+}
+
 // Get the queue directory
 pub fn get_queue_dir() -> NodeResult<PathBuf> {
     let state_dir = state::get_state_dir()?;
@@ -0,0 +1,218 @@
+use crate::checkpoint;
+use crate::dag;
+use crate::error::{NodeError, NodeResult};
+use crate::executor;
+use crate::queue;
+use crate::state;
+use crate::vertex::Vertex;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+// Where replay looks for proposal files and what it keeps afterwards
+#[derive(Debug, Clone, Default)]
+pub struct ReplayOptions {
+    pub proposal_dirs: Vec<PathBuf>,
+    pub keep_storage: bool,
+    // Start from this checkpoint's storage instead of empty storage
+    pub from_checkpoint: Option<u64>,
+}
+
+// The first vertex whose recomputed result differs from the recorded one
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    pub vertex_id: String,
+    pub proposal_id: String,
+    pub recorded: String,
+    pub recomputed: String,
+}
+
+// Outcome of a replay
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub checkpoint: Option<u64>,
+    pub total: usize,
+    pub verified: usize,
+    // Re-executed, but recorded before result hashes were kept
+    pub unverified: usize,
+    // Vertex ids whose proposal file could not be found
+    pub missing: Vec<String>,
+    pub divergence: Option<Divergence>,
+    pub storage_dir: Option<PathBuf>,
+}
+
+// Temporary CoVM storage for a replay, removed when dropped unless kept
+pub(crate) struct ReplayStorage {
+    pub path: PathBuf,
+    keep: bool,
+}
+
+impl ReplayStorage {
+    pub fn create(prefix: &str, keep: bool) -> NodeResult<Self> {
+        let path = state::get_state_dir()?
+            .join("tmp")
+            .join(format!("{}-{}", prefix, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&path)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o700))?;
+        }
+
+        Ok(Self { path, keep })
+    }
+}
+
+impl Drop for ReplayStorage {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            warn!("Failed to remove replay storage {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Index proposal files by the SHA-256 of their content, which is what
+// vertices record as their payload hash
+fn index_proposals(dirs: &[PathBuf]) -> NodeResult<HashMap<String, PathBuf>> {
+    let mut proposals = HashMap::new();
+
+    for dir in dirs {
+        if !dir.is_dir() {
+            continue;
+        }
+
+        let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("dsl") {
+                continue;
+            }
+
+            let content = fs::read(&path)?;
+            let hash = format!("{:x}", Sha256::digest(&content));
+            proposals.entry(hash).or_insert(path);
+        }
+    }
+
+    debug!("Indexed {} proposal files for replay", proposals.len());
+    Ok(proposals)
+}
+
+// Proposal files in `executed/`, the queue and `extra` directories, by content hash
+pub(crate) fn proposal_index(extra: &[PathBuf]) -> NodeResult<HashMap<String, PathBuf>> {
+    let mut dirs = vec![queue::get_executed_dir()?, queue::get_queue_dir()?];
+    dirs.extend(extra.iter().cloned());
+    index_proposals(&dirs)
+}
+
+// Re-execute vertices in the given order into `storage`, counting outcomes in
+// `report` and stopping at the first vertex whose result hash differs from
+// the recorded one
+pub(crate) fn run(
+    order: &[&Vertex],
+    proposals: &HashMap<String, PathBuf>,
+    storage: &Path,
+    report: &mut ReplayReport,
+) -> NodeResult<()> {
+    for vertex in order {
+        let path = match proposals.get(&vertex.hash) {
+            Some(path) => path,
+            None => {
+                warn!("No proposal file with hash {} for vertex {}", vertex.hash, vertex.id);
+                report.missing.push(vertex.id.clone());
+                continue;
+            }
+        };
+
+        let result = executor::replay_proposal(path, storage)
+            .map_err(|e| NodeError::Execution(format!("Replay of vertex {} failed: {}", vertex.id, e)))?;
+        let recomputed = executor::result_hash(&result);
+
+        if vertex.result_hash.is_empty() {
+            report.unverified += 1;
+            continue;
+        }
+
+        if recomputed != vertex.result_hash {
+            report.divergence = Some(Divergence {
+                vertex_id: vertex.id.clone(),
+                proposal_id: vertex.proposal_id.clone(),
+                recorded: vertex.result_hash.clone(),
+                recomputed,
+            });
+            break;
+        }
+
+        debug!("Vertex {} replayed with matching result", vertex.id);
+        report.verified += 1;
+    }
+
+    Ok(())
+}
+
+// Re-execute every vertex's proposal in topological order against fresh
+// storage, stopping at the first vertex whose result hash differs from the
+// recorded one. Replays start from a checkpoint when one is given, and from
+// the checkpoint history was pruned to when the DAG is pruned.
+pub fn replay(options: &ReplayOptions) -> NodeResult<ReplayReport> {
+    let (pruned_checkpoint, pruned_count, retained, superseded) = state::manager().read(|state| {
+        (
+            state.pruned_history.as_ref().map(|pruned| pruned.checkpoint),
+            state.vertex_count() - state.dag_vertices.len(),
+            state.dag_vertices.clone(),
+            state.dag().conflicts().into_iter()
+                .flat_map(|conflict| conflict.superseded)
+                .collect::<HashSet<String>>(),
+        )
+    })?;
+
+    let checkpoint = match options.from_checkpoint.or(pruned_checkpoint) {
+        Some(id) => Some(checkpoint::get_checkpoint(id)?),
+        None => None,
+    };
+
+    // Vertices recorded after the starting point, by their position in recording order
+    let start = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.vertex_count);
+    if start < pruned_count {
+        return Err(NodeError::Dag(format!(
+            "History before vertex {} is pruned; replay from checkpoint {} or later",
+            pruned_count, pruned_checkpoint.unwrap_or_default()
+        )));
+    }
+    // Only proposal executions change CoVM storage, and executions that lost
+    // a conflict are not part of the agreed history
+    let vertices: Vec<Vertex> = retained.into_iter()
+        .skip(start - pruned_count)
+        .filter(|vertex| vertex.is_execution() && !superseded.contains(&vertex.id))
+        .collect();
+    let order = dag::topological_order(&vertices)?;
+
+    let proposals = proposal_index(&options.proposal_dirs)?;
+
+    let storage = ReplayStorage::create("replay", options.keep_storage)?;
+    if let Some(checkpoint) = &checkpoint {
+        checkpoint::restore_storage(checkpoint, &storage.path)?;
+        info!("Replaying from checkpoint {} at {} vertices", checkpoint.id, checkpoint.vertex_count);
+    }
+    info!("Replaying {} vertices into {:?}", order.len(), storage.path);
+
+    let mut report = ReplayReport {
+        checkpoint: checkpoint.as_ref().map(|checkpoint| checkpoint.id),
+        total: order.len(),
+        storage_dir: options.keep_storage.then(|| storage.path.clone()),
+        ..ReplayReport::default()
+    };
+
+    run(&order, &proposals, &storage.path, &mut report)?;
+
+    Ok(report)
+}
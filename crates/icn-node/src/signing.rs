@@ -0,0 +1,141 @@
+use crate::crypto;
+use crate::error::{NodeError, NodeResult};
+use crate::state;
+use crate::vertex::Vertex;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use tracing::info;
+
+// Prefix of the message signed for a vertex; a new layout needs a new prefix
+const SIGNATURE_DOMAIN: &[u8] = b"icn-vertex-sig-v1";
+
+const KEY_FILE: &str = "node.key";
+
+// The node's identity key, loaded or created on first use
+static NODE_KEY: OnceCell<SigningKey> = OnceCell::new();
+
+// On-disk layout of the node key, sealed like other node data when encryption is on
+#[derive(Debug, Serialize, Deserialize)]
+struct NodeKeyFile {
+    version: u32,
+    algorithm: String,
+    secret_key: String,
+    public_key: String,
+}
+
+// Directory holding the node's keys
+pub fn get_keys_dir() -> NodeResult<PathBuf> {
+    Ok(state::get_state_dir()?.join("keys"))
+}
+
+// Get the node's signing key, creating one the first time a node signs
+pub fn node_key() -> NodeResult<&'static SigningKey> {
+    NODE_KEY.get_or_try_init(load_or_create_key)
+}
+
+fn load_or_create_key() -> NodeResult<SigningKey> {
+    let keys_dir = get_keys_dir()?;
+    let key_file = keys_dir.join(KEY_FILE);
+
+    if key_file.exists() {
+        let content = crypto::read_file(&key_file)?;
+        let stored: NodeKeyFile = serde_json::from_slice(&content)
+            .map_err(|e| NodeError::Crypto(format!("Failed to parse node key {:?}: {}", key_file, e)))?;
+
+        if stored.algorithm != "ed25519" {
+            return Err(NodeError::Crypto(format!(
+                "Unsupported node key algorithm {} in {:?}", stored.algorithm, key_file
+            )));
+        }
+
+        let secret: [u8; 32] = BASE64.decode(&stored.secret_key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| NodeError::Crypto(format!("Invalid node key in {:?}", key_file)))?;
+
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+
+    fs::create_dir_all(&keys_dir)?;
+
+    // Only the node user may read its keys
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&keys_dir, fs::Permissions::from_mode(0o700))?;
+    }
+
+    let mut secret = [0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    let key = SigningKey::from_bytes(&secret);
+
+    let stored = NodeKeyFile {
+        version: 1,
+        algorithm: "ed25519".to_string(),
+        secret_key: BASE64.encode(secret),
+        public_key: BASE64.encode(key.verifying_key().as_bytes()),
+    };
+    let content = serde_json::to_vec_pretty(&stored)?;
+    state::write_atomic(&key_file, &crypto::seal(&content)?)
+        .map_err(|e| NodeError::Crypto(format!("Failed to write node key {:?}: {}", key_file, e)))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600))?;
+    }
+
+    info!("Created node signing key {}", stored.public_key);
+    Ok(key)
+}
+
+// The bytes a vertex signature covers: its content address, the proposal it
+// records, the result hash and the key that signs it. Each field is
+// length-prefixed.
+fn signing_message(vertex: &Vertex) -> Vec<u8> {
+    let mut message = SIGNATURE_DOMAIN.to_vec();
+    for field in [&vertex.id, &vertex.proposal_id, &vertex.result_hash, &vertex.public_key] {
+        message.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        message.extend_from_slice(field.as_bytes());
+    }
+    message
+}
+
+// Public half of a signing key as carried by vertices (base64)
+pub fn public_key(key: &SigningKey) -> String {
+    BASE64.encode(key.verifying_key().as_bytes())
+}
+
+// Sign a vertex with the node key. The id must already be set.
+pub fn sign_vertex(key: &SigningKey, vertex: &mut Vertex) {
+    vertex.public_key = public_key(key);
+    vertex.signature = BASE64.encode(key.sign(&signing_message(vertex)).to_bytes());
+}
+
+// Check that a vertex carries a valid signature by the key it names
+pub fn verify_vertex(vertex: &Vertex) -> NodeResult<()> {
+    if vertex.signature.is_empty() || vertex.public_key.is_empty() {
+        return Err(NodeError::Validation(format!("Vertex {} is not signed", vertex.id)));
+    }
+
+    let public_key: [u8; 32] = BASE64.decode(&vertex.public_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| NodeError::Validation(format!("Vertex {} has a malformed public key", vertex.id)))?;
+    let public_key = VerifyingKey::from_bytes(&public_key)
+        .map_err(|e| NodeError::Validation(format!("Vertex {} has an invalid public key: {}", vertex.id, e)))?;
+
+    let signature = BASE64.decode(&vertex.signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| NodeError::Validation(format!("Vertex {} has a malformed signature", vertex.id)))?;
+
+    public_key.verify_strict(&signing_message(vertex), &signature)
+        .map_err(|_| NodeError::Validation(format!("Vertex {} has an invalid signature", vertex.id)))
+}
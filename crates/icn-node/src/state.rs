@@ -1,18 +1,84 @@
+use crate::checkpoint;
+use crate::crypto;
+use crate::dag::{self, DagGraph, PrunedHistory};
+use crate::dag_log::{self, DagEvent, DagLogEntry};
 use crate::error::{NodeError, NodeResult};
+use crate::index::StateIndex;
+use crate::store::{self, StateStore, StoreBackend};
+use crate::vertex::Vertex;
 use chrono::{DateTime, Utc};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::RangeBounds;
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-// Global state instance
-static STATE: Lazy<Arc<Mutex<NodeState>>> = Lazy::new(|| {
-    Arc::new(Mutex::new(NodeState::default()))
+// Layout version of the state file written by this build. Bump it together with
+// a new entry in `MIGRATIONS` whenever `NodeState` changes shape.
+pub const STATE_SCHEMA_VERSION: u32 = 6;
+
+// Files written before the schema version was recorded
+pub(crate) const LEGACY_SCHEMA_VERSION: u32 = 1;
+
+// Retention applied to snapshot backups after every new snapshot
+const DEFAULT_BACKUP_RETENTION: BackupRetention = BackupRetention {
+    keep: Some(20),
+    max_age: None,
+};
+
+// Migration steps, where `MIGRATIONS[i]` upgrades a version `i + 1` state value to `i + 2`
+type Migration = fn(serde_json::Value) -> NodeResult<serde_json::Value>;
+const MIGRATIONS: &[Migration] = &[
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+    migrate_v5_to_v6,
+];
+
+// Top-level `NodeState` fields; every other key lives in `extensions`
+pub(crate) const CORE_FIELDS: &[&str] = &[
+    "node_id",
+    "initialized",
+    "last_updated",
+    "last_executed_block",
+    "last_proposal_id",
+    "executed_proposals",
+    "active_connection",
+    "peers",
+    "system_version",
+    "dag_vertices",
+    "pruned_history",
+    "wal_sequence",
+];
+
+// Global state manager
+static MANAGER: Lazy<StateManager> = Lazy::new(StateManager::new);
+
+// Data directory selected at startup (`--data-dir` / `ICN_DATA_DIR`)
+static DATA_DIR: OnceCell<PathBuf> = OnceCell::new();
+
+// Storage backend selected at startup (`--state-backend` / `ICN_STATE_BACKEND`)
+static BACKEND: OnceCell<StoreBackend> = OnceCell::new();
+
+// Extension namespaces in use, mapped to the type stored under each
+static EXTENSIONS: Lazy<Mutex<BTreeMap<&'static str, &'static str>>> = Lazy::new(|| {
+    Mutex::new(BTreeMap::new())
 });
 
+// State owned by another module and stored under its own namespace. Values
+// survive snapshots and log replay like the core fields.
+pub trait StateExtension: Serialize + DeserializeOwned {
+    const NAMESPACE: &'static str;
+}
+
 // Node state structure
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeState {
@@ -25,15 +91,90 @@ pub struct NodeState {
     pub active_connection: String,
     pub peers: Vec<String>,
     pub system_version: String,
-    pub dag_vertices: Vec<VertexEntry>,
+    pub dag_vertices: Vec<Vertex>,
+    // Summary of vertices moved out of `dag_vertices` by pruning
+    #[serde(default)]
+    pub pruned_history: Option<PrunedHistory>,
+    // Sequence number of the last write-ahead log record folded into this state
+    pub wal_sequence: u64,
+    // Namespaced values stored by other modules (see `StateExtension`)
+    pub extensions: BTreeMap<String, serde_json::Value>,
+    // Lookups over vertices and executed proposals; rebuilt by `reindex`
+    #[serde(skip)]
+    index: StateIndex,
+}
+
+// Owns the in-memory node state. Readers share an `RwLock` and never wait on
+// disk I/O; writers are serialized by the writer lock, which also guards the
+// store. Lock order is always writer, then state.
+pub struct StateManager {
+    state: RwLock<NodeState>,
+    writer: Mutex<()>,
+    store: OnceCell<Box<dyn StateStore>>,
+}
+
+// A batch of operations that is validated up front, handed to the store in a
+// single write and only then applied as a unit
+#[derive(Debug, Default)]
+pub struct Transaction {
+    ops: Vec<StateOp>,
 }
 
+// A single mutation recorded in the write-ahead log
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct VertexEntry {
-    pub id: String,
-    pub proposal_id: String,
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum StateOp {
+    AddVertex { vertex: Box<Vertex> },
+    AddExecutedProposal { proposal_id: String },
+    Set { key: String, value: serde_json::Value },
+}
+
+// Snapshot file layout: the state plus its schema version and a checksum over
+// its canonical encoding
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotFile {
+    #[serde(default = "legacy_schema_version")]
+    schema_version: u32,
+    checksum: String,
+    state: serde_json::Value,
+}
+
+fn legacy_schema_version() -> u32 {
+    LEGACY_SCHEMA_VERSION
+}
+
+// A snapshot backup found in the backup directory
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub path: PathBuf,
+    pub created: DateTime<Utc>,
+    pub size: u64,
+}
+
+// Which snapshot backups to keep. A backup is kept if it is one of the newest
+// `keep` backups or younger than `max_age`; with neither set nothing is removed.
+#[derive(Debug, Clone, Copy)]
+pub struct BackupRetention {
+    pub keep: Option<usize>,
+    pub max_age: Option<chrono::Duration>,
+}
+
+// A top-level state field that differs between two states
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    pub key: String,
+    pub current: Option<serde_json::Value>,
+    pub other: Option<serde_json::Value>,
+}
+
+// A committed operation with its log position. The JSON file store writes one
+// of these per line to the write-ahead log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalRecord {
+    pub seq: u64,
     pub timestamp: DateTime<Utc>,
-    pub hash: String,
+    #[serde(flatten)]
+    pub op: StateOp,
 }
 
 impl Default for NodeState {
@@ -49,12 +190,226 @@ impl Default for NodeState {
             peers: Vec::new(),
             system_version: env!("CARGO_PKG_VERSION").to_string(),
             dag_vertices: Vec::new(),
+            pruned_history: None,
+            wal_sequence: 0,
+            extensions: BTreeMap::new(),
+            index: StateIndex::default(),
         }
     }
 }
 
+impl NodeState {
+    // Check that an operation can be applied to this state, without touching it
+    fn validate(&self, op: &StateOp) -> NodeResult<()> {
+        match op {
+            StateOp::AddVertex { vertex } => {
+                dag::verify_vertex(vertex)?;
+                dag::verify_submitter(self, vertex)?;
+            }
+            StateOp::Set { key, value } => {
+                NodeState::default().set_field(key, value.clone())?;
+            }
+            StateOp::AddExecutedProposal { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    // Apply a committed record to the in-memory state
+    pub(crate) fn apply_record(&mut self, record: &WalRecord) -> NodeResult<()> {
+        self.apply(&record.op, record.timestamp)?;
+        self.wal_sequence = record.seq;
+        Ok(())
+    }
+
+    // Apply a single operation to the in-memory state
+    fn apply(&mut self, op: &StateOp, at: DateTime<Utc>) -> NodeResult<()> {
+        match op {
+            StateOp::AddVertex { vertex } => {
+                // Vertex ids are content addresses, so a known id is the same vertex
+                if !self.index.dag().contains(&vertex.id) {
+                    self.index.insert_vertex(self.dag_vertices.len(), vertex);
+                    self.dag_vertices.push(vertex.as_ref().clone());
+                }
+            }
+            StateOp::AddExecutedProposal { proposal_id } => {
+                if !self.index.is_executed(proposal_id) {
+                    self.index.insert_executed(proposal_id);
+                    self.executed_proposals.push(proposal_id.clone());
+                }
+            }
+            StateOp::Set { key, value } => {
+                if !self.set_field(key, value.clone())? {
+                    self.extensions.insert(key.clone(), value.clone());
+                }
+
+                if key == "dag_vertices" || key == "executed_proposals" || key == "pruned_history" {
+                    self.reindex();
+                }
+            }
+        }
+
+        self.last_updated = at;
+        Ok(())
+    }
+
+    // Rebuild the lookup index, e.g. after the state was deserialized. Pruned
+    // history comes from the segment indexes loaded by `checkpoint::load_pruned_index`.
+    pub(crate) fn reindex(&mut self) {
+        let segments = checkpoint::pruned_segments();
+        self.index = StateIndex::build(
+            &self.dag_vertices, &self.executed_proposals, self.pruned_history.as_ref(), &segments,
+        );
+    }
+
+    pub fn vertex(&self, id: &str) -> Option<&Vertex> {
+        self.index.vertex(id).map(|position| &self.dag_vertices[position])
+    }
+
+    pub fn vertices_for_proposal(&self, proposal_id: &str) -> Vec<&Vertex> {
+        self.index.vertices_for_proposal(proposal_id).iter()
+            .map(|position| &self.dag_vertices[*position])
+            .collect()
+    }
+
+    pub fn vertices_with_hash(&self, hash: &str) -> Vec<&Vertex> {
+        self.index.vertices_with_hash(hash).iter()
+            .map(|position| &self.dag_vertices[*position])
+            .collect()
+    }
+
+    pub fn vertices_by_submitter(&self, submitter: &str) -> Vec<&Vertex> {
+        self.index.vertices_by_submitter(submitter).iter()
+            .map(|position| &self.dag_vertices[*position])
+            .collect()
+    }
+
+    // Vertices with a timestamp in `range`, oldest first
+    pub fn vertices_between<R>(&self, range: R) -> Vec<&Vertex>
+    where
+        R: RangeBounds<DateTime<Utc>>,
+    {
+        self.index.vertices_between(range)
+            .map(|position| &self.dag_vertices[position])
+            .collect()
+    }
+
+    pub fn is_executed(&self, proposal_id: &str) -> bool {
+        self.index.is_executed(proposal_id)
+    }
+
+    // Parent/child structure of the DAG
+    pub fn dag(&self) -> &DagGraph {
+        self.index.dag()
+    }
+
+    // Number of vertices ever recorded, including pruned ones
+    pub fn vertex_count(&self) -> usize {
+        self.pruned_history.as_ref().map_or(0, |pruned| pruned.vertex_count) + self.dag_vertices.len()
+    }
+
+    // Read an extension value
+    pub fn extension<T: StateExtension>(&self) -> NodeResult<Option<T>> {
+        self.extensions.get(T::NAMESPACE)
+            .map(|value| {
+                serde_json::from_value(value.clone()).map_err(|e| NodeError::State(format!(
+                    "Failed to deserialize state extension {}: {}", T::NAMESPACE, e
+                )))
+            })
+            .transpose()
+    }
+
+    // Serialize a single core field, or `None` if `key` is not a core field
+    pub(crate) fn field(&self, key: &str) -> NodeResult<Option<serde_json::Value>> {
+        let value = match key {
+            "node_id" => serde_json::to_value(&self.node_id),
+            "initialized" => serde_json::to_value(self.initialized),
+            "last_updated" => serde_json::to_value(self.last_updated),
+            "last_executed_block" => serde_json::to_value(self.last_executed_block),
+            "last_proposal_id" => serde_json::to_value(self.last_proposal_id),
+            "executed_proposals" => serde_json::to_value(&self.executed_proposals),
+            "active_connection" => serde_json::to_value(&self.active_connection),
+            "peers" => serde_json::to_value(&self.peers),
+            "system_version" => serde_json::to_value(&self.system_version),
+            "dag_vertices" => serde_json::to_value(&self.dag_vertices),
+            "pruned_history" => serde_json::to_value(&self.pruned_history),
+            "wal_sequence" => serde_json::to_value(self.wal_sequence),
+            _ => return Ok(None),
+        };
+
+        value.map(Some)
+            .map_err(|e| NodeError::State(format!("Failed to serialize state field {}: {}", key, e)))
+    }
+
+    // Update a single core field. Returns false if `key` is not a core field.
+    fn set_field(&mut self, key: &str, value: serde_json::Value) -> NodeResult<bool> {
+        fn parse<T: DeserializeOwned>(key: &str, value: serde_json::Value) -> NodeResult<T> {
+            serde_json::from_value(value)
+                .map_err(|e| NodeError::State(format!("Invalid value for state field {}: {}", key, e)))
+        }
+
+        match key {
+            "node_id" => self.node_id = parse(key, value)?,
+            "initialized" => self.initialized = parse(key, value)?,
+            "last_updated" => self.last_updated = parse(key, value)?,
+            "last_executed_block" => self.last_executed_block = parse(key, value)?,
+            "last_proposal_id" => self.last_proposal_id = parse(key, value)?,
+            "executed_proposals" => self.executed_proposals = parse(key, value)?,
+            "active_connection" => self.active_connection = parse(key, value)?,
+            "peers" => self.peers = parse(key, value)?,
+            "system_version" => self.system_version = parse(key, value)?,
+            "dag_vertices" => self.dag_vertices = parse(key, value)?,
+            "pruned_history" => self.pruned_history = parse(key, value)?,
+            "wal_sequence" | "extensions" => {
+                return Err(NodeError::State(format!("State field {} cannot be set directly", key)));
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+// Set the data directory used by every module. Must be called before `init`.
+pub fn set_data_dir(path: &str) -> NodeResult<()> {
+    let expanded = shellexpand::tilde(path).to_string();
+
+    DATA_DIR.set(PathBuf::from(expanded))
+        .map_err(|dir| NodeError::Config(format!("Data directory already set to {:?}", dir)))
+}
+
+// Select the storage backend. Must be called before `init`.
+pub fn set_backend(backend: StoreBackend) -> NodeResult<()> {
+    BACKEND.set(backend)
+        .map_err(|backend| NodeError::Config(format!("State backend already set to {}", backend)))
+}
+
+// Storage backend chosen at startup, falling back to `ICN_STATE_BACKEND` and
+// then the JSON file store
+pub fn get_backend() -> NodeResult<StoreBackend> {
+    if let Some(backend) = BACKEND.get() {
+        return Ok(*backend);
+    }
+
+    match std::env::var("ICN_STATE_BACKEND") {
+        Ok(name) if !name.is_empty() => name.parse(),
+        _ => Ok(StoreBackend::default()),
+    }
+}
+
 // State file paths
 pub fn get_state_dir() -> NodeResult<PathBuf> {
+    if let Some(dir) = DATA_DIR.get() {
+        return Ok(dir.clone());
+    }
+
+    // Allow library callers (and tests) to redirect the node without the CLI
+    if let Ok(dir) = std::env::var("ICN_DATA_DIR") {
+        if !dir.is_empty() {
+            return Ok(PathBuf::from(shellexpand::tilde(&dir).to_string()));
+        }
+    }
+
     let home_dir = dirs::home_dir()
         .ok_or_else(|| NodeError::State("Could not determine home directory".to_string()))?;
     
@@ -67,6 +422,16 @@ pub fn get_state_file() -> NodeResult<PathBuf> {
     Ok(state_dir.join("state.json"))
 }
 
+pub fn get_wal_file() -> NodeResult<PathBuf> {
+    let state_dir = get_state_dir()?;
+    Ok(state_dir.join("state.wal"))
+}
+
+pub fn get_database_dir() -> NodeResult<PathBuf> {
+    let state_dir = get_state_dir()?;
+    Ok(state_dir.join("state.db"))
+}
+
 pub fn get_backup_dir() -> NodeResult<PathBuf> {
     let state_dir = get_state_dir()?;
     Ok(state_dir.join("state").join("backups"))
@@ -74,185 +439,1048 @@ pub fn get_backup_dir() -> NodeResult<PathBuf> {
 
 // Initialize state
 pub fn init() -> NodeResult<()> {
-    let state_dir = get_state_dir()?;
-    let state_file = get_state_file()?;
-    let backup_dir = get_backup_dir()?;
-
-    // Create directories if they don't exist
-    fs::create_dir_all(&state_dir)?;
-    fs::create_dir_all(&backup_dir)?;
+    let store = open_store()?;
 
     // Load or create state
-    if state_file.exists() {
-        load_state()?;
-    } else {
+    let loaded = store.load()?;
+    let created = loaded.is_none();
+    MANAGER.install(loaded.unwrap_or_default(), store)?;
+
+    // A new, migrated or recovered state is written out in full right away
+    if created || MANAGER.store()?.needs_compaction() {
         save_state()?;
     }
 
     Ok(())
 }
 
-// Load state from file
-pub fn load_state() -> NodeResult<()> {
-    let state_file = get_state_file()?;
-    
-    let file = File::open(&state_file)
-        .map_err(|e| NodeError::State(format!("Failed to open state file: {}", e)))?;
-    
-    let reader = BufReader::new(file);
-    let state: NodeState = serde_json::from_reader(reader)
-        .map_err(|e| NodeError::State(format!("Failed to parse state file: {}", e)))?;
-    
-    // Update global state
-    let mut global_state = STATE.lock()
-        .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))?;
-    
-    *global_state = state;
-    
-    Ok(())
+// Initialize state from an imported snapshot instead of the store's contents.
+// Refuses to overwrite a store that already holds state.
+pub fn import_state(state: NodeState) -> NodeResult<()> {
+    let store = open_store()?;
+
+    if store.load()?.is_some() {
+        return Err(NodeError::State(format!(
+            "The {} state store already holds state; import into a fresh data directory", store.name()
+        )));
+    }
+
+    MANAGER.install(state, store)?;
+    save_state()
 }
 
-// Save state to file
-pub fn save_state() -> NodeResult<()> {
-    let state_file = get_state_file()?;
-    
-    // Get current state
-    let mut state = STATE.lock()
-        .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))?;
-    
-    // Update timestamp
-    state.last_updated = Utc::now();
-    
-    // Create backup first if the file exists
-    if state_file.exists() {
-        backup_state()?;
+// Open the configured store, creating its directories
+fn open_store() -> NodeResult<Box<dyn StateStore>> {
+    let backend = get_backend()?;
+
+    // The in-memory store never touches the filesystem
+    if backend != StoreBackend::Memory {
+        let state_dir = get_state_dir()?;
+        let backup_dir = get_backup_dir()?;
+
+        // Create directories if they don't exist
+        fs::create_dir_all(&state_dir)?;
+        fs::create_dir_all(&backup_dir)?;
     }
-    
-    // Write to file
-    let file = File::create(&state_file)
-        .map_err(|e| NodeError::State(format!("Failed to create state file: {}", e)))?;
-    
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, &*state)
-        .map_err(|e| NodeError::State(format!("Failed to write state file: {}", e)))?;
-    
-    Ok(())
+
+    let store = store::open(backend)?;
+    info!("Using {} state store", store.name());
+    Ok(store)
+}
+
+// Read and verify a snapshot file, migrating it to the current schema.
+// Returns the state together with the schema version found on disk.
+pub(crate) fn read_snapshot(path: &Path) -> NodeResult<(NodeState, u32)> {
+    let content = fs::read(path)
+        .map_err(|e| NodeError::State(format!("Failed to open state file {:?}: {}", path, e)))?;
+
+    // A missing or wrong key is reported as such rather than as a parse error
+    let content = crypto::open(&content)
+        .map_err(|e| NodeError::Crypto(format!("State file {:?}: {}", path, e)))?;
+
+    let value: serde_json::Value = serde_json::from_slice(&content)
+        .map_err(|e| NodeError::State(format!("Failed to parse state file {:?}: {}", path, e)))?;
+
+    // Files written before checksums were introduced hold the bare state
+    let (state_value, schema_version) = if value.get("checksum").is_some() && value.get("state").is_some() {
+        let snapshot: SnapshotFile = serde_json::from_value(value)
+            .map_err(|e| NodeError::State(format!("Failed to parse state file {:?}: {}", path, e)))?;
+
+        let actual = compute_checksum(&snapshot.state)?;
+        if actual != snapshot.checksum {
+            return Err(NodeError::State(format!(
+                "Checksum mismatch in state file {:?}: expected {}, found {}",
+                path, snapshot.checksum, actual
+            )));
+        }
+
+        (snapshot.state, snapshot.schema_version)
+    } else {
+        debug!("State file {:?} has no checksum, loading as legacy format", path);
+        (value, LEGACY_SCHEMA_VERSION)
+    };
+
+    let state_value = migrate(state_value, schema_version)
+        .map_err(|e| NodeError::State(format!("State file {:?}: {}", path, e)))?;
+
+    let mut state: NodeState = serde_json::from_value(state_value)
+        .map_err(|e| NodeError::State(format!("Failed to parse state file {:?}: {}", path, e)))?;
+    state.reindex();
+
+    Ok((state, schema_version))
+}
+
+// Read only the schema version of a snapshot file, if it can be parsed at all
+pub(crate) fn peek_schema_version(path: &Path) -> Option<u32> {
+    let content = crypto::read_file(path).ok()?;
+    let value: serde_json::Value = serde_json::from_slice(&content).ok()?;
+
+    match value.get("schema_version") {
+        Some(version) => version.as_u64().map(|v| v as u32),
+        None => Some(LEGACY_SCHEMA_VERSION),
+    }
+}
+
+// Run the migration chain from `from_version` up to `STATE_SCHEMA_VERSION`
+pub(crate) fn migrate(mut value: serde_json::Value, from_version: u32) -> NodeResult<serde_json::Value> {
+    if from_version > STATE_SCHEMA_VERSION {
+        return Err(NodeError::State(format!(
+            "schema version {} is newer than the version {} supported by icn-node {}; \
+             upgrade icn-node to load this state",
+            from_version, STATE_SCHEMA_VERSION, env!("CARGO_PKG_VERSION")
+        )));
+    }
+
+    if from_version < LEGACY_SCHEMA_VERSION {
+        return Err(NodeError::State(format!("invalid schema version {}", from_version)));
+    }
+
+    for version in from_version..STATE_SCHEMA_VERSION {
+        debug!("Migrating state from schema version {} to {}", version, version + 1);
+        let step = MIGRATIONS[(version - LEGACY_SCHEMA_VERSION) as usize];
+        value = step(value)?;
+    }
+
+    Ok(value)
 }
 
-// Backup state
-pub fn backup_state() -> NodeResult<PathBuf> {
-    let state_file = get_state_file()?;
+// v1 -> v2: record the write-ahead log position in the state
+fn migrate_v1_to_v2(mut value: serde_json::Value) -> NodeResult<serde_json::Value> {
+    let map = value.as_object_mut()
+        .ok_or_else(|| NodeError::State("State is not an object".to_string()))?;
+
+    map.entry("wal_sequence").or_insert(serde_json::json!(0));
+
+    Ok(value)
+}
+
+// v2 -> v3: move keys that are not core fields into the `extensions` map.
+// Earlier builds silently dropped such keys, so this mostly creates the map.
+fn migrate_v2_to_v3(mut value: serde_json::Value) -> NodeResult<serde_json::Value> {
+    let map = value.as_object_mut()
+        .ok_or_else(|| NodeError::State("State is not an object".to_string()))?;
+
+    let extra_keys: Vec<String> = map.keys()
+        .filter(|key| !CORE_FIELDS.contains(&key.as_str()) && key.as_str() != "extensions")
+        .cloned()
+        .collect();
+
+    let mut extensions = serde_json::Map::new();
+    for key in extra_keys {
+        if let Some(extra) = map.remove(&key) {
+            extensions.insert(key, extra);
+        }
+    }
+
+    map.insert("extensions".to_string(), serde_json::Value::Object(extensions));
+
+    Ok(value)
+}
+
+// v3 -> v4: vertices reference their parents. Earlier builds treated the
+// vertex list as a chain, so each vertex gets its predecessor as parent.
+fn migrate_v3_to_v4(mut value: serde_json::Value) -> NodeResult<serde_json::Value> {
+    if let Some(vertices) = value.get_mut("dag_vertices").and_then(|v| v.as_array_mut()) {
+        let mut previous: Option<serde_json::Value> = None;
+        for vertex in vertices.iter_mut() {
+            let map = vertex.as_object_mut()
+                .ok_or_else(|| NodeError::State("DAG vertex is not an object".to_string()))?;
+
+            let parents = previous.take().map_or_else(Vec::new, |id| vec![id]);
+            map.entry("parents").or_insert(serde_json::Value::Array(parents));
+            previous = map.get("id").cloned();
+        }
+    }
+
+    Ok(value)
+}
+
+// v4 -> v5: the state can hold a summary of pruned DAG history
+fn migrate_v4_to_v5(mut value: serde_json::Value) -> NodeResult<serde_json::Value> {
+    let map = value.as_object_mut()
+        .ok_or_else(|| NodeError::State("State is not an object".to_string()))?;
+
+    map.entry("pruned_history").or_insert(serde_json::Value::Null);
+
+    Ok(value)
+}
+
+// v5 -> v6: vertices carry a layout version. Each vertex is read in whatever
+// layout it was written in and rewritten in the current one, or marked as
+// legacy if it was never signed.
+fn migrate_v5_to_v6(mut value: serde_json::Value) -> NodeResult<serde_json::Value> {
+    if let Some(vertices) = value.get_mut("dag_vertices").and_then(|v| v.as_array_mut()) {
+        for entry in vertices.iter_mut() {
+            let vertex = Vertex::migrate(entry.take())
+                .map_err(|e| NodeError::State(format!("Failed to read DAG vertex: {}", e)))?;
+            *entry = serde_json::to_value(&vertex)?;
+        }
+    }
+
+    Ok(value)
+}
+
+// Copy a state file aside before it is rewritten in a newer layout
+pub(crate) fn backup_before_migration(state_file: &Path, schema_version: u32) -> NodeResult<PathBuf> {
     let backup_dir = get_backup_dir()?;
-    
-    // Create backup filename with timestamp
+
     let timestamp = Utc::now().format("%Y%m%d_%H%M%S");
-    let backup_file = backup_dir.join(format!("state_{}.json", timestamp));
-    
-    // Copy the current state file to backup
-    fs::copy(&state_file, &backup_file)
-        .map_err(|e| NodeError::State(format!("Failed to create backup: {}", e)))?;
-    
+    let backup_file = backup_dir.join(format!("premigration_v{}_{}.json", schema_version, timestamp));
+
+    fs::copy(state_file, &backup_file)
+        .map_err(|e| NodeError::State(format!("Failed to back up state before migration: {}", e)))?;
+
     Ok(backup_file)
 }
 
+// Checksum over the canonical (sorted-key, compact) encoding of a state value
+fn compute_checksum(state: &serde_json::Value) -> NodeResult<String> {
+    let canonical = serde_json::to_vec(state)
+        .map_err(|e| NodeError::State(format!("Failed to serialize state: {}", e)))?;
+
+    Ok(format!("sha256:{:x}", Sha256::digest(&canonical)))
+}
+
+// Compact the current state into the store
+pub fn save_state() -> NodeResult<()> {
+    let _writer = MANAGER.lock_writer()?;
+    MANAGER.compact_locked()
+}
+
+// Encode a state as a snapshot file carrying its schema version and checksum
+pub(crate) fn encode_snapshot(state: &NodeState) -> NodeResult<Vec<u8>> {
+    let state_value = serde_json::to_value(state)
+        .map_err(|e| NodeError::State(format!("Failed to serialize state: {}", e)))?;
+
+    let snapshot = SnapshotFile {
+        schema_version: STATE_SCHEMA_VERSION,
+        checksum: compute_checksum(&state_value)?,
+        state: state_value,
+    };
+
+    serde_json::to_vec_pretty(&snapshot)
+        .map_err(|e| NodeError::State(format!("Failed to serialize state: {}", e)))
+}
+
+// Write a file via a temporary sibling, fsync and rename, so readers only ever
+// see the old or the new content
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+
+    // Make the rename itself durable
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+impl StateManager {
+    fn new() -> Self {
+        Self {
+            state: RwLock::new(NodeState::default()),
+            writer: Mutex::new(()),
+            store: OnceCell::new(),
+        }
+    }
+
+    // Attach the store together with the state loaded from it
+    fn install(&self, mut state: NodeState, store: Box<dyn StateStore>) -> NodeResult<()> {
+        let _writer = self.lock_writer()?;
+        checkpoint::load_pruned_index(state.pruned_history.as_ref())?;
+        state.reindex();
+
+        self.store.set(store)
+            .map_err(|_| NodeError::State("State store is already initialized".to_string()))?;
+        *self.state_write()? = state;
+
+        Ok(())
+    }
+
+    fn store(&self) -> NodeResult<&dyn StateStore> {
+        self.store.get()
+            .map(|store| store.as_ref())
+            .ok_or_else(|| NodeError::State("State store is not initialized".to_string()))
+    }
+
+    fn lock_writer(&self) -> NodeResult<MutexGuard<'_, ()>> {
+        self.writer.lock()
+            .map_err(|e| NodeError::State(format!("Failed to lock state writer: {}", e)))
+    }
+
+    fn state_read(&self) -> NodeResult<RwLockReadGuard<'_, NodeState>> {
+        self.state.read()
+            .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))
+    }
+
+    fn state_write(&self) -> NodeResult<RwLockWriteGuard<'_, NodeState>> {
+        self.state.write()
+            .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))
+    }
+
+    // Run a read-only closure against the current state
+    pub fn read<R>(&self, f: impl FnOnce(&NodeState) -> R) -> NodeResult<R> {
+        let state = self.state_read()?;
+        Ok(f(&state))
+    }
+
+    // Build a transaction from the current state and commit it, with no other
+    // writer able to run in between
+    pub fn write<R>(&self, f: impl FnOnce(&NodeState, &mut Transaction) -> NodeResult<R>) -> NodeResult<R> {
+        let _writer = self.lock_writer()?;
+
+        let mut tx = Transaction::new();
+        let result = {
+            let state = self.state_read()?;
+            f(&state, &mut tx)?
+        };
+
+        self.commit_locked(tx.ops)?;
+        Ok(result)
+    }
+
+    // Commit a prepared transaction
+    pub fn commit(&self, tx: Transaction) -> NodeResult<()> {
+        let _writer = self.lock_writer()?;
+        self.commit_locked(tx.ops)
+    }
+
+    // Persist operations through the store, then apply them to the state.
+    // Callers must hold the writer lock.
+    fn commit_locked(&self, mut ops: Vec<StateOp>) -> NodeResult<()> {
+        let store = self.store()?;
+
+        // Validate everything first so a batch is never half applied. A vertex
+        // that is already recorded collapses into the existing one.
+        {
+            let state = self.state_read()?;
+            for op in &ops {
+                state.validate(op)?;
+            }
+
+            let mut seen = HashSet::new();
+            ops.retain(|op| match op {
+                StateOp::AddVertex { vertex } => {
+                    !state.dag().contains(&vertex.id) && seen.insert(vertex.id.clone())
+                }
+                _ => true,
+            });
+        }
+
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        // Only the holder of the writer lock advances `wal_sequence`, so the
+        // numbers stay valid until the records are applied below
+        let timestamp = Utc::now();
+        let first_seq = self.state_read()?.wal_sequence + 1;
+        let records: Vec<WalRecord> = ops.into_iter()
+            .enumerate()
+            .map(|(offset, op)| WalRecord { seq: first_seq + offset as u64, timestamp, op })
+            .collect();
+
+        // Make the records durable before anyone can see them. Readers are not
+        // blocked on the disk write, and a failed write leaves the state as it was.
+        store.append(&records)?;
+
+        // Publish the whole batch at once
+        {
+            let mut state = self.state_write()?;
+            for record in &records {
+                state.apply_record(record)?;
+            }
+        }
+
+        {
+            let state = self.state_read()?;
+            for record in &records {
+                let vertex = match &record.op {
+                    StateOp::AddVertex { vertex } => vertex,
+                    _ => continue,
+                };
+
+                let conflict = state.dag().conflict_of(&vertex.id);
+                if let Some(conflict) = &conflict {
+                    warn!(
+                        "Vertex {} conflicts with earlier claims on {} ({:?}); vertex {} wins",
+                        vertex.id, conflict.subject, conflict.kind, conflict.winner
+                    );
+                }
+
+                // The vertices are committed by now, so a log failure must
+                // not fail the commit
+                if store.persistent() {
+                    let mut entries = vec![DagLogEntry::for_vertex(DagEvent::VertexAdded, vertex)];
+                    if let Some(conflict) = conflict {
+                        let mut entry = DagLogEntry::for_vertex(DagEvent::ConflictDetected, vertex);
+                        entry.detail = serde_json::to_string(&conflict).ok();
+                        entries.push(entry);
+                    }
+                    for entry in &entries {
+                        if let Err(e) = dag_log::append(entry) {
+                            warn!("Failed to log {:?} for vertex {}: {}", entry.event, vertex.id, e);
+                        }
+                    }
+                }
+            }
+        }
+
+        if store.needs_compaction() {
+            self.compact_locked()?;
+        }
+
+        Ok(())
+    }
+
+    // Rewrite the store from the current state and keep a backup of it.
+    // Callers must hold the writer lock.
+    fn compact_locked(&self) -> NodeResult<()> {
+        let store = self.store()?;
+
+        {
+            let state = self.state_read()?;
+            store.compact(&state)?;
+            debug!("Compacted {} state store at log sequence {}", store.name(), state.wal_sequence);
+        }
+
+        // Keep a copy of every compacted state so a damaged store can be recovered
+        if store.persistent() {
+            self.backup_locked()?;
+
+            if let Err(e) = prune_backups(&DEFAULT_BACKUP_RETENTION) {
+                warn!("Failed to prune old state backups: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write the current state to a new snapshot backup. Only a short read lock
+    // is held while the state is encoded. Callers must hold the writer lock.
+    fn backup_locked(&self) -> NodeResult<PathBuf> {
+        let content = {
+            let state = self.state_read()?;
+            encode_snapshot(&state)?
+        };
+
+        let backup_dir = get_backup_dir()?;
+        fs::create_dir_all(&backup_dir)?;
+
+        // Create backup filename with timestamp
+        let timestamp = Utc::now().format("%Y%m%d_%H%M%S_%3f");
+        let backup_file = backup_dir.join(format!("state_{}.json", timestamp));
+
+        write_atomic(&backup_file, &crypto::seal(&content)?)
+            .map_err(|e| NodeError::State(format!("Failed to create backup: {}", e)))?;
+
+        Ok(backup_file)
+    }
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // Set a core field, or an untyped extension for any other key
+    pub fn set<T: Serialize>(&mut self, key: &str, value: T) -> NodeResult<&mut Self> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| NodeError::State(format!("Failed to serialize value: {}", e)))?;
+
+        self.ops.push(StateOp::Set { key: key.to_string(), value });
+        Ok(self)
+    }
+
+    pub fn put_extension<T: StateExtension>(&mut self, value: &T) -> NodeResult<&mut Self> {
+        register_extension::<T>()?;
+
+        let value = serde_json::to_value(value)
+            .map_err(|e| NodeError::State(format!("Failed to serialize state extension {}: {}", T::NAMESPACE, e)))?;
+
+        self.ops.push(StateOp::Set { key: T::NAMESPACE.to_string(), value });
+        Ok(self)
+    }
+
+    pub fn add_vertex(&mut self, vertex: Vertex) -> &mut Self {
+        self.ops.push(StateOp::AddVertex { vertex: Box::new(vertex) });
+        self
+    }
+
+    pub fn add_executed_proposal(&mut self, proposal_id: &str) -> &mut Self {
+        self.ops.push(StateOp::AddExecutedProposal { proposal_id: proposal_id.to_string() });
+        self
+    }
+}
+
+// Access the global state manager
+pub fn manager() -> &'static StateManager {
+    &MANAGER
+}
+
+// Commit a transaction against the global state
+pub fn commit(tx: Transaction) -> NodeResult<()> {
+    MANAGER.commit(tx)
+}
+
+// Commit a transaction from async code. The store write and fsync run on the
+// blocking thread pool so they never stall the tokio runtime.
+#[allow(dead_code)]
+pub async fn commit_async(tx: Transaction) -> NodeResult<()> {
+    tokio::task::spawn_blocking(move || MANAGER.commit(tx))
+        .await
+        .map_err(|e| NodeError::State(format!("State writer task failed: {}", e)))?
+}
+
+// Build and commit a transaction from async code (see `StateManager::write`)
+pub async fn write_async<R, F>(f: F) -> NodeResult<R>
+where
+    R: Send + 'static,
+    F: FnOnce(&NodeState, &mut Transaction) -> NodeResult<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || MANAGER.write(f))
+        .await
+        .map_err(|e| NodeError::State(format!("State writer task failed: {}", e)))?
+}
+
+// Write the current state to a new backup and return its path
+pub fn create_backup() -> NodeResult<PathBuf> {
+    let _writer = MANAGER.lock_writer()?;
+    MANAGER.backup_locked()
+}
+
+// List snapshot backups, newest first
+pub fn list_backups() -> NodeResult<Vec<BackupInfo>> {
+    let backup_dir = get_backup_dir()?;
+
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(&backup_dir)? {
+        let path = entry?.path();
+
+        let is_backup = path.file_name()
+            .and_then(|f| f.to_str())
+            .is_some_and(|name| name.starts_with("state_") && name.ends_with(".json"));
+        if !is_backup {
+            continue;
+        }
+
+        let metadata = fs::metadata(&path)?;
+        let created = metadata.modified()
+            .map(DateTime::<Utc>::from)
+            .unwrap_or_else(|_| Utc::now());
+
+        backups.push(BackupInfo { path, created, size: metadata.len() });
+    }
+
+    // Backup names embed their timestamp, so name order is age order
+    backups.sort_by(|a, b| b.path.cmp(&a.path));
+
+    Ok(backups)
+}
+
+// Remove backups outside the retention policy. Returns the removed paths.
+pub fn prune_backups(policy: &BackupRetention) -> NodeResult<Vec<PathBuf>> {
+    if policy.keep.is_none() && policy.max_age.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let cutoff = policy.max_age.map(|age| Utc::now() - age);
+    let mut removed = Vec::new();
+
+    for (index, backup) in list_backups()?.into_iter().enumerate() {
+        let within_count = policy.keep.is_some_and(|keep| index < keep);
+        let within_age = cutoff.is_some_and(|cutoff| backup.created >= cutoff);
+
+        if within_count || within_age {
+            continue;
+        }
+
+        fs::remove_file(&backup.path)
+            .map_err(|e| NodeError::State(format!("Failed to remove backup {:?}: {}", backup.path, e)))?;
+        debug!("Removed old state backup: {:?}", backup.path);
+        removed.push(backup.path);
+    }
+
+    Ok(removed)
+}
+
+// Resolve a backup given either a path or a file name inside the backup directory
+pub fn resolve_backup(name: &str) -> NodeResult<PathBuf> {
+    let path = PathBuf::from(shellexpand::tilde(name).to_string());
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let in_backup_dir = get_backup_dir()?.join(name);
+    if in_backup_dir.exists() {
+        return Ok(in_backup_dir);
+    }
+
+    Err(NodeError::State(format!("Backup not found: {}", name)))
+}
+
+// Read and verify a backup without touching the live state
+pub fn read_backup(path: &Path) -> NodeResult<NodeState> {
+    let (state, _) = read_snapshot(path)?;
+    Ok(state)
+}
+
+// Replace the live state with the contents of a backup. The current state is
+// backed up first. Returns that pre-restore backup.
+pub fn restore_backup(path: &Path) -> NodeResult<PathBuf> {
+    let restored = read_backup(path)?;
+
+    let _writer = MANAGER.lock_writer()?;
+    let pre_restore_backup = MANAGER.backup_locked()?;
+
+    {
+        let mut state = MANAGER.state_write()?;
+
+        // Keep the log sequence moving forward so no stale log record is ever
+        // replayed on top of the restored state
+        let wal_sequence = state.wal_sequence.max(restored.wal_sequence);
+        *state = restored;
+        state.wal_sequence = wal_sequence;
+        state.reindex();
+    }
+
+    MANAGER.compact_locked()?;
+
+    info!("State restored from backup {:?}", path);
+    Ok(pre_restore_backup)
+}
+
+// Get a copy of the full current state
+pub fn snapshot() -> NodeResult<NodeState> {
+    MANAGER.read(|state| state.clone())
+}
+
+// Compare two states field by field
+pub fn diff(current: &NodeState, other: &NodeState) -> NodeResult<Vec<StateChange>> {
+    let to_map = |state: &NodeState| -> NodeResult<serde_json::Map<String, serde_json::Value>> {
+        match serde_json::to_value(state) {
+            Ok(serde_json::Value::Object(map)) => Ok(map),
+            Ok(_) => Err(NodeError::State("State is not an object".to_string())),
+            Err(e) => Err(NodeError::State(format!("Failed to serialize state: {}", e))),
+        }
+    };
+
+    let current = to_map(current)?;
+    let other = to_map(other)?;
+
+    let mut keys: Vec<&String> = current.keys().chain(other.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    Ok(keys.into_iter()
+        .filter(|key| current.get(*key) != other.get(*key))
+        .map(|key| StateChange {
+            key: key.clone(),
+            current: current.get(key).cloned(),
+            other: other.get(key).cloned(),
+        })
+        .collect())
+}
+
 // Get a value from state
 pub fn get<T: for<'de> Deserialize<'de>>( key: &str) -> NodeResult<T> {
-    let state = STATE.lock()
-        .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))?;
-    
-    let value = serde_json::to_value(&*state)
-        .map_err(|e| NodeError::State(format!("Failed to serialize state: {}", e)))?;
-    
-    let result = value.get(key)
-        .ok_or_else(|| NodeError::State(format!("Key not found in state: {}", key)))?
-        .clone();
-    
+    let state = MANAGER.state_read()?;
+
+    let result = match state.field(key)? {
+        Some(value) => value,
+        None => state.extensions.get(key)
+            .cloned()
+            .ok_or_else(|| NodeError::State(format!("Key not found in state: {}", key)))?,
+    };
+
     serde_json::from_value(result)
         .map_err(|e| NodeError::State(format!("Failed to deserialize state value: {}", e)))
 }
 
-// Set a value in state
+// Set a value in state. Keys that are not core fields are stored as extensions.
 pub fn set<T: Serialize>(key: &str, value: T) -> NodeResult<()> {
-    let mut state = STATE.lock()
-        .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))?;
-    
-    let mut state_value = serde_json::to_value(&*state)
-        .map_err(|e| NodeError::State(format!("Failed to serialize state: {}", e)))?;
-    
-    let value = serde_json::to_value(value)
-        .map_err(|e| NodeError::State(format!("Failed to serialize value: {}", e)))?;
-    
-    if let serde_json::Value::Object(ref mut map) = state_value {
-        map.insert(key.to_string(), value);
-    } else {
-        return Err(NodeError::State("State is not an object".to_string()));
+    let mut tx = Transaction::new();
+    tx.set(key, value)?;
+    commit(tx)
+}
+
+// Check that an extension type may use its namespace
+fn register_extension<T: StateExtension>() -> NodeResult<()> {
+    if CORE_FIELDS.contains(&T::NAMESPACE) || T::NAMESPACE == "extensions" {
+        return Err(NodeError::State(format!(
+            "Extension namespace {} collides with a core state field", T::NAMESPACE
+        )));
     }
-    
-    *state = serde_json::from_value(state_value)
-        .map_err(|e| NodeError::State(format!("Failed to update state: {}", e)))?;
-    
-    save_state()?;
-    
+
+    let mut registered = EXTENSIONS.lock()
+        .map_err(|e| NodeError::State(format!("Failed to lock extension registry: {}", e)))?;
+
+    let type_name = std::any::type_name::<T>();
+    let existing = registered.entry(T::NAMESPACE).or_insert(type_name);
+    if *existing != type_name {
+        return Err(NodeError::State(format!(
+            "Extension namespace {} is already registered for {}", T::NAMESPACE, existing
+        )));
+    }
+
     Ok(())
 }
 
-// Add a DAG vertex
-pub fn add_vertex(vertex: VertexEntry) -> NodeResult<()> {
-    let mut state = STATE.lock()
-        .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))?;
-    
-    state.dag_vertices.push(vertex.clone());
-    
-    // Log the vertex
-    log_vertex(&vertex)?;
-    
-    save_state()?;
-    
-    Ok(())
+// Get the value stored under an extension namespace, if any
+pub fn get_extension<T: StateExtension>() -> NodeResult<Option<T>> {
+    register_extension::<T>()?;
+
+    let state = MANAGER.state_read()?;
+    state.extension()
 }
 
-// Log vertex to dag.log
-fn log_vertex(vertex: &VertexEntry) -> NodeResult<()> {
-    let state_dir = get_state_dir()?;
-    let logs_dir = state_dir.join("logs");
-    let log_file = logs_dir.join("dag.log");
-    
-    // Create logs directory if it doesn't exist
-    fs::create_dir_all(&logs_dir)?;
-    
-    // Append to log file
-    let log_entry = format!(
-        "{} - Vertex ID: {}, Proposal: {}, Hash: {}\n",
-        vertex.timestamp, vertex.id, vertex.proposal_id, vertex.hash
-    );
-    
-    fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(log_file)?
-        .write_all(log_entry.as_bytes())?;
-    
-    Ok(())
+// Store a value under its extension namespace
+pub fn put_extension<T: StateExtension>(value: &T) -> NodeResult<()> {
+    let mut tx = Transaction::new();
+    tx.put_extension(value)?;
+    commit(tx)
+}
+
+// Read-modify-write an extension value while holding the state lock
+pub fn update_extension<T, F>(update: F) -> NodeResult<T>
+where
+    T: StateExtension + Default,
+    F: FnOnce(&mut T),
+{
+    register_extension::<T>()?;
+
+    MANAGER.write(|state, tx| {
+        let mut current: T = state.extension()?.unwrap_or_default();
+
+        update(&mut current);
+
+        tx.put_extension(&current)?;
+        Ok(current)
+    })
+}
+
+// Look up a single vertex that has not been pruned, in the store's index if
+// it keeps one
+pub fn get_vertex(id: &str) -> NodeResult<Option<Vertex>> {
+    let store = MANAGER.store()?;
+    if store.indexed() {
+        return store.get_vertex(id);
+    }
+
+    MANAGER.read(|state| state.vertex(id).cloned())
+}
+
+// Get the vertices recorded for a proposal
+pub fn get_vertices_for_proposal(proposal_id: &str) -> NodeResult<Vec<Vertex>> {
+    MANAGER.read(|state| state.vertices_for_proposal(proposal_id).into_iter().cloned().collect())
+}
+
+// Get the vertices with a content hash
+#[allow(dead_code)]
+pub fn get_vertices_with_hash(hash: &str) -> NodeResult<Vec<Vertex>> {
+    MANAGER.read(|state| state.vertices_with_hash(hash).into_iter().cloned().collect())
+}
+
+// Get the vertices with a timestamp in `range`, oldest first
+pub fn get_vertices_between<R>(range: R) -> NodeResult<Vec<Vertex>>
+where
+    R: RangeBounds<DateTime<Utc>>,
+{
+    MANAGER.read(|state| state.vertices_between(range).into_iter().cloned().collect())
+}
+
+// Check whether a proposal has been executed, in the store's index if it
+// keeps one
+pub fn is_executed(proposal_id: &str) -> NodeResult<bool> {
+    let store = MANAGER.store()?;
+    if store.indexed() {
+        return store.is_executed(proposal_id);
+    }
+
+    MANAGER.read(|state| state.is_executed(proposal_id))
 }
 
 // Get executed proposals
 pub fn get_executed_proposals() -> NodeResult<Vec<String>> {
-    let state = STATE.lock()
-        .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))?;
-    
-    Ok(state.executed_proposals.clone())
+    MANAGER.read(|state| state.executed_proposals.clone())
 }
 
 // Add executed proposal
+#[allow(dead_code)]
 pub fn add_executed_proposal(proposal_id: &str) -> NodeResult<()> {
-    let mut state = STATE.lock()
-        .map_err(|e| NodeError::State(format!("Failed to lock state: {}", e)))?;
-    
-    if !state.executed_proposals.contains(&proposal_id.to_string()) {
-        state.executed_proposals.push(proposal_id.to_string());
+    MANAGER.write(|state, tx| {
+        if !state.is_executed(proposal_id) {
+            tx.add_executed_proposal(proposal_id);
+        }
+
+        Ok(())
+    })
+}
+
+// Record an executed proposal and advance `last_proposal_id` for numeric ids
+pub fn record_execution(proposal_id: &str) -> NodeResult<()> {
+    MANAGER.write(|state, tx| {
+        if !state.is_executed(proposal_id) {
+            tx.add_executed_proposal(proposal_id);
+        }
+
+        if let Ok(numeric_id) = proposal_id.parse::<u64>() {
+            if numeric_id > state.last_proposal_id {
+                tx.set("last_proposal_id", numeric_id)?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    // An in-memory store whose appends can be made to fail
+    struct FlakyStore {
+        inner: store::MemoryStore,
+        failing: Arc<AtomicBool>,
     }
-    
-    save_state()?;
-    
-    Ok(())
-} 
\ No newline at end of file
+
+    impl StateStore for FlakyStore {
+        fn name(&self) -> &'static str {
+            "flaky"
+        }
+
+        fn persistent(&self) -> bool {
+            false
+        }
+
+        fn load(&self) -> NodeResult<Option<NodeState>> {
+            self.inner.load()
+        }
+
+        fn append(&self, records: &[WalRecord]) -> NodeResult<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(NodeError::State("disk full".to_string()));
+            }
+            self.inner.append(records)
+        }
+
+        fn needs_compaction(&self) -> bool {
+            false
+        }
+
+        fn compact(&self, state: &NodeState) -> NodeResult<()> {
+            self.inner.compact(state)
+        }
+    }
+
+    fn flaky_manager() -> (StateManager, Arc<AtomicBool>) {
+        let failing = Arc::new(AtomicBool::new(false));
+        let store = FlakyStore { inner: store::MemoryStore::new(), failing: failing.clone() };
+        store.compact(&NodeState::default()).unwrap();
+
+        let manager = StateManager::new();
+        manager.install(NodeState::default(), Box::new(store)).unwrap();
+        (manager, failing)
+    }
+
+    #[test]
+    #[serial]
+    fn commit_applies_persisted_records() {
+        let (manager, _) = flaky_manager();
+
+        manager.write(|_, tx| {
+            tx.add_executed_proposal("p1");
+            tx.set("last_proposal_id", 3u64)?;
+            Ok(())
+        }).unwrap();
+
+        let (executed, last_proposal_id, sequence) = manager.read(|state| {
+            (state.executed_proposals.clone(), state.last_proposal_id, state.wal_sequence)
+        }).unwrap();
+        assert_eq!(executed, vec!["p1"]);
+        assert_eq!(last_proposal_id, 3);
+        assert_eq!(sequence, 2);
+
+        let stored = manager.store().unwrap().load().unwrap().unwrap();
+        assert_eq!(stored.wal_sequence, 2);
+    }
+
+    #[test]
+    fn looks_up_vertices_by_hash_and_time_range() {
+        let mut state = NodeState::default();
+        let vertices = [
+            ("a", "h1", "2024-01-01T00:00:00Z"),
+            ("b", "h2", "2024-01-02T00:00:00Z"),
+            ("c", "h1", "2024-01-03T00:00:00Z"),
+        ];
+        for (id, hash, timestamp) in vertices {
+            state.dag_vertices.push(serde_json::from_value(serde_json::json!({
+                "id": id, "proposal_id": "1", "timestamp": timestamp, "hash": hash,
+            })).unwrap());
+        }
+        state.reindex();
+
+        let ids = |vertices: Vec<&Vertex>| vertices.into_iter().map(|vertex| vertex.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(state.vertices_with_hash("h1")), vec!["a", "c"]);
+        assert!(state.vertices_with_hash("h3").is_empty());
+
+        let since: DateTime<Utc> = "2024-01-02T00:00:00Z".parse().unwrap();
+        let until: DateTime<Utc> = "2024-01-03T00:00:00Z".parse().unwrap();
+        assert_eq!(ids(state.vertices_between(since..until)), vec!["b"]);
+        assert_eq!(ids(state.vertices_between(since..)), vec!["b", "c"]);
+    }
+
+    #[test]
+    #[serial]
+    fn failed_append_leaves_state_untouched() {
+        let (manager, failing) = flaky_manager();
+        failing.store(true, Ordering::SeqCst);
+
+        let mut tx = Transaction::new();
+        tx.add_executed_proposal("p1");
+        assert!(manager.commit(tx).is_err());
+
+        let (executed, sequence) = manager.read(|state| (state.executed_proposals.clone(), state.wal_sequence)).unwrap();
+        assert!(executed.is_empty());
+        assert_eq!(sequence, 0);
+
+        // The next commit reuses the sequence number of the failed one
+        failing.store(false, Ordering::SeqCst);
+        let mut tx = Transaction::new();
+        tx.add_executed_proposal("p2");
+        manager.commit(tx).unwrap();
+
+        let (executed, sequence) = manager.read(|state| (state.executed_proposals.clone(), state.wal_sequence)).unwrap();
+        assert_eq!(executed, vec!["p2"]);
+        assert_eq!(sequence, 1);
+    }
+
+    #[test]
+    #[serial]
+    fn invalid_batch_is_not_applied() {
+        let (manager, _) = flaky_manager();
+
+        let mut tx = Transaction::new();
+        tx.add_executed_proposal("p1");
+        tx.set("last_proposal_id", "not a number").unwrap();
+        assert!(manager.commit(tx).is_err());
+
+        let executed = manager.read(|state| state.executed_proposals.clone()).unwrap();
+        assert!(executed.is_empty());
+    }
+
+    // A state file as written before schema versions and checksums existed
+    fn legacy_state() -> serde_json::Value {
+        serde_json::json!({
+            "node_id": "node-1",
+            "initialized": "2024-01-01T00:00:00Z",
+            "last_updated": "2024-01-02T00:00:00Z",
+            "last_executed_block": 0,
+            "last_proposal_id": 2,
+            "executed_proposals": ["1", "2"],
+            "active_connection": "",
+            "peers": [],
+            "system_version": "0.1.0",
+            "dag_vertices": [
+                { "id": "a", "proposal_id": "1", "timestamp": "2024-01-01T00:00:00Z", "hash": "h1" },
+                { "id": "b", "proposal_id": "2", "timestamp": "2024-01-02T00:00:00Z", "hash": "h2" }
+            ],
+            "federation_config": { "federation_name": "test" }
+        })
+    }
+
+    #[test]
+    fn migrates_legacy_state_to_current_schema() {
+        let value = migrate(legacy_state(), LEGACY_SCHEMA_VERSION).unwrap();
+        let state: NodeState = serde_json::from_value(value).unwrap();
+
+        assert_eq!(state.wal_sequence, 0);
+        assert_eq!(state.executed_proposals, vec!["1", "2"]);
+        assert!(state.pruned_history.is_none());
+        assert_eq!(state.extensions["federation_config"]["federation_name"], "test");
+
+        // The vertex list was a chain of unsigned vertices
+        assert!(state.dag_vertices[0].parents.is_empty());
+        assert_eq!(state.dag_vertices[1].parents, vec!["a"]);
+        assert!(state.dag_vertices.iter().all(|vertex| vertex.is_legacy()));
+    }
+
+    #[test]
+    fn migration_keeps_values_of_later_schemas() {
+        let mut value = legacy_state();
+        value["wal_sequence"] = 9.into();
+        value["dag_vertices"][1]["parents"] = serde_json::json!([]);
+
+        let state: NodeState = serde_json::from_value(migrate(value, 2).unwrap()).unwrap();
+        assert_eq!(state.wal_sequence, 9);
+        assert!(state.dag_vertices[1].parents.is_empty());
+    }
+
+    #[test]
+    fn migration_rejects_unknown_versions() {
+        assert!(migrate(legacy_state(), STATE_SCHEMA_VERSION + 1).is_err());
+        assert!(migrate(legacy_state(), 0).is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn reads_legacy_and_checksummed_snapshots() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("state.json");
+
+        fs::write(&path, serde_json::to_vec(&legacy_state()).unwrap()).unwrap();
+        let (state, version) = read_snapshot(&path).unwrap();
+        assert_eq!(version, LEGACY_SCHEMA_VERSION);
+        assert_eq!(state.node_id, "node-1");
+
+        fs::write(&path, encode_snapshot(&state).unwrap()).unwrap();
+        let (reread, version) = read_snapshot(&path).unwrap();
+        assert_eq!(version, STATE_SCHEMA_VERSION);
+        assert_eq!(reread.dag_vertices.len(), 2);
+
+        // Any change to the state breaks the checksum
+        let tampered = String::from_utf8(fs::read(&path).unwrap()).unwrap().replace("node-1", "node-2");
+        fs::write(&path, tampered).unwrap();
+        let err = read_snapshot(&path).unwrap_err();
+        assert!(err.to_string().contains("Checksum mismatch"), "{}", err);
+    }
+}
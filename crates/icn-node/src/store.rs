@@ -0,0 +1,911 @@
+use crate::crypto;
+use crate::error::{NodeError, NodeResult};
+use crate::state::{self, NodeState, StateOp, WalRecord};
+use crate::vertex::Vertex;
+use chrono::Utc;
+use sled::Transactional;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::{debug, error, info, warn};
+
+// Number of log records after which the log is compacted into a new snapshot
+const SNAPSHOT_INTERVAL: u64 = 1000;
+
+// Keys of the sled metadata tree that are not core state fields
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+const EXTENSION_PREFIX: &str = "ext:";
+
+// Where node state is persisted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum StoreBackend {
+    /// `state.json` snapshot plus `state.wal` write-ahead log
+    #[default]
+    Json,
+    /// Embedded transactional key-value database in `state.db`
+    Sled,
+    /// Process memory only, for tests
+    Memory,
+}
+
+// Persistence behind the state manager. The manager owns the in-memory state,
+// which holds every unpruned vertex, and serializes writers; a store has to
+// load that state and make committed records durable. A store that keeps
+// vertices and executed proposals indexed on disk also answers lookups of
+// single entries, so they need no copy of the state.
+pub trait StateStore: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // Whether anything is written outside the process
+    fn persistent(&self) -> bool {
+        true
+    }
+
+    // Load the persisted state, or `None` if nothing has been stored yet
+    fn load(&self) -> NodeResult<Option<NodeState>>;
+
+    // Persist records before they are applied to the in-memory state. On
+    // error the records are discarded, so nothing may be left half written.
+    fn append(&self, records: &[WalRecord]) -> NodeResult<()>;
+
+    // Whether the store wants to be rewritten from the full state
+    fn needs_compaction(&self) -> bool;
+
+    // Replace everything stored with the given state
+    fn compact(&self, state: &NodeState) -> NodeResult<()>;
+
+    // Whether `get_vertex` and `is_executed` are answered from indexes on
+    // disk. Other stores leave these lookups to the in-memory state.
+    fn indexed(&self) -> bool {
+        false
+    }
+
+    // Look up a stored vertex by id
+    fn get_vertex(&self, _id: &str) -> NodeResult<Option<Vertex>> {
+        Err(NodeError::State(format!("The {} state store keeps no vertex index", self.name())))
+    }
+
+    // Whether a proposal is stored as executed
+    fn is_executed(&self, _proposal_id: &str) -> NodeResult<bool> {
+        Err(NodeError::State(format!("The {} state store keeps no executed proposal index", self.name())))
+    }
+}
+
+// Open the store for a backend under the configured data directory
+pub fn open(backend: StoreBackend) -> NodeResult<Box<dyn StateStore>> {
+    let store: Box<dyn StateStore> = match backend {
+        StoreBackend::Json => Box::new(JsonFileStore::new(state::get_state_file()?, state::get_wal_file()?)),
+        StoreBackend::Sled => Box::new(SledStore::open(&state::get_database_dir()?)?),
+        StoreBackend::Memory => Box::new(MemoryStore::new()),
+    };
+
+    Ok(store)
+}
+
+impl fmt::Display for StoreBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            StoreBackend::Json => "json",
+            StoreBackend::Sled => "sled",
+            StoreBackend::Memory => "memory",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for StoreBackend {
+    type Err = NodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(StoreBackend::Json),
+            "sled" => Ok(StoreBackend::Sled),
+            "memory" => Ok(StoreBackend::Memory),
+            _ => Err(NodeError::Config(format!(
+                "Unknown state backend {} (expected json, sled or memory)", s
+            ))),
+        }
+    }
+}
+
+// Stores the state as a checksummed `state.json` snapshot plus a write-ahead
+// log of JSON lines that is folded into a new snapshot every
+// `SNAPSHOT_INTERVAL` records
+pub struct JsonFileStore {
+    state_file: PathBuf,
+    wal_file: PathBuf,
+    // Number of records appended to the write-ahead log since the last snapshot
+    wal_records: AtomicU64,
+    // Set when the files on disk must be rewritten before new records are appended
+    dirty: AtomicBool,
+}
+
+impl JsonFileStore {
+    pub fn new(state_file: PathBuf, wal_file: PathBuf) -> Self {
+        Self {
+            state_file,
+            wal_file,
+            wal_records: AtomicU64::new(0),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    // Read the snapshot, backing it up first if it has to be migrated
+    fn load_snapshot(&self) -> NodeResult<NodeState> {
+        let (state, schema_version) = state::read_snapshot(&self.state_file)?;
+
+        if schema_version < state::STATE_SCHEMA_VERSION {
+            let backup_file = state::backup_before_migration(&self.state_file, schema_version)?;
+            info!(
+                "Migrated state file from schema version {} to {} (original saved to {:?})",
+                schema_version, state::STATE_SCHEMA_VERSION, backup_file
+            );
+
+            // Rewrite the file in the current layout
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+
+        Ok(state)
+    }
+
+    // Load the newest valid backup after the primary file failed to load
+    fn recover_from_backup(&self) -> NodeResult<NodeState> {
+        for backup in state::list_backups()? {
+            let backup = &backup.path;
+            let (state, _) = match state::read_snapshot(backup) {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    warn!("Skipping unusable backup {:?}: {}", backup, e);
+                    continue;
+                }
+            };
+
+            // Keep the damaged file around for inspection before it is replaced
+            let corrupt_file = self.state_file.with_extension(
+                format!("json.corrupt-{}", Utc::now().format("%Y%m%d_%H%M%S"))
+            );
+            fs::copy(&self.state_file, &corrupt_file)?;
+
+            warn!(
+                "Recovered node state from backup {:?}: snapshot sequence {}, {} DAG vertices, \
+                 {} executed proposals; damaged file copied to {:?}",
+                backup, state.wal_sequence, state.dag_vertices.len(),
+                state.executed_proposals.len(), corrupt_file
+            );
+
+            // Persist the recovered state as the new primary snapshot
+            self.dirty.store(true, Ordering::SeqCst);
+            return Ok(state);
+        }
+
+        Err(NodeError::State(format!(
+            "State file {:?} is unreadable and no valid backup was found in {:?}",
+            self.state_file, state::get_backup_dir()?
+        )))
+    }
+
+    // Replay write-ahead log records newer than the snapshot into the given state.
+    // Returns the number of records applied and whether a torn record was dropped.
+    fn replay_wal(&self, state: &mut NodeState) -> NodeResult<(u64, bool)> {
+        if !self.wal_file.exists() {
+            return Ok((0, false));
+        }
+
+        let file = File::open(&self.wal_file)
+            .map_err(|e| NodeError::State(format!("Failed to open state log: {}", e)))?;
+
+        let mut lines = BufReader::new(file).lines().enumerate().peekable();
+        let mut replayed = 0;
+        let mut torn = false;
+
+        while let Some((index, line)) = lines.next() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let parsed = crypto::open(line.as_bytes())
+                .and_then(|plain| serde_json::from_slice::<WalRecord>(&plain).map_err(NodeError::from));
+
+            let record = match parsed {
+                Ok(record) => record,
+                // A torn final record is the expected result of a crash mid-append
+                Err(e) if lines.peek().is_none() && crypto::has_key_for(line.as_bytes()) => {
+                    warn!("Ignoring incomplete trailing state log record at line {}: {}", index + 1, e);
+                    torn = true;
+                    break;
+                }
+                Err(e) => {
+                    return Err(NodeError::State(format!(
+                        "Corrupt state log record at line {}: {}", index + 1, e
+                    )));
+                }
+            };
+
+            // Records already folded into the snapshot are skipped
+            if record.seq <= state.wal_sequence {
+                continue;
+            }
+
+            // Happens when the snapshot was restored from an older backup
+            if record.seq > state.wal_sequence + 1 {
+                warn!(
+                    "State log records {}..{} are missing; continuing from record {}",
+                    state.wal_sequence + 1, record.seq - 1, record.seq
+                );
+            }
+
+            state.apply_record(&record)?;
+            replayed += 1;
+        }
+
+        Ok((replayed, torn))
+    }
+}
+
+impl StateStore for JsonFileStore {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn load(&self) -> NodeResult<Option<NodeState>> {
+        if !self.state_file.exists() {
+            return Ok(None);
+        }
+
+        let mut state = match self.load_snapshot() {
+            Ok(state) => state,
+            Err(e) => {
+                // A file from a newer build is intact; falling back to an older
+                // backup would silently discard its history
+                let version = state::peek_schema_version(&self.state_file);
+                if version.is_some_and(|v| v > state::STATE_SCHEMA_VERSION) {
+                    return Err(e);
+                }
+
+                // Likewise a file sealed with a key we don't have is not damaged
+                let data = fs::read(&self.state_file)?;
+                if !crypto::has_key_for(&data) {
+                    return Err(e);
+                }
+
+                error!("Primary state file is unreadable: {}", e);
+                self.recover_from_backup()?
+            }
+        };
+
+        let (replayed, torn) = self.replay_wal(&mut state)?;
+        if replayed > 0 {
+            info!("Replayed {} state log records on top of snapshot", replayed);
+        }
+
+        // Fold a long or damaged log into a fresh snapshot right away, so that
+        // new records are never appended behind a torn one
+        self.wal_records.store(replayed, Ordering::SeqCst);
+        if torn {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+
+        Ok(Some(state))
+    }
+
+    fn append(&self, records: &[WalRecord]) -> NodeResult<()> {
+        let mut lines = String::new();
+        for record in records {
+            let line = serde_json::to_vec(record)
+                .map_err(|e| NodeError::State(format!("Failed to serialize state log record: {}", e)))?;
+            lines.push_str(&String::from_utf8_lossy(&crypto::seal(&line)?));
+            lines.push('\n');
+        }
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.wal_file)
+            .map_err(|e| NodeError::State(format!("Failed to open state log: {}", e)))?;
+
+        // Cut a partial write off again, so the next append does not land
+        // behind a torn record
+        let length = file.metadata()?.len();
+        if let Err(e) = file.write_all(lines.as_bytes()).and_then(|_| file.sync_data()) {
+            if let Err(truncate_error) = file.set_len(length) {
+                error!("Failed to remove partial state log records: {}", truncate_error);
+                self.dirty.store(true, Ordering::SeqCst);
+            }
+            return Err(NodeError::State(format!("Failed to append to state log: {}", e)));
+        }
+
+        self.wal_records.fetch_add(records.len() as u64, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst) || self.wal_records.load(Ordering::SeqCst) >= SNAPSHOT_INTERVAL
+    }
+
+    fn compact(&self, state: &NodeState) -> NodeResult<()> {
+        let content = state::encode_snapshot(state)?;
+
+        state::write_atomic(&self.state_file, &crypto::seal(&content)?)
+            .map_err(|e| NodeError::State(format!("Failed to write state file: {}", e)))?;
+
+        // Every record up to `wal_sequence` is now part of the snapshot, so the
+        // log can start over. Records left behind by a crash before this point
+        // are skipped on replay by their sequence number.
+        File::create(&self.wal_file)
+            .map_err(|e| NodeError::State(format!("Failed to truncate state log: {}", e)))?;
+
+        self.wal_records.store(0, Ordering::SeqCst);
+        self.dirty.store(false, Ordering::SeqCst);
+
+        Ok(())
+    }
+}
+
+// Stores the state in an embedded sled database. Scalar fields and extensions
+// live in a metadata tree; vertices and executed proposals each get a tree
+// keyed by position plus an index tree keyed by id, so appends skip known
+// entries and single vertices and executed proposals are looked up without
+// reading the trees back. Every commit is a single multi-tree transaction.
+pub struct SledStore {
+    db: sled::Db,
+    meta: sled::Tree,
+    vertices: sled::Tree,
+    vertex_index: sled::Tree,
+    executed: sled::Tree,
+    executed_index: sled::Tree,
+    // Set when the database must be rewritten in the current layout
+    dirty: AtomicBool,
+}
+
+// Pending writes for each tree of a `SledStore`
+#[derive(Default)]
+struct SledBatches {
+    meta: sled::Batch,
+    vertices: sled::Batch,
+    vertex_index: sled::Batch,
+    executed: sled::Batch,
+    executed_index: sled::Batch,
+}
+
+fn db_error(e: sled::Error) -> NodeError {
+    NodeError::State(format!("State database error: {}", e))
+}
+
+fn position_key(position: u64) -> Vec<u8> {
+    position.to_be_bytes().to_vec()
+}
+
+// Values are sealed individually; keys stay in plaintext so the indexes work
+fn to_json<T: serde::Serialize>(value: &T) -> NodeResult<Vec<u8>> {
+    let json = serde_json::to_vec(value)
+        .map_err(|e| NodeError::State(format!("Failed to serialize state database value: {}", e)))?;
+    crypto::seal(&json)
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> NodeResult<T> {
+    serde_json::from_slice(&crypto::open(bytes)?)
+        .map_err(|e| NodeError::State(format!("Failed to parse state database value: {}", e)))
+}
+
+// Metadata key for a core field or extension namespace
+fn meta_key(key: &str) -> String {
+    if state::CORE_FIELDS.contains(&key) {
+        key.to_string()
+    } else {
+        format!("{}{}", EXTENSION_PREFIX, key)
+    }
+}
+
+impl SledStore {
+    pub fn open(path: &Path) -> NodeResult<Self> {
+        let db = sled::open(path)
+            .map_err(|e| NodeError::State(format!("Failed to open state database {:?}: {}", path, e)))?;
+
+        Ok(Self {
+            meta: db.open_tree("meta").map_err(db_error)?,
+            vertices: db.open_tree("vertices").map_err(db_error)?,
+            vertex_index: db.open_tree("vertex_index").map_err(db_error)?,
+            executed: db.open_tree("executed").map_err(db_error)?,
+            executed_index: db.open_tree("executed_index").map_err(db_error)?,
+            db,
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    // Position after the last entry of a positional tree
+    fn next_position(tree: &sled::Tree) -> NodeResult<u64> {
+        match tree.last().map_err(db_error)? {
+            Some((key, _)) => {
+                let bytes: [u8; 8] = key.as_ref().try_into()
+                    .map_err(|_| NodeError::State("Invalid position key in state database".to_string()))?;
+                Ok(u64::from_be_bytes(bytes) + 1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Collect every value of a positional tree in order
+    fn values(tree: &sled::Tree) -> NodeResult<Vec<serde_json::Value>> {
+        tree.iter()
+            .values()
+            .map(|value| from_json(&value.map_err(db_error)?))
+            .collect()
+    }
+
+    // Apply all batches in one transaction and wait until it is on disk
+    fn apply(&self, batches: SledBatches) -> NodeResult<()> {
+        let trees = (&self.meta, &self.vertices, &self.vertex_index, &self.executed, &self.executed_index);
+
+        trees.transaction(|(meta, vertices, vertex_index, executed, executed_index)| {
+            meta.apply_batch(&batches.meta)?;
+            vertices.apply_batch(&batches.vertices)?;
+            vertex_index.apply_batch(&batches.vertex_index)?;
+            executed.apply_batch(&batches.executed)?;
+            executed_index.apply_batch(&batches.executed_index)?;
+            Ok::<(), sled::transaction::ConflictableTransactionError<sled::Error>>(())
+        })
+        .map_err(|e| NodeError::State(format!("State database transaction failed: {}", e)))?;
+
+        self.db.flush().map_err(db_error)?;
+        Ok(())
+    }
+}
+
+// Appends entries to a positional tree and its id index, skipping ids that
+// are already stored or were added earlier in the same batch
+struct PositionalAppend<'a> {
+    tree: &'a sled::Tree,
+    index: &'a sled::Tree,
+    values: sled::Batch,
+    index_entries: sled::Batch,
+    next: u64,
+    added: HashSet<String>,
+    // Set once the stored entries are dropped by `clear`
+    cleared: bool,
+}
+
+impl<'a> PositionalAppend<'a> {
+    fn new(tree: &'a sled::Tree, index: &'a sled::Tree) -> NodeResult<Self> {
+        Ok(Self {
+            tree,
+            index,
+            values: sled::Batch::default(),
+            index_entries: sled::Batch::default(),
+            next: SledStore::next_position(tree)?,
+            added: HashSet::new(),
+            cleared: false,
+        })
+    }
+
+    fn push(&mut self, id: &str, value: Vec<u8>) -> NodeResult<()> {
+        let stored = !self.cleared && self.index.contains_key(id.as_bytes()).map_err(db_error)?;
+        if stored || !self.added.insert(id.to_string()) {
+            return Ok(());
+        }
+
+        self.values.insert(position_key(self.next), value);
+        self.index_entries.insert(id.as_bytes(), position_key(self.next));
+        self.next += 1;
+        Ok(())
+    }
+
+    // Drop every stored entry and everything pushed so far
+    fn clear(&mut self) -> NodeResult<()> {
+        for key in self.tree.iter().keys() {
+            self.values.remove(key.map_err(db_error)?);
+        }
+        for key in self.index.iter().keys() {
+            self.index_entries.remove(key.map_err(db_error)?);
+        }
+        for position in 0..self.next {
+            self.values.remove(position_key(position));
+        }
+        for id in self.added.drain() {
+            self.index_entries.remove(id.as_bytes());
+        }
+
+        self.next = 0;
+        self.cleared = true;
+        Ok(())
+    }
+
+    fn into_batches(self) -> (sled::Batch, sled::Batch) {
+        (self.values, self.index_entries)
+    }
+}
+
+impl StateStore for SledStore {
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn load(&self) -> NodeResult<Option<NodeState>> {
+        if self.meta.is_empty() {
+            return Ok(None);
+        }
+
+        let mut map = serde_json::Map::new();
+        let mut extensions = serde_json::Map::new();
+        let mut schema_version = state::LEGACY_SCHEMA_VERSION;
+
+        for entry in self.meta.iter() {
+            let (key, value) = entry.map_err(db_error)?;
+            let key = String::from_utf8_lossy(&key).into_owned();
+            let value: serde_json::Value = from_json(&value)?;
+
+            if key == SCHEMA_VERSION_KEY {
+                schema_version = value.as_u64()
+                    .ok_or_else(|| NodeError::State("Invalid schema version in state database".to_string()))?
+                    as u32;
+            } else if let Some(namespace) = key.strip_prefix(EXTENSION_PREFIX) {
+                extensions.insert(namespace.to_string(), value);
+            } else {
+                map.insert(key, value);
+            }
+        }
+
+        map.insert("dag_vertices".to_string(), serde_json::Value::Array(Self::values(&self.vertices)?));
+        map.insert("executed_proposals".to_string(), serde_json::Value::Array(Self::values(&self.executed)?));
+        map.insert("extensions".to_string(), serde_json::Value::Object(extensions));
+
+        let value = state::migrate(serde_json::Value::Object(map), schema_version)
+            .map_err(|e| NodeError::State(format!("State database: {}", e)))?;
+
+        if schema_version < state::STATE_SCHEMA_VERSION {
+            info!(
+                "Migrated state database from schema version {} to {}",
+                schema_version, state::STATE_SCHEMA_VERSION
+            );
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+
+        let mut state: NodeState = serde_json::from_value(value)
+            .map_err(|e| NodeError::State(format!("Failed to parse state database: {}", e)))?;
+        state.reindex();
+
+        Ok(Some(state))
+    }
+
+    fn append(&self, records: &[WalRecord]) -> NodeResult<()> {
+        let last = match records.last() {
+            Some(record) => record,
+            None => return Ok(()),
+        };
+
+        let mut meta = sled::Batch::default();
+        let mut vertices = PositionalAppend::new(&self.vertices, &self.vertex_index)?;
+        let mut executed = PositionalAppend::new(&self.executed, &self.executed_index)?;
+
+        for record in records {
+            match &record.op {
+                StateOp::AddVertex { vertex } => vertices.push(&vertex.id, to_json(vertex)?)?,
+                StateOp::AddExecutedProposal { proposal_id } => executed.push(proposal_id, to_json(proposal_id)?)?,
+                // Replacing a whole collection rewrites its tree
+                StateOp::Set { key, value } if key == "dag_vertices" => {
+                    let replacement: Vec<Vertex> = serde_json::from_value(value.clone())
+                        .map_err(|e| NodeError::State(format!("Invalid dag_vertices value: {}", e)))?;
+                    vertices.clear()?;
+                    for vertex in &replacement {
+                        vertices.push(&vertex.id, to_json(vertex)?)?;
+                    }
+                }
+                StateOp::Set { key, value } if key == "executed_proposals" => {
+                    let replacement: Vec<String> = serde_json::from_value(value.clone())
+                        .map_err(|e| NodeError::State(format!("Invalid executed_proposals value: {}", e)))?;
+                    executed.clear()?;
+                    for proposal_id in &replacement {
+                        executed.push(proposal_id, to_json(proposal_id)?)?;
+                    }
+                }
+                StateOp::Set { key, value } => {
+                    meta.insert(meta_key(key).as_bytes(), to_json(value)?);
+                }
+            }
+        }
+
+        meta.insert("wal_sequence", to_json(&last.seq)?);
+        meta.insert("last_updated", to_json(&last.timestamp)?);
+
+        let (vertices, vertex_index) = vertices.into_batches();
+        let (executed, executed_index) = executed.into_batches();
+        self.apply(SledBatches { meta, vertices, vertex_index, executed, executed_index })
+    }
+
+    fn needs_compaction(&self) -> bool {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    fn indexed(&self) -> bool {
+        true
+    }
+
+    fn get_vertex(&self, id: &str) -> NodeResult<Option<Vertex>> {
+        let position = match self.vertex_index.get(id.as_bytes()).map_err(db_error)? {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+
+        self.vertices.get(position).map_err(db_error)?
+            .map(|value| from_json(&value))
+            .transpose()
+    }
+
+    fn is_executed(&self, proposal_id: &str) -> NodeResult<bool> {
+        self.executed_index.contains_key(proposal_id.as_bytes()).map_err(db_error)
+    }
+
+    fn compact(&self, state: &NodeState) -> NodeResult<()> {
+        let mut batches = SledBatches::default();
+
+        // Clear every tree first; inserts below override removals of the same key
+        let clears = [
+            (&self.meta, &mut batches.meta),
+            (&self.vertices, &mut batches.vertices),
+            (&self.vertex_index, &mut batches.vertex_index),
+            (&self.executed, &mut batches.executed),
+            (&self.executed_index, &mut batches.executed_index),
+        ];
+        for (tree, batch) in clears {
+            for key in tree.iter().keys() {
+                batch.remove(key.map_err(db_error)?);
+            }
+        }
+
+        batches.meta.insert(SCHEMA_VERSION_KEY, to_json(&state::STATE_SCHEMA_VERSION)?);
+
+        for key in state::CORE_FIELDS {
+            if *key == "dag_vertices" || *key == "executed_proposals" {
+                continue;
+            }
+
+            if let Some(value) = state.field(key)? {
+                batches.meta.insert(meta_key(key).as_bytes(), to_json(&value)?);
+            }
+        }
+
+        for (namespace, value) in &state.extensions {
+            batches.meta.insert(meta_key(namespace).as_bytes(), to_json(value)?);
+        }
+
+        for (position, vertex) in state.dag_vertices.iter().enumerate() {
+            batches.vertices.insert(position_key(position as u64), to_json(vertex)?);
+            batches.vertex_index.insert(vertex.id.as_bytes(), position_key(position as u64));
+        }
+
+        for (position, proposal_id) in state.executed_proposals.iter().enumerate() {
+            batches.executed.insert(position_key(position as u64), to_json(proposal_id)?);
+            batches.executed_index.insert(proposal_id.as_bytes(), position_key(position as u64));
+        }
+
+        self.apply(batches)?;
+        self.dirty.store(false, Ordering::SeqCst);
+
+        debug!("Rewrote state database at log sequence {}", state.wal_sequence);
+        Ok(())
+    }
+}
+
+// Keeps the last committed state in memory only, so tests never touch the
+// filesystem
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<Option<NodeState>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_state<R>(&self, f: impl FnOnce(&Option<NodeState>) -> R) -> NodeResult<R> {
+        let state = self.state.lock()
+            .map_err(|e| NodeError::State(format!("Failed to lock memory store: {}", e)))?;
+        Ok(f(&state))
+    }
+
+    fn replace(&self, state: &NodeState) -> NodeResult<()> {
+        *self.state.lock()
+            .map_err(|e| NodeError::State(format!("Failed to lock memory store: {}", e)))? = Some(state.clone());
+        Ok(())
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn persistent(&self) -> bool {
+        false
+    }
+
+    fn load(&self) -> NodeResult<Option<NodeState>> {
+        self.with_state(|state| state.clone())
+    }
+
+    fn append(&self, records: &[WalRecord]) -> NodeResult<()> {
+        let mut state = self.state.lock()
+            .map_err(|e| NodeError::State(format!("Failed to lock memory store: {}", e)))?;
+        let state = state.as_mut()
+            .ok_or_else(|| NodeError::State("Memory store holds no state to append to".to_string()))?;
+
+        for record in records {
+            state.apply_record(record)?;
+        }
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        false
+    }
+
+    fn compact(&self, state: &NodeState) -> NodeResult<()> {
+        self.replace(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use tempfile::TempDir;
+
+    fn record(seq: u64, op: StateOp) -> WalRecord {
+        WalRecord { seq, timestamp: Utc::now(), op }
+    }
+
+    fn executed(seq: u64, proposal_id: &str) -> WalRecord {
+        record(seq, StateOp::AddExecutedProposal { proposal_id: proposal_id.to_string() })
+    }
+
+    fn legacy_vertex(id: &str) -> Vertex {
+        serde_json::from_value(serde_json::json!({
+            "version": crate::vertex::LEGACY_VERTEX_FORMAT_VERSION,
+            "id": id,
+            "proposal_id": "proposal-1",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "hash": "abc",
+        }))
+        .unwrap()
+    }
+
+    fn json_store(dir: &TempDir) -> JsonFileStore {
+        JsonFileStore::new(dir.path().join("state.json"), dir.path().join("state.wal"))
+    }
+
+    // A store holding a freshly compacted default state
+    fn compacted_json_store(dir: &TempDir) -> JsonFileStore {
+        let store = json_store(dir);
+        store.compact(&NodeState::default()).unwrap();
+        store
+    }
+
+    #[test]
+    #[serial]
+    fn json_store_replays_log_on_top_of_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let store = compacted_json_store(&dir);
+
+        store.append(&[executed(1, "p1"), executed(2, "p2")]).unwrap();
+        store.append(&[record(3, StateOp::Set { key: "last_proposal_id".to_string(), value: 7.into() })]).unwrap();
+
+        let state = json_store(&dir).load().unwrap().unwrap();
+        assert_eq!(state.executed_proposals, vec!["p1", "p2"]);
+        assert_eq!(state.last_proposal_id, 7);
+        assert_eq!(state.wal_sequence, 3);
+    }
+
+    #[test]
+    #[serial]
+    fn json_store_skips_records_already_in_snapshot() {
+        let dir = TempDir::new().unwrap();
+        let store = json_store(&dir);
+
+        let mut state = NodeState::default();
+        state.apply_record(&executed(1, "p1")).unwrap();
+        store.compact(&state).unwrap();
+
+        // A crash between the snapshot and truncating the log leaves old records behind
+        fs::write(dir.path().join("state.wal"), format!("{}\n", serde_json::to_string(&executed(1, "p1")).unwrap())).unwrap();
+        store.append(&[executed(2, "p2")]).unwrap();
+
+        let state = json_store(&dir).load().unwrap().unwrap();
+        assert_eq!(state.executed_proposals, vec!["p1", "p2"]);
+        assert_eq!(state.wal_sequence, 2);
+    }
+
+    #[test]
+    #[serial]
+    fn json_store_drops_torn_trailing_record() {
+        let dir = TempDir::new().unwrap();
+        let store = compacted_json_store(&dir);
+        store.append(&[executed(1, "p1")]).unwrap();
+
+        let mut wal = fs::OpenOptions::new().append(true).open(dir.path().join("state.wal")).unwrap();
+        wal.write_all(br#"{"seq":2,"timestamp":"2024-01-01T00:00:00Z","op":"add_exec"#).unwrap();
+
+        let reopened = json_store(&dir);
+        let state = reopened.load().unwrap().unwrap();
+        assert_eq!(state.executed_proposals, vec!["p1"]);
+        assert_eq!(state.wal_sequence, 1);
+
+        // The torn record is folded away before anything is appended behind it
+        assert!(reopened.needs_compaction());
+    }
+
+    #[test]
+    #[serial]
+    fn json_store_rejects_corrupt_record_before_the_end() {
+        let dir = TempDir::new().unwrap();
+        let store = compacted_json_store(&dir);
+
+        fs::write(dir.path().join("state.wal"), "not a record\n").unwrap();
+        store.append(&[executed(1, "p1")]).unwrap();
+
+        let err = json_store(&dir).load().unwrap_err();
+        assert!(err.to_string().contains("Corrupt state log record at line 1"), "{}", err);
+    }
+
+    #[test]
+    #[serial]
+    fn json_store_compaction_truncates_log() {
+        let dir = TempDir::new().unwrap();
+        let store = compacted_json_store(&dir);
+        store.append(&[executed(1, "p1")]).unwrap();
+
+        let mut state = json_store(&dir).load().unwrap().unwrap();
+        state.apply_record(&executed(2, "p2")).unwrap();
+        store.compact(&state).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.path().join("state.wal")).unwrap(), "");
+        let state = json_store(&dir).load().unwrap().unwrap();
+        assert_eq!(state.executed_proposals, vec!["p1", "p2"]);
+    }
+
+    #[test]
+    #[serial]
+    fn sled_store_appends_and_replaces_collections() {
+        let dir = TempDir::new().unwrap();
+        let store = SledStore::open(&dir.path().join("state.db")).unwrap();
+        store.compact(&NodeState::default()).unwrap();
+
+        store.append(&[
+            record(1, StateOp::AddVertex { vertex: Box::new(legacy_vertex("v1")) }),
+            record(2, StateOp::AddVertex { vertex: Box::new(legacy_vertex("v1")) }),
+            executed(3, "p1"),
+        ]).unwrap();
+        store.append(&[
+            record(4, StateOp::Set { key: "executed_proposals".to_string(), value: serde_json::json!(["p2", "p3"]) }),
+            executed(5, "p1"),
+        ]).unwrap();
+
+        let state = store.load().unwrap().unwrap();
+        assert_eq!(state.dag_vertices.len(), 1);
+        assert_eq!(state.executed_proposals, vec!["p2", "p3", "p1"]);
+        assert_eq!(state.wal_sequence, 5);
+        assert_eq!(store.get_vertex("v1").unwrap().unwrap().id, "v1");
+        assert!(store.get_vertex("v2").unwrap().is_none());
+        assert!(store.is_executed("p3").unwrap());
+        assert!(!store.is_executed("p4").unwrap());
+    }
+
+    #[test]
+    fn memory_store_applies_appended_records() {
+        let store = MemoryStore::new();
+        assert!(store.append(&[executed(1, "p1")]).is_err());
+
+        store.compact(&NodeState::default()).unwrap();
+        store.append(&[executed(1, "p1")]).unwrap();
+
+        let state = store.load().unwrap().unwrap();
+        assert_eq!(state.executed_proposals, vec!["p1"]);
+        assert_eq!(state.wal_sequence, 1);
+    }
+}
@@ -0,0 +1,35 @@
+use std::ffi::OsString;
+use std::path::Path;
+use tempfile::TempDir;
+
+const DATA_DIR_VAR: &str = "ICN_DATA_DIR";
+
+// A fresh data directory the node is pointed at through `ICN_DATA_DIR`. The
+// variable is shared by the whole process, so tests using one must be
+// `#[serial]`; its previous value is put back before the directory is removed.
+pub struct DataDir {
+    dir: TempDir,
+    previous: Option<OsString>,
+}
+
+impl DataDir {
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl Drop for DataDir {
+    fn drop(&mut self) {
+        match self.previous.take() {
+            Some(previous) => std::env::set_var(DATA_DIR_VAR, previous),
+            None => std::env::remove_var(DATA_DIR_VAR),
+        }
+    }
+}
+
+pub fn data_dir() -> DataDir {
+    let dir = TempDir::new().unwrap();
+    let previous = std::env::var_os(DATA_DIR_VAR);
+    std::env::set_var(DATA_DIR_VAR, dir.path());
+    DataDir { dir, previous }
+}
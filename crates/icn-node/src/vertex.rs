@@ -0,0 +1,216 @@
+use crate::payload::{self, VertexPayload};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Layout version of vertices written by this build
+pub const VERTEX_FORMAT_VERSION: u32 = 2;
+
+// Vertices written before the layout was versioned. Unsigned vertices from
+// then keep this version when the state is migrated, which marks them as
+// legacy (see `Vertex::is_legacy` and `Vertex::migrate`).
+pub const LEGACY_VERTEX_FORMAT_VERSION: u32 = 1;
+
+// A DAG vertex. This is the one vertex model used by the state, the
+// executor, federation sync, DAG archives and the CLI. It is written in the
+// current layout, except for legacy vertices; older layouts are converted
+// when read (see `VertexRecord`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "VertexRecord")]
+pub struct Vertex {
+    // Layout version (see `VERTEX_FORMAT_VERSION`)
+    pub version: u32,
+    pub id: String,
+    pub proposal_id: String,
+    pub timestamp: DateTime<Utc>,
+    // Content hash: the SHA-256 of the proposal file for executions, of the payload otherwise
+    pub hash: String,
+    // Ids of the vertices this one extends (the tips when it was recorded)
+    pub parents: Vec<String>,
+    // Node that recorded the vertex
+    pub submitter: String,
+    // Hash of the execution result (see `executor::result_hash`), checked by replay
+    pub result_hash: String,
+    // Ed25519 key of the submitter and its signature over the vertex (base64)
+    pub public_key: String,
+    pub signature: String,
+    // What the vertex records; absent on vertices from before payloads were typed
+    pub payload: Option<VertexPayload>,
+}
+
+// A vertex in any layout it has been written in. Vertices from before the
+// layout was versioned carry no version and, depending on their age, no
+// parents, submitter, result hash, signature or payload; those fields read
+// as empty.
+#[derive(Debug, Deserialize)]
+struct VertexRecord {
+    #[serde(default)]
+    version: Option<u32>,
+    id: String,
+    proposal_id: String,
+    timestamp: DateTime<Utc>,
+    hash: String,
+    #[serde(default)]
+    parents: Vec<String>,
+    #[serde(default)]
+    submitter: String,
+    #[serde(default)]
+    result_hash: String,
+    #[serde(default)]
+    public_key: String,
+    #[serde(default)]
+    signature: String,
+    #[serde(default)]
+    payload: Option<VertexPayload>,
+}
+
+impl TryFrom<VertexRecord> for Vertex {
+    type Error = String;
+
+    // A record without a version is read in the current layout, so its id
+    // and signature are verified like any other. Only the state migration
+    // may mark such a record as legacy (see `Vertex::migrate`).
+    fn try_from(record: VertexRecord) -> Result<Self, Self::Error> {
+        let version = record.version.unwrap_or(VERTEX_FORMAT_VERSION);
+        if version > VERTEX_FORMAT_VERSION {
+            return Err(format!(
+                "Vertex {} uses layout version {}, newer than the version {} supported by icn-node {}",
+                record.id, version, VERTEX_FORMAT_VERSION, env!("CARGO_PKG_VERSION")
+            ));
+        }
+
+        // Vertices from before signatures had no content address either, so
+        // they can never be verified and stay marked as legacy
+        let legacy = version == LEGACY_VERTEX_FORMAT_VERSION
+            && record.signature.is_empty()
+            && record.public_key.is_empty()
+            && record.payload.is_none();
+
+        Ok(Vertex {
+            version: if legacy { LEGACY_VERTEX_FORMAT_VERSION } else { VERTEX_FORMAT_VERSION },
+            id: record.id,
+            proposal_id: record.proposal_id,
+            timestamp: record.timestamp,
+            hash: record.hash,
+            parents: record.parents,
+            submitter: record.submitter,
+            result_hash: record.result_hash,
+            public_key: record.public_key,
+            signature: record.signature,
+            payload: record.payload,
+        })
+    }
+}
+
+impl Vertex {
+    // A new vertex recorded now. Parents, submitter, id and signature are
+    // filled in by `dag::prepare_vertex` when it is committed.
+    pub fn new(proposal_id: String, hash: String, result_hash: String, payload: VertexPayload) -> Self {
+        Vertex {
+            version: VERTEX_FORMAT_VERSION,
+            id: String::new(),
+            proposal_id,
+            timestamp: Utc::now(),
+            hash,
+            parents: Vec::new(),
+            submitter: String::new(),
+            result_hash,
+            public_key: String::new(),
+            signature: String::new(),
+            payload: Some(payload),
+        }
+    }
+
+    // Read a vertex from a state file being migrated from before vertices
+    // were versioned. A record without a version is taken to be in the legacy
+    // layout, so an unsigned one is marked as legacy.
+    pub fn migrate(value: serde_json::Value) -> Result<Self, String> {
+        let mut record: VertexRecord = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        record.version.get_or_insert(LEGACY_VERTEX_FORMAT_VERSION);
+        Vertex::try_from(record)
+    }
+
+    // Whether the vertex was recorded before vertices were content addressed
+    // and signed. Legacy vertices are accepted without verification and
+    // make no exclusive claims.
+    pub fn is_legacy(&self) -> bool {
+        self.version == LEGACY_VERTEX_FORMAT_VERSION
+    }
+
+    // Type of what the vertex records
+    pub fn data_type(&self) -> &str {
+        self.payload.as_ref().map_or(payload::LEGACY_DATA_TYPE, |payload| payload.data_type())
+    }
+
+    pub fn scope(&self) -> Option<&str> {
+        self.payload.as_ref().and_then(|payload| payload.scope())
+    }
+
+    // Whether the vertex records a proposal execution, which replay can re-run
+    pub fn is_execution(&self) -> bool {
+        matches!(self.payload, None | Some(VertexPayload::ProposalExecution(_)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(value: serde_json::Value) -> Result<Vertex, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+
+    fn unversioned() -> serde_json::Value {
+        serde_json::json!({
+            "id": "0b6c8f7e-5d0a-4a55-9a5e-7f1c8b3e2d10",
+            "proposal_id": "1",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "hash": "abc",
+        })
+    }
+
+    #[test]
+    fn migration_keeps_unsigned_unversioned_vertices_as_legacy() {
+        let vertex = Vertex::migrate(unversioned()).unwrap();
+
+        assert!(vertex.is_legacy());
+        assert!(vertex.parents.is_empty());
+        assert!(read(serde_json::to_value(&vertex).unwrap()).unwrap().is_legacy());
+    }
+
+    #[test]
+    fn reads_unversioned_vertices_in_the_current_layout() {
+        // Outside a migration an unsigned vertex cannot pass as legacy
+        let vertex = read(unversioned()).unwrap();
+
+        assert!(!vertex.is_legacy());
+        assert_eq!(vertex.version, VERTEX_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn upgrades_signed_unversioned_vertices() {
+        let vertex = read(serde_json::json!({
+            "id": "abc",
+            "proposal_id": "1",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "hash": "abc",
+            "public_key": "key",
+            "signature": "signature",
+        })).unwrap();
+
+        assert!(!vertex.is_legacy());
+        assert_eq!(vertex.version, VERTEX_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn rejects_newer_layouts() {
+        let err = read(serde_json::json!({
+            "version": VERTEX_FORMAT_VERSION + 1,
+            "id": "abc",
+            "proposal_id": "1",
+            "timestamp": "2024-01-01T00:00:00Z",
+            "hash": "abc",
+        })).unwrap_err();
+
+        assert!(err.to_string().contains("newer than the version"), "{}", err);
+    }
+}
@@ -178,6 +178,9 @@ validate_args() {
 run_node() {
   log_info "Starting ICN node runner in $RUN_MODE mode"
   
+  # Point the node at the selected data directory
+  export ICN_DATA_DIR="$DATA_DIR"
+  
   case "$RUN_MODE" in
     run)
       # Run as daemon